@@ -0,0 +1,359 @@
+use std::{fmt, str::FromStr};
+
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{self, doc, oid::ObjectId},
+    options::{FindOneAndUpdateOptions, IndexOptions, ReturnDocument},
+    Collection, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+
+use crate::{
+    candidate_service::is_duplicate_key,
+    discovery_service::{Advertiser, AdvertiserKind},
+    house_service::{HouseDTO, HouseEntity},
+};
+
+#[derive(Clone)]
+pub struct AgenciesService {
+    collection: Collection<AgencyEntity>,
+    // Needed to join the agencies with their houses
+    house_collection: String,
+}
+
+type Result<T> = std::result::Result<T, AgenciesServiceError>;
+
+#[derive(Debug)]
+pub enum AgenciesServiceError {
+    MongoDbError(mongodb::error::Error),
+    BsonError(bson::de::Error),
+    ObjectId(mongodb::bson::oid::Error),
+    AgencyNotFound(String),
+}
+
+impl fmt::Display for AgenciesServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::MongoDbError(e) => write!(f, "mongodb: {}", e),
+            Self::BsonError(e) => write!(f, "unexpected agency document: {}", e),
+            Self::ObjectId(e) => write!(f, "invalid id: {}", e),
+            Self::AgencyNotFound(id) => write!(f, "agency {} not found", id),
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for AgenciesServiceError {
+    fn from(e: mongodb::error::Error) -> Self {
+        Self::MongoDbError(e)
+    }
+}
+impl From<bson::de::Error> for AgenciesServiceError {
+    fn from(e: bson::de::Error) -> Self {
+        Self::BsonError(e)
+    }
+}
+impl From<mongodb::bson::oid::Error> for AgenciesServiceError {
+    fn from(e: mongodb::bson::oid::Error) -> Self {
+        Self::ObjectId(e)
+    }
+}
+
+impl AgenciesService {
+    pub fn new(collection: Collection<AgencyEntity>, house_collection: String) -> Self {
+        Self {
+            collection,
+            house_collection,
+        }
+    }
+
+    /// The keys `upsert` recognizes the agencies by: without them two houses
+    /// of a new agency inserted together would create it twice
+    pub async fn create_indexes(&self) -> Result<()> {
+        let page_url = IndexModel::builder()
+            .keys(doc! { "page_url": 1 })
+            .options(
+                IndexOptions::builder()
+                    .unique(true)
+                    .partial_filter_expression(doc! { "page_url": { "$type": "string" } })
+                    .build(),
+            )
+            .build();
+        // The upsert stores a null page_url for the private advertisers
+        let name = IndexModel::builder()
+            .keys(doc! { "name": 1 })
+            .options(
+                IndexOptions::builder()
+                    .unique(true)
+                    .partial_filter_expression(doc! { "page_url": { "$type": "null" } })
+                    .build(),
+            )
+            .build();
+        self.collection
+            .create_indexes([page_url, name], None)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Finds the agency publishing the listing, creating it the first time we meet it.
+    /// Agencies are recognized by their page on the portal, private advertisers by name.
+    pub async fn upsert(&self, advertiser: &Advertiser) -> Result<AgencyEntity> {
+        let filter = match &advertiser.page_url {
+            Some(page_url) => doc! { "page_url": page_url },
+            None => doc! { "name": &advertiser.name, "page_url": null },
+        };
+
+        let mut set = doc! {
+            "name": &advertiser.name,
+            "kind": bson::to_bson(&advertiser.kind).unwrap(),
+        };
+        if let Some(phone) = &advertiser.phone {
+            set.insert("phone", phone);
+        }
+        let update = doc! {
+            "$set": set,
+            "$setOnInsert": { "blocked": false },
+        };
+
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+
+        event!(Level::INFO, name = %advertiser.name, "upserting agency");
+        let agency = match self
+            .collection
+            .find_one_and_update(filter.clone(), update.clone(), options.clone())
+            .await
+        {
+            // Inserted meanwhile by another house: now it is found
+            Err(e) if is_duplicate_key(&e) => {
+                self.collection
+                    .find_one_and_update(filter, update, options)
+                    .await?
+            }
+            agency => agency?,
+        };
+
+        // With upsert and ReturnDocument::After the document is always there
+        agency.ok_or_else(|| AgenciesServiceError::AgencyNotFound(advertiser.name.clone()))
+    }
+
+    /// With the houses still listed
+    pub async fn get_agencies(&self) -> Result<Vec<AgencyDTO>> {
+        let pipeline = vec![
+            doc! { "$sort": { "name": 1 } },
+            // Just what tells the houses apart in the list
+            doc! { "$lookup": {
+                "from": &self.house_collection,
+                "localField": "_id",
+                "foreignField": "agency_id",
+                "pipeline": [
+                    { "$match": { "removed": false } },
+                    { "$project": {
+                        "link": 1,
+                        "removed": 1,
+                        "vote": 1,
+                        "listing_type": 1,
+                        "city": 1,
+                        "zone": 1,
+                        "neighborhood": 1,
+                        "street": 1,
+                        "rooms_number": 1,
+                        "square_meters": 1,
+                        "cost": 1,
+                        "agency_id": 1,
+                        "agency_blocked": 1,
+                    } },
+                ],
+                "as": "houses",
+            } },
+        ];
+
+        let cur = self.collection.aggregate(pipeline, None).await?;
+        let agencies: Vec<bson::Document> = cur.try_collect().await?;
+
+        event!(Level::INFO, count = agencies.len(), "found");
+
+        agencies
+            .into_iter()
+            .map(|a| {
+                let a: AgencyWithHousesEntity = bson::from_document(a)?;
+                Ok(a.into())
+            })
+            .collect()
+    }
+
+    pub async fn update_agency_by_id(&self, id: String, update: UpdateAgencyDTO) -> Result<()> {
+        let obj_id = ObjectId::from_str(&id)?;
+
+        event!(Level::INFO, agency_id = %id, blocked = update.blocked, "update");
+        let ret = self
+            .collection
+            .find_one_and_update(
+                doc! { "_id": obj_id },
+                doc! { "$set": { "blocked": update.blocked } },
+                None,
+            )
+            .await?;
+
+        if ret.is_none() {
+            return Err(AgenciesServiceError::AgencyNotFound(id));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AgencyEntity {
+    pub _id: ObjectId,
+    pub name: String,
+    pub kind: AdvertiserKind,
+    pub phone: Option<String>,
+    pub page_url: Option<String>,
+    pub blocked: bool,
+}
+
+#[derive(Deserialize)]
+struct AgencyWithHousesEntity {
+    #[serde(flatten)]
+    agency: AgencyEntity,
+    houses: Vec<HouseEntity>,
+}
+
+#[derive(Serialize)]
+pub struct AgencyDTO {
+    pub id: String,
+    pub name: String,
+    pub kind: AdvertiserKind,
+    pub phone: Option<String>,
+    pub page_url: Option<String>,
+    pub blocked: bool,
+    pub houses: Vec<HouseDTO>,
+}
+
+impl From<AgencyWithHousesEntity> for AgencyDTO {
+    fn from(e: AgencyWithHousesEntity) -> Self {
+        Self {
+            id: e.agency._id.to_hex(),
+            name: e.agency.name,
+            kind: e.agency.kind,
+            phone: e.agency.phone,
+            page_url: e.agency.page_url,
+            blocked: e.agency.blocked,
+            houses: e.houses.into_iter().map(HouseDTO::from).collect(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UpdateAgencyDTO {
+    blocked: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use mongodb::options::{ClientOptions, ResolverConfig};
+    use mongodb::Client;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_flow() {
+        pretty_env_logger::try_init().ok();
+
+        let db = connect_mongo().await;
+
+        let service = AgenciesService::new(db.collection("agencies"), "houses".to_string());
+        service.create_indexes().await.unwrap();
+
+        let agency = Advertiser {
+            name: "the-agency".to_string(),
+            kind: AdvertiserKind::Agency,
+            phone: None,
+            page_url: Some("http://the.link/agency".to_string()),
+        };
+        let private = Advertiser {
+            name: "the-owner".to_string(),
+            kind: AdvertiserKind::Private,
+            phone: Some("123".to_string()),
+            page_url: None,
+        };
+
+        let upserted = service.upsert(&agency).await.unwrap();
+        assert!(!upserted.blocked);
+        // Found by its page, even renamed
+        let renamed = Advertiser {
+            name: "the-agency-renamed".to_string(),
+            ..agency.clone()
+        };
+        let found = service.upsert(&renamed).await.unwrap();
+        assert_eq!(found._id, upserted._id);
+        assert_eq!(found.name, "the-agency-renamed");
+        // Private advertisers by name
+        let owner = service.upsert(&private).await.unwrap();
+        assert_ne!(owner._id, upserted._id);
+        assert_eq!(service.upsert(&private).await.unwrap()._id, owner._id);
+
+        let agencies = service.get_agencies().await.unwrap();
+        assert_eq!(agencies.len(), 2);
+        assert!(agencies.iter().all(|a| !a.blocked && a.houses.is_empty()));
+
+        let block = serde_json::from_str(r#"{"blocked": true}"#).unwrap();
+        service
+            .update_agency_by_id(upserted._id.to_hex(), block)
+            .await
+            .unwrap();
+        // Meeting it again does not unblock it
+        assert!(service.upsert(&agency).await.unwrap().blocked);
+        assert!(!service.upsert(&private).await.unwrap().blocked);
+
+        let unblock = serde_json::from_str(r#"{"blocked": false}"#).unwrap();
+        service
+            .update_agency_by_id(upserted._id.to_hex(), unblock)
+            .await
+            .unwrap();
+        assert!(!service.upsert(&agency).await.unwrap().blocked);
+
+        let block = serde_json::from_str(r#"{"blocked": true}"#).unwrap();
+        let res = service
+            .update_agency_by_id(ObjectId::new().to_hex(), block)
+            .await;
+        assert!(matches!(res, Err(AgenciesServiceError::AgencyNotFound(_))));
+        let block = serde_json::from_str(r#"{"blocked": true}"#).unwrap();
+        let res = service
+            .update_agency_by_id("not-an-id".to_string(), block)
+            .await;
+        assert!(matches!(res, Err(AgenciesServiceError::ObjectId(_))));
+    }
+
+    async fn connect_mongo() -> mongodb::Database {
+        let mut client_options = ClientOptions::parse_with_resolver_config(
+            get_mongo_url(),
+            ResolverConfig::cloudflare(),
+        )
+        .await
+        .unwrap();
+
+        client_options.app_name = Some("preference-be-test".to_string());
+
+        let client = Client::with_options(client_options).unwrap();
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        let now = now.as_secs();
+        let database_name = format!("test-agencies-{}", now);
+        client.database(&database_name)
+    }
+
+    fn get_mongo_url() -> String {
+        let host = std::env::var("MONGODB_HOST").unwrap_or("localhost".to_string());
+        let port = std::env::var("MONGODB_PORT").unwrap_or("27017".to_string());
+        format!("mongodb://{}:{}/test", host, port)
+    }
+}
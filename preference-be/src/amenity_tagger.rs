@@ -0,0 +1,150 @@
+use std::collections::BTreeMap;
+
+/// Words which, right before a phrase, mean the amenity is missing:
+/// "senza ascensore", "no balcony"...
+const NEGATIONS: &[&str] = &["senza", "no", "non", "without", "not"];
+
+/// Turns the free text description of a listing into amenity flags.
+/// The dictionary maps every amenity to the phrases (any language)
+/// which mention it.
+#[derive(Clone, Debug)]
+pub struct AmenityTagger {
+    dictionary: BTreeMap<String, Vec<String>>,
+}
+
+impl AmenityTagger {
+    pub fn new(dictionary: BTreeMap<String, Vec<String>>) -> Self {
+        let dictionary = dictionary
+            .into_iter()
+            .map(|(amenity, phrases)| {
+                let phrases = phrases.into_iter().map(|p| p.to_lowercase()).collect();
+                (amenity, phrases)
+            })
+            .collect();
+        Self { dictionary }
+    }
+
+    /// Reads the dictionary from a JSON file, falling back to the built-in one
+    pub fn load(path: Option<&str>) -> Self {
+        let path = match path {
+            None => return Self::default(),
+            Some(path) => path,
+        };
+
+        let content = std::fs::read_to_string(path).unwrap();
+        Self::new(serde_json::from_str(&content).unwrap())
+    }
+
+    /// Every known amenity gets a flag, so a missing mention is stored as `false`
+    pub fn tag(&self, description: Option<&str>) -> BTreeMap<String, bool> {
+        let words = description.map(words).unwrap_or_default();
+
+        self.dictionary
+            .iter()
+            .map(|(amenity, phrases)| {
+                let found = phrases.iter().any(|phrase| mentions(&words, phrase));
+                (amenity.clone(), found)
+            })
+            .collect()
+    }
+}
+
+impl Default for AmenityTagger {
+    fn default() -> Self {
+        let dictionary = [
+            ("elevator", &["ascensore", "elevator", "lift"][..]),
+            ("cellar", &["cantina", "cantinola", "cellar"]),
+            (
+                "air_conditioning",
+                &[
+                    "aria condizionata",
+                    "climatizzato",
+                    "condizionatore",
+                    "air conditioning",
+                ],
+            ),
+            (
+                "no_agencies",
+                &[
+                    "no agenzie",
+                    "no agenzia",
+                    "astenersi agenzie",
+                    "no agencies",
+                ],
+            ),
+            ("balcony", &["balcone", "balconi", "balcony"]),
+            ("terrace", &["terrazzo", "terrazza", "terrace"]),
+            ("furnished", &["arredato", "arredata", "furnished"]),
+            ("garden", &["giardino", "garden"]),
+            ("parking", &["box auto", "posto auto", "garage", "parking"]),
+            ("concierge", &["portineria", "portiere", "concierge"]),
+            ("dishwasher", &["lavastoviglie", "dishwasher"]),
+            ("washing_machine", &["lavatrice", "washing machine"]),
+        ];
+
+        Self::new(
+            dictionary
+                .iter()
+                .map(|(amenity, phrases)| {
+                    let phrases = phrases.iter().map(|p| p.to_string()).collect();
+                    (amenity.to_string(), phrases)
+                })
+                .collect(),
+        )
+    }
+}
+
+fn words(text: &str) -> Vec<String> {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_owned())
+        .collect()
+}
+
+/// True if the phrase appears as whole words and is not negated
+fn mentions(words: &[String], phrase: &str) -> bool {
+    let phrase: Vec<_> = phrase.split_whitespace().collect();
+    if phrase.is_empty() {
+        return false;
+    }
+
+    words
+        .windows(phrase.len())
+        .enumerate()
+        .filter(|(_, w)| w.iter().zip(&phrase).all(|(a, b)| a == b))
+        .any(|(i, _)| i == 0 || !NEGATIONS.contains(&words[i - 1].as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tag() {
+        let tagger = AmenityTagger::default();
+
+        let flags = tagger.tag(Some(
+            "Bilocale al piano quinto con Ascensore, completamente arredato, \
+             senza cantina. Aria condizionata in tutte le stanze. No agenzie.",
+        ));
+
+        assert_eq!(flags.get("elevator"), Some(&true));
+        assert_eq!(flags.get("furnished"), Some(&true));
+        assert_eq!(flags.get("air_conditioning"), Some(&true));
+        assert_eq!(flags.get("no_agencies"), Some(&true));
+        assert_eq!(flags.get("cellar"), Some(&false));
+        assert_eq!(flags.get("garden"), Some(&false));
+    }
+
+    #[test]
+    fn test_tag_whole_words() {
+        let mut dictionary = BTreeMap::new();
+        dictionary.insert("lift".to_owned(), vec!["Lift".to_owned()]);
+        let tagger = AmenityTagger::new(dictionary);
+
+        assert_eq!(tagger.tag(Some("uplifting view")).get("lift"), Some(&false));
+        assert_eq!(tagger.tag(Some("there is a LIFT")).get("lift"), Some(&true));
+        assert_eq!(tagger.tag(None).get("lift"), Some(&false));
+    }
+}
@@ -0,0 +1,229 @@
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document},
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+
+use crate::geo::{GeoPolygon, InvalidGeometry};
+
+/// Where each of us is willing to live, and where not at all
+#[derive(Clone)]
+pub struct AreasService {
+    collection: Collection<AreaEntity>,
+}
+
+type Result<T> = std::result::Result<T, AreasServiceError>;
+
+#[derive(Debug)]
+pub enum AreasServiceError {
+    MongoDbError(mongodb::error::Error),
+    ObjectId(mongodb::bson::oid::Error),
+    AreaNotFound(String),
+    InvalidGeometry(String),
+}
+
+impl fmt::Display for AreasServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::MongoDbError(e) => write!(f, "mongodb: {}", e),
+            Self::ObjectId(e) => write!(f, "invalid id: {}", e),
+            Self::AreaNotFound(id) => write!(f, "area {} not found", id),
+            Self::InvalidGeometry(message) => write!(f, "invalid geometry: {}", message),
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for AreasServiceError {
+    fn from(e: mongodb::error::Error) -> Self {
+        Self::MongoDbError(e)
+    }
+}
+impl From<mongodb::bson::oid::Error> for AreasServiceError {
+    fn from(e: mongodb::bson::oid::Error) -> Self {
+        Self::ObjectId(e)
+    }
+}
+impl From<InvalidGeometry> for AreasServiceError {
+    fn from(e: InvalidGeometry) -> Self {
+        Self::InvalidGeometry(e.0)
+    }
+}
+
+impl AreasService {
+    pub fn new(collection: Collection<AreaEntity>) -> Self {
+        Self { collection }
+    }
+
+    pub async fn insert_area(&self, area: AreaDTOInsert) -> Result<String> {
+        let area = AreaEntity {
+            _id: ObjectId::new(),
+            user: area.user,
+            name: area.name,
+            kind: area.kind,
+            polygon: area.polygon.validate()?,
+        };
+
+        event!(Level::INFO, user = %area.user, name = %area.name, "inserting area");
+        self.collection.insert_one(&area, None).await?;
+
+        Ok(area._id.to_hex())
+    }
+
+    pub async fn get_areas(&self, query: AreasQuery) -> Result<Vec<AreaDTO>> {
+        let filter = match query.user {
+            Some(user) => doc! { "user": user },
+            None => doc! {},
+        };
+        let areas = self.areas(filter).await?;
+
+        event!(Level::INFO, count = areas.len(), "found");
+
+        Ok(areas.into_iter().map(AreaDTO::from).collect())
+    }
+
+    pub async fn remove_area(&self, area_id: String) -> Result<()> {
+        let id = ObjectId::from_str(&area_id)?;
+
+        event!(Level::INFO, area_id = %area_id, "removing");
+        let res = self.collection.delete_one(doc! { "_id": id }, None).await?;
+
+        if res.deleted_count == 0 {
+            event!(Level::WARN, area_id = %area_id, "Not found");
+            return Err(AreasServiceError::AreaNotFound(area_id));
+        }
+
+        Ok(())
+    }
+
+    async fn areas(&self, filter: Document) -> Result<Vec<AreaEntity>> {
+        let cur = self.collection.find(filter, None).await?;
+        Ok(cur.try_collect().await?)
+    }
+
+    /// Every area of everybody, read once to flag as many houses as needed
+    pub async fn load(&self) -> Result<Areas> {
+        Ok(Areas(self.areas(doc! {}).await?))
+    }
+}
+
+/// See `AreasService::load`
+pub struct Areas(Vec<AreaEntity>);
+
+impl Areas {
+    /// Whether a house is inside each area, keyed by the area id
+    pub fn flags(&self, lat: f64, lng: f64) -> BTreeMap<String, AreaFlag> {
+        self.0
+            .iter()
+            .map(|a| (a._id.to_hex(), AreaFlag::new(a, lat, lng)))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AreaKind {
+    /// Where we would live
+    Ok,
+    /// Where we would not, whatever the house
+    NoGo,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AreaEntity {
+    _id: ObjectId,
+    /// Whose boundary it is
+    user: String,
+    name: String,
+    kind: AreaKind,
+    polygon: GeoPolygon,
+}
+
+#[derive(Deserialize)]
+pub struct AreaDTOInsert {
+    user: String,
+    name: String,
+    kind: AreaKind,
+    polygon: GeoPolygon,
+}
+
+#[derive(Deserialize)]
+pub struct AreasQuery {
+    user: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct AreaDTO {
+    id: String,
+    user: String,
+    name: String,
+    kind: AreaKind,
+    polygon: GeoPolygon,
+}
+
+impl From<AreaEntity> for AreaDTO {
+    fn from(e: AreaEntity) -> Self {
+        Self {
+            id: e._id.to_hex(),
+            user: e.user,
+            name: e.name,
+            kind: e.kind,
+            polygon: e.polygon,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AreaFlag {
+    pub user: String,
+    pub name: String,
+    pub kind: AreaKind,
+    pub inside: bool,
+}
+
+impl AreaFlag {
+    fn new(area: &AreaEntity, lat: f64, lng: f64) -> Self {
+        Self {
+            user: area.user.clone(),
+            name: area.name.clone(),
+            kind: area.kind,
+            inside: area.polygon.contains(lat, lng),
+        }
+    }
+
+    pub fn is_no_go(&self) -> bool {
+        self.inside && self.kind == AreaKind::NoGo
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flag() {
+        let navigli = AreaEntity {
+            _id: ObjectId::new(),
+            user: "alice".to_string(),
+            name: "navigli".to_string(),
+            kind: AreaKind::NoGo,
+            polygon: GeoPolygon::bounding_box(9.16, 45.44, 9.19, 45.46),
+        };
+
+        let flag = AreaFlag::new(&navigli, 45.4510, 9.1750);
+        assert!(flag.inside);
+        assert!(flag.is_no_go());
+
+        let duomo = AreaFlag::new(&navigli, 45.4641, 9.1919);
+        assert!(!duomo.inside);
+        assert!(!duomo.is_no_go());
+
+        assert_eq!(
+            serde_json::to_value(AreaKind::NoGo).unwrap(),
+            serde_json::json!("no_go")
+        );
+    }
+}
@@ -0,0 +1,439 @@
+use std::{collections::HashSet, fmt, str::FromStr};
+
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document},
+    error::{ErrorKind, WriteFailure},
+    options::{FindOptions, IndexOptions},
+    Collection, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+
+use crate::{
+    cluster::{Marker, MarkerKind},
+    discovery_service::DiscoveryResult,
+    geo::{self, GeoPoint, InvalidGeometry},
+    house_query::BoundingBoxQuery,
+    house_service::ListingType,
+    price_snapshot_service::{
+        PriceSnapshotEntity, PriceSnapshotsService, PriceSnapshotsServiceError,
+    },
+};
+
+/// The inbox of the listings found by the ingestion,
+/// waiting for somebody to look at them before becoming houses
+#[derive(Clone)]
+pub struct CandidatesService {
+    collection: Collection<CandidateEntity>,
+    price_snapshots_service: PriceSnapshotsService,
+}
+
+type Result<T> = std::result::Result<T, CandidatesServiceError>;
+
+#[derive(Debug)]
+pub enum CandidatesServiceError {
+    MongoDbError(mongodb::error::Error),
+    ObjectId(mongodb::bson::oid::Error),
+    CandidateNotFound(String),
+    InvalidGeometry(String),
+    PriceSnapshotError(PriceSnapshotsServiceError),
+}
+
+impl fmt::Display for CandidatesServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::MongoDbError(e) => write!(f, "mongodb: {}", e),
+            Self::ObjectId(e) => write!(f, "invalid id: {}", e),
+            Self::CandidateNotFound(id) => write!(f, "candidate {} not found", id),
+            Self::InvalidGeometry(message) => write!(f, "invalid geometry: {}", message),
+            Self::PriceSnapshotError(e) => e.fmt(f),
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for CandidatesServiceError {
+    fn from(e: mongodb::error::Error) -> Self {
+        Self::MongoDbError(e)
+    }
+}
+impl From<mongodb::bson::oid::Error> for CandidatesServiceError {
+    fn from(e: mongodb::bson::oid::Error) -> Self {
+        Self::ObjectId(e)
+    }
+}
+impl From<InvalidGeometry> for CandidatesServiceError {
+    fn from(e: InvalidGeometry) -> Self {
+        Self::InvalidGeometry(e.0)
+    }
+}
+impl From<PriceSnapshotsServiceError> for CandidatesServiceError {
+    fn from(e: PriceSnapshotsServiceError) -> Self {
+        Self::PriceSnapshotError(e)
+    }
+}
+
+impl CandidatesService {
+    pub fn new(
+        collection: Collection<CandidateEntity>,
+        price_snapshots_service: PriceSnapshotsService,
+    ) -> Self {
+        Self {
+            collection,
+            price_snapshots_service,
+        }
+    }
+
+    /// One candidate per link: two ingestions running together
+    /// would both find a new link and insert it
+    pub async fn create_indexes(&self) -> Result<()> {
+        // The duplicates inserted before the index, keeping the first
+        let duplicates = self
+            .collection
+            .aggregate(
+                [
+                    doc! { "$sort": { "_id": 1 } },
+                    doc! { "$group": { "_id": "$link", "ids": { "$push": "$_id" } } },
+                    doc! { "$match": { "ids.1": { "$exists": true } } },
+                ],
+                None,
+            )
+            .await?;
+        let duplicates: Vec<Document> = duplicates.try_collect().await?;
+        for duplicate in duplicates {
+            let Ok(ids) = duplicate.get_array("ids") else {
+                continue;
+            };
+            self.collection
+                .delete_many(doc! { "_id": { "$in": &ids[1..] } }, None)
+                .await?;
+        }
+
+        let link = IndexModel::builder()
+            .keys(doc! { "link": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        let location = IndexModel::builder()
+            .keys(doc! { "location": "2dsphere" })
+            .build();
+        self.collection
+            .create_indexes([link, location], None)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn migrate(&self) -> Result<()> {
+        let res = self
+            .collection
+            .update_many(
+                // The index refuses the others
+                doc! {
+                    "location": { "$exists": false },
+                    "discovery.lat": { "$type": "number", "$gte": -90, "$lte": 90 },
+                    "discovery.lng": { "$type": "number", "$gte": -180, "$lte": 180 },
+                },
+                vec![doc! { "$set": { "location": {
+                    "type": "Point",
+                    "coordinates": ["$discovery.lng", "$discovery.lat"],
+                } } }],
+                None,
+            )
+            .await?;
+        event!(
+            Level::INFO,
+            modified = res.modified_count,
+            "location migrated"
+        );
+
+        Ok(())
+    }
+
+    /// Returns false when the link is already in the inbox
+    pub async fn insert_candidate(
+        &self,
+        link: String,
+        source: String,
+        discovery: DiscoveryResult,
+    ) -> Result<bool> {
+        let location = location(&discovery);
+        let candidate = CandidateEntity {
+            _id: ObjectId::new(),
+            link,
+            source,
+            discovery,
+            location,
+        };
+
+        event!(Level::INFO, link = %candidate.link, "inserting candidate");
+        match self.collection.insert_one(candidate, None).await {
+            Ok(_) => Ok(true),
+            Err(e) if is_duplicate_key(&e) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Records the price of another discovery of a candidate next to the first one,
+    /// which stays. Returns whether it was a price not seen before.
+    pub async fn record_price(&self, link: &str, discovery: &DiscoveryResult) -> Result<bool> {
+        let candidate = match self
+            .collection
+            .find_one(doc! { "link": link }, None)
+            .await?
+        {
+            Some(candidate) => candidate,
+            None => return Ok(false),
+        };
+
+        let saved = &candidate.discovery;
+        let snapshot = PriceSnapshotEntity::new(
+            link,
+            ListingType::from_link(link),
+            saved.position().or(discovery.position()),
+            discovery.cost(),
+            saved.square_meters().or(discovery.square_meters()),
+        );
+        match snapshot {
+            Some(snapshot) => Ok(self
+                .price_snapshots_service
+                .record(&snapshot, saved.cost())
+                .await?),
+            None => Ok(false),
+        }
+    }
+
+    /// Returns the links, among the given ones, already in the inbox
+    pub async fn existing_links(&self, links: &[String]) -> Result<HashSet<String>> {
+        let cur = self
+            .collection
+            .find(doc! { "link": { "$in": links } }, None)
+            .await?;
+        let candidates: Vec<CandidateEntity> = cur.try_collect().await?;
+
+        Ok(candidates.into_iter().map(|c| c.link).collect())
+    }
+
+    /// Just the positions, for the map
+    pub async fn get_markers_in_bbox(&self, query: &BoundingBoxQuery) -> Result<Vec<Marker>> {
+        let polygon = query.polygon()?;
+        let filter = doc! {
+            "location": { "$geoWithin": { "$geometry": polygon.to_document() } },
+        };
+        let options = FindOptions::builder()
+            .projection(doc! { "discovery.lat": 1, "discovery.lng": 1, "discovery.cost": 1 })
+            .build();
+
+        let cur = self
+            .collection
+            .clone_with_type::<CandidatePosition>()
+            .find(filter, options)
+            .await?;
+        let candidates: Vec<CandidatePosition> = cur.try_collect().await?;
+
+        Ok(candidates
+            .into_iter()
+            .filter_map(|c| {
+                Some(Marker {
+                    id: c._id.to_hex(),
+                    kind: MarkerKind::Candidate,
+                    lat: c.discovery.lat?,
+                    lng: c.discovery.lng?,
+                    cost: c.discovery.cost,
+                })
+            })
+            .collect())
+    }
+
+    pub async fn get_candidates(&self) -> Result<Vec<CandidateDTO>> {
+        let cur = self.collection.find(doc! {}, None).await?;
+        let candidates: Vec<CandidateEntity> = cur.try_collect().await?;
+
+        event!(Level::INFO, count = candidates.len(), "found");
+
+        Ok(candidates.into_iter().map(CandidateDTO::from).collect())
+    }
+
+    pub async fn remove_candidate(&self, candidate_id: String) -> Result<()> {
+        let id = ObjectId::from_str(&candidate_id)?;
+
+        event!(Level::INFO, candidate_id = %candidate_id, "removing");
+        let res = self.collection.delete_one(doc! { "_id": id }, None).await?;
+
+        if res.deleted_count == 0 {
+            event!(Level::WARN, candidate_id = %candidate_id, "Not found");
+            return Err(CandidatesServiceError::CandidateNotFound(candidate_id));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CandidateEntity {
+    _id: ObjectId,
+    link: String,
+    /// The feed or the mailbox the link came from
+    source: String,
+    discovery: DiscoveryResult,
+    /// Same as the discovered position, for the 2dsphere index
+    #[serde(default)]
+    location: Option<GeoPoint>,
+}
+
+#[derive(Deserialize)]
+struct CandidatePosition {
+    _id: ObjectId,
+    discovery: Position,
+}
+
+#[derive(Deserialize)]
+struct Position {
+    lat: Option<f64>,
+    lng: Option<f64>,
+    cost: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct CandidateDTO {
+    pub id: String,
+    pub link: String,
+    pub source: String,
+    pub discovery: DiscoveryResult,
+}
+
+impl From<CandidateEntity> for CandidateDTO {
+    fn from(e: CandidateEntity) -> Self {
+        Self {
+            id: e._id.to_hex(),
+            link: e.link,
+            source: e.source,
+            discovery: e.discovery,
+        }
+    }
+}
+
+/// The portal may give a position the index refuses
+fn location(discovery: &DiscoveryResult) -> Option<GeoPoint> {
+    discovery
+        .position()
+        .filter(|&(lat, lng)| geo::check_position(lat, lng).is_ok())
+        .map(|(lat, lng)| GeoPoint::new(lat, lng))
+}
+
+/// The insert hit a unique index
+pub fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    matches!(
+        e.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(w)) if w.code == 11000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use mongodb::options::{ClientOptions, ResolverConfig};
+    use mongodb::Client;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_flow() {
+        pretty_env_logger::try_init().ok();
+
+        let db = connect_mongo().await;
+
+        let price_snapshots_service = PriceSnapshotsService::new(db.collection("price_snapshots"));
+        let service =
+            CandidatesService::new(db.collection("candidates"), price_snapshots_service.clone());
+        service.create_indexes().await.unwrap();
+        price_snapshots_service.create_indexes().await.unwrap();
+
+        let link = "http://the.link/affitto/1".to_string();
+        let discovery = |cost: u32| -> DiscoveryResult {
+            serde_json::from_value(serde_json::json!({
+                "lat": 45.4642,
+                "lng": 9.1899,
+                "square_meters": 50,
+                "cost": cost,
+                "photos": [],
+                "floor_plans": [],
+            }))
+            .unwrap()
+        };
+
+        let inserted = service
+            .insert_candidate(link.clone(), "the-feed".to_string(), discovery(1000))
+            .await
+            .unwrap();
+        assert!(inserted);
+        // Found again by another feed: the first one stays
+        let inserted = service
+            .insert_candidate(link.clone(), "another-feed".to_string(), discovery(900))
+            .await
+            .unwrap();
+        assert!(!inserted);
+
+        let candidates = service.get_candidates().await.unwrap();
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].source, "the-feed");
+        assert_eq!(candidates[0].discovery.cost(), Some(1000));
+
+        let links = [link.clone(), "http://the.link/affitto/2".to_string()];
+        let existing = service.existing_links(&links).await.unwrap();
+        assert_eq!(existing, HashSet::from([link.clone()]));
+
+        // The saved price
+        assert!(!service.record_price(&link, &discovery(1000)).await.unwrap());
+        assert!(service.record_price(&link, &discovery(900)).await.unwrap());
+        // The last one seen
+        assert!(!service.record_price(&link, &discovery(900)).await.unwrap());
+        assert!(service.record_price(&link, &discovery(950)).await.unwrap());
+        // Not in the inbox
+        let unknown = "http://the.link/affitto/2";
+        assert!(!service
+            .record_price(unknown, &discovery(900))
+            .await
+            .unwrap());
+
+        // The candidate keeps its first price
+        let candidates = service.get_candidates().await.unwrap();
+        assert_eq!(candidates[0].discovery.cost(), Some(1000));
+
+        service
+            .remove_candidate(candidates[0].id.clone())
+            .await
+            .unwrap();
+        let res = service.remove_candidate(candidates[0].id.clone()).await;
+        assert!(matches!(
+            res,
+            Err(CandidatesServiceError::CandidateNotFound(_))
+        ));
+    }
+
+    async fn connect_mongo() -> mongodb::Database {
+        let mut client_options = ClientOptions::parse_with_resolver_config(
+            get_mongo_url(),
+            ResolverConfig::cloudflare(),
+        )
+        .await
+        .unwrap();
+
+        client_options.app_name = Some("preference-be-test".to_string());
+
+        let client = Client::with_options(client_options).unwrap();
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        let now = now.as_secs();
+        let database_name = format!("test-candidates-{}", now);
+        client.database(&database_name)
+    }
+
+    fn get_mongo_url() -> String {
+        let host = std::env::var("MONGODB_HOST").unwrap_or("localhost".to_string());
+        let port = std::env::var("MONGODB_PORT").unwrap_or("27017".to_string());
+        format!("mongodb://{}:{}/test", host, port)
+    }
+}
@@ -0,0 +1,162 @@
+use std::{collections::HashMap, f64::consts::PI};
+
+use serde::Serialize;
+
+/// Side of the cells grouping the markers, in screen pixels
+const CELL_PIXELS: f64 = 64.0;
+/// Side of a map tile, in screen pixels
+const TILE_PIXELS: f64 = 256.0;
+pub const MAX_ZOOM: u8 = 22;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MarkerKind {
+    House,
+    Candidate,
+}
+
+/// Something to show on the map
+#[derive(Debug, Clone, PartialEq)]
+pub struct Marker {
+    pub id: String,
+    pub kind: MarkerKind,
+    pub lat: f64,
+    pub lng: f64,
+    pub cost: Option<u32>,
+}
+
+/// The markers close on the screen at a zoom level, drawn as one
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Cluster {
+    /// The centroid of the markers
+    pub lat: f64,
+    pub lng: f64,
+    pub count: u32,
+    pub houses: u32,
+    pub candidates: u32,
+    pub min_cost: Option<u32>,
+    pub max_cost: Option<u32>,
+    /// Only for a lone marker, to open it
+    pub id: Option<String>,
+    pub kind: Option<MarkerKind>,
+}
+
+impl Cluster {
+    fn new(marker: &Marker) -> Self {
+        Self {
+            lat: 0.0,
+            lng: 0.0,
+            count: 0,
+            houses: 0,
+            candidates: 0,
+            min_cost: marker.cost,
+            max_cost: marker.cost,
+            id: Some(marker.id.clone()),
+            kind: Some(marker.kind),
+        }
+    }
+
+    fn add(&mut self, marker: &Marker) {
+        self.count += 1;
+        // Sums until `finish`
+        self.lat += marker.lat;
+        self.lng += marker.lng;
+        match marker.kind {
+            MarkerKind::House => self.houses += 1,
+            MarkerKind::Candidate => self.candidates += 1,
+        }
+        if let Some(cost) = marker.cost {
+            self.min_cost = Some(self.min_cost.map_or(cost, |c| c.min(cost)));
+            self.max_cost = Some(self.max_cost.map_or(cost, |c| c.max(cost)));
+        }
+    }
+
+    fn finish(mut self) -> Self {
+        self.lat /= self.count as f64;
+        self.lng /= self.count as f64;
+        if self.count > 1 {
+            self.id = None;
+            self.kind = None;
+        }
+        self
+    }
+}
+
+/// Web Mercator pixel coordinates at a zoom level, as the map draws them
+fn pixel(lat: f64, lng: f64, zoom: u8) -> (f64, f64) {
+    let size = TILE_PIXELS * 2f64.powi(zoom as i32);
+    let lat = lat.clamp(-85.05, 85.05).to_radians();
+    let x = (lng + 180.0) / 360.0 * size;
+    let y = (1.0 - (lat.tan() + 1.0 / lat.cos()).ln() / PI) / 2.0 * size;
+    (x, y)
+}
+
+/// Groups the markers falling in the same cell of a grid of screen pixels
+pub fn cluster(markers: &[Marker], zoom: u8) -> Vec<Cluster> {
+    let mut cells: HashMap<(i64, i64), Cluster> = HashMap::new();
+    for marker in markers {
+        let (x, y) = pixel(marker.lat, marker.lng, zoom);
+        let cell = (
+            (x / CELL_PIXELS).floor() as i64,
+            (y / CELL_PIXELS).floor() as i64,
+        );
+        cells
+            .entry(cell)
+            .or_insert_with(|| Cluster::new(marker))
+            .add(marker);
+    }
+
+    let mut clusters: Vec<_> = cells.into_values().map(Cluster::finish).collect();
+    // Stable output, the biggest first
+    clusters.sort_by(|a, b| b.count.cmp(&a.count).then(a.lat.total_cmp(&b.lat)));
+    clusters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn marker(id: &str, kind: MarkerKind, lat: f64, lng: f64, cost: Option<u32>) -> Marker {
+        Marker {
+            id: id.to_string(),
+            kind,
+            lat,
+            lng,
+            cost,
+        }
+    }
+
+    #[test]
+    fn test_cluster() {
+        let markers = [
+            // Around the Duomo, a few hundred meters apart
+            marker("a", MarkerKind::House, 45.4641, 9.1919, Some(1200)),
+            marker("b", MarkerKind::House, 45.4660, 9.1900, Some(900)),
+            marker("c", MarkerKind::Candidate, 45.4630, 9.1930, None),
+            // Dergano
+            marker("d", MarkerKind::House, 45.5050, 9.1800, Some(800)),
+        ];
+
+        let city = cluster(&markers, 12);
+        assert_eq!(city.len(), 2);
+        assert_eq!(
+            (city[0].count, city[0].houses, city[0].candidates),
+            (3, 2, 1)
+        );
+        assert_eq!(
+            (city[0].min_cost, city[0].max_cost),
+            (Some(900), Some(1200))
+        );
+        assert_eq!(city[0].id, None);
+        assert_eq!(city[1].id.as_deref(), Some("d"));
+        assert_eq!(city[1].kind, Some(MarkerKind::House));
+        assert!((city[1].lat - 45.5050).abs() < 1e-9);
+
+        let region = cluster(&markers, 8);
+        assert_eq!(region.len(), 1);
+        assert_eq!(region[0].count, 4);
+
+        let street = cluster(&markers, 18);
+        assert_eq!(street.len(), 4);
+    }
+}
@@ -0,0 +1,249 @@
+use std::{fmt, str::FromStr, sync::Arc};
+
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId},
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+
+use crate::gtfs::{parse_time, Router};
+
+/// When people usually leave for the office
+const DEFAULT_DEPARTURE: &str = "08:30";
+
+/// The places we commute to, and the transit travel time from a house to them
+#[derive(Clone)]
+pub struct CommuteService {
+    collection: Collection<DestinationEntity>,
+    router: Option<Arc<Router>>,
+}
+
+type Result<T> = std::result::Result<T, CommuteServiceError>;
+
+#[derive(Debug)]
+pub enum CommuteServiceError {
+    MongoDbError(mongodb::error::Error),
+    ObjectId(mongodb::bson::oid::Error),
+    DestinationNotFound(String),
+    InvalidDeparture(String),
+    RouterError(tokio::task::JoinError),
+}
+
+impl fmt::Display for CommuteServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::MongoDbError(e) => write!(f, "mongodb: {}", e),
+            Self::ObjectId(e) => write!(f, "invalid id: {}", e),
+            Self::DestinationNotFound(id) => write!(f, "destination {} not found", id),
+            Self::InvalidDeparture(departure) => {
+                write!(f, "invalid departure {:?}: HH:MM expected", departure)
+            }
+            Self::RouterError(e) => write!(f, "routing failed: {}", e),
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for CommuteServiceError {
+    fn from(e: mongodb::error::Error) -> Self {
+        Self::MongoDbError(e)
+    }
+}
+impl From<mongodb::bson::oid::Error> for CommuteServiceError {
+    fn from(e: mongodb::bson::oid::Error) -> Self {
+        Self::ObjectId(e)
+    }
+}
+impl From<tokio::task::JoinError> for CommuteServiceError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        Self::RouterError(e)
+    }
+}
+
+impl CommuteService {
+    pub fn new(collection: Collection<DestinationEntity>, router: Option<Router>) -> Self {
+        Self {
+            collection,
+            router: router.map(Arc::new),
+        }
+    }
+
+    pub async fn insert_destination(&self, destination: DestinationDTOInsert) -> Result<String> {
+        let departure = destination
+            .departure
+            .unwrap_or_else(|| DEFAULT_DEPARTURE.to_owned());
+        if parse_time(&departure).is_none() {
+            return Err(CommuteServiceError::InvalidDeparture(departure));
+        }
+
+        let destination = DestinationEntity {
+            _id: ObjectId::new(),
+            name: destination.name,
+            lat: destination.lat,
+            lng: destination.lng,
+            departure,
+        };
+
+        event!(Level::INFO, name = %destination.name, "inserting destination");
+        self.collection.insert_one(&destination, None).await?;
+
+        Ok(destination._id.to_hex())
+    }
+
+    pub async fn get_destinations(&self) -> Result<Vec<DestinationDTO>> {
+        let destinations = self.destinations().await?;
+
+        Ok(destinations.into_iter().map(DestinationDTO::from).collect())
+    }
+
+    pub async fn remove_destination(&self, destination_id: String) -> Result<()> {
+        let id = ObjectId::from_str(&destination_id)?;
+
+        event!(Level::INFO, destination_id = %destination_id, "removing");
+        let res = self.collection.delete_one(doc! { "_id": id }, None).await?;
+
+        if res.deleted_count == 0 {
+            event!(Level::WARN, destination_id = %destination_id, "Not found");
+            return Err(CommuteServiceError::DestinationNotFound(destination_id));
+        }
+
+        Ok(())
+    }
+
+    async fn destinations(&self) -> Result<Vec<DestinationEntity>> {
+        let cur = self.collection.find(doc! {}, None).await?;
+        Ok(cur.try_collect().await?)
+    }
+
+    /// Every destination, read once to route as many houses as needed
+    pub async fn load(&self) -> Result<Commutes> {
+        let destinations = match &self.router {
+            None => vec![],
+            Some(_) => self.destinations().await?,
+        };
+
+        Ok(Commutes {
+            router: self.router.clone(),
+            destinations: Arc::new(destinations),
+        })
+    }
+}
+
+/// See `CommuteService::load`
+pub struct Commutes {
+    router: Option<Arc<Router>>,
+    destinations: Arc<Vec<DestinationEntity>>,
+}
+
+impl Commutes {
+    /// Transit commutes from a house to every destination.
+    /// Empty without a GTFS feed.
+    pub async fn commutes(&self, lat: f64, lng: f64) -> Result<Vec<CommuteEntity>> {
+        let router = match &self.router {
+            None => return Ok(vec![]),
+            Some(router) => router.clone(),
+        };
+        let destinations = self.destinations.clone();
+
+        // Scanning the timetable takes a while
+        let commutes = tokio::task::spawn_blocking(move || {
+            destinations
+                .iter()
+                .filter_map(|d| {
+                    let departure = parse_time(&d.departure)?;
+                    let journey = router.journey((lat, lng), (d.lat, d.lng), departure)?;
+                    Some(CommuteEntity {
+                        destination_id: d._id,
+                        destination: d.name.clone(),
+                        minutes: journey.minutes,
+                        lines: journey.lines,
+                    })
+                })
+                .collect()
+        })
+        .await?;
+
+        Ok(commutes)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DestinationEntity {
+    _id: ObjectId,
+    name: String,
+    lat: f64,
+    lng: f64,
+    /// "HH:MM"
+    departure: String,
+}
+
+#[derive(Deserialize)]
+pub struct DestinationDTOInsert {
+    name: String,
+    lat: f64,
+    lng: f64,
+    departure: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct DestinationDTO {
+    id: String,
+    name: String,
+    lat: f64,
+    lng: f64,
+    departure: String,
+}
+
+impl From<DestinationEntity> for DestinationDTO {
+    fn from(e: DestinationEntity) -> Self {
+        Self {
+            id: e._id.to_hex(),
+            name: e.name,
+            lat: e.lat,
+            lng: e.lng,
+            departure: e.departure,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommuteEntity {
+    destination_id: ObjectId,
+    destination: String,
+    minutes: u32,
+    lines: Vec<String>,
+}
+
+impl CommuteEntity {
+    pub fn destination_id(&self) -> ObjectId {
+        self.destination_id
+    }
+
+    pub fn destination(&self) -> &str {
+        &self.destination
+    }
+
+    pub fn minutes(&self) -> u32 {
+        self.minutes
+    }
+}
+
+#[derive(Serialize)]
+pub struct CommuteDTO {
+    destination_id: String,
+    destination: String,
+    minutes: u32,
+    lines: Vec<String>,
+}
+
+impl From<CommuteEntity> for CommuteDTO {
+    fn from(e: CommuteEntity) -> Self {
+        Self {
+            destination_id: e.destination_id.to_hex(),
+            destination: e.destination,
+            minutes: e.minutes,
+            lines: e.lines,
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use serde::Serialize;
+
+/// Which way a criterion is better
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Better {
+    Lower,
+    Higher,
+}
+
+/// A criterion across the compared houses, in their order
+#[derive(Debug, PartialEq, Serialize)]
+pub struct CriterionComparison {
+    pub criterion: String,
+    pub better: Better,
+    pub values: Vec<Option<f64>>,
+    /// From the best value: how much more a house costs, how much smaller it is...
+    pub deltas: Vec<Option<f64>>,
+    /// The houses with the best value, by index.
+    /// None when less than two houses have a value: there is no match.
+    pub winners: Vec<usize>,
+}
+
+impl CriterionComparison {
+    pub fn new(criterion: impl Into<String>, better: Better, values: Vec<Option<f64>>) -> Self {
+        let known: Vec<f64> = values.iter().flatten().copied().collect();
+        let best = match better {
+            Better::Lower => known.iter().copied().reduce(f64::min),
+            Better::Higher => known.iter().copied().reduce(f64::max),
+        };
+
+        let deltas = values.iter().map(|&v| Some((v? - best?).abs())).collect();
+        let winners = match best {
+            Some(best) if known.len() > 1 => values
+                .iter()
+                .enumerate()
+                .filter(|(_, v)| **v == Some(best))
+                .map(|(i, _)| i)
+                .collect(),
+            _ => vec![],
+        };
+
+        Self {
+            criterion: criterion.into(),
+            better,
+            values,
+            deltas,
+            winners,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Comparison {
+    pub criteria: Vec<CriterionComparison>,
+    /// How many criteria each house wins, ties included
+    pub wins: Vec<usize>,
+}
+
+impl Comparison {
+    /// The criteria no house has a value for are left out
+    pub fn new(houses: usize, criteria: Vec<CriterionComparison>) -> Self {
+        let criteria: Vec<_> = criteria
+            .into_iter()
+            .filter(|c| c.values.iter().any(Option::is_some))
+            .collect();
+
+        let mut wins = vec![0; houses];
+        for i in criteria.iter().flat_map(|c| &c.winners) {
+            wins[*i] += 1;
+        }
+
+        Self { criteria, wins }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_comparison() {
+        let cost = CriterionComparison::new(
+            "cost",
+            Better::Lower,
+            vec![Some(1200.0), Some(1000.0), None],
+        );
+        assert_eq!(cost.deltas, [Some(200.0), Some(0.0), None]);
+        assert_eq!(cost.winners, [1]);
+
+        let size = CriterionComparison::new(
+            "square_meters",
+            Better::Higher,
+            vec![Some(70.0), Some(60.0), Some(70.0)],
+        );
+        assert_eq!(size.deltas, [Some(0.0), Some(10.0), Some(0.0)]);
+        assert_eq!(size.winners, [0, 2]);
+
+        // Nothing to compare with
+        let alone = CriterionComparison::new("floor", Better::Higher, vec![None, Some(3.0), None]);
+        assert!(alone.winners.is_empty());
+        let unknown = CriterionComparison::new("vote", Better::Higher, vec![None, None, None]);
+
+        let comparison = Comparison::new(3, vec![cost, size, alone, unknown]);
+        assert_eq!(comparison.criteria.len(), 3);
+        assert_eq!(comparison.wins, [1, 1, 1]);
+    }
+}
@@ -4,16 +4,59 @@ use envconfig::{Envconfig, Error};
 pub struct Config {
     #[envconfig(from = "STATIC_DIRECTORY")]
     pub static_directory: String,
+    #[envconfig(from = "MEDIA_DIRECTORY", default = "media")]
+    pub media_directory: String,
     #[envconfig(nested = true)]
     pub mongodb: MongoConfig,
     #[envconfig(from = "PORT")]
     pub http_port: u16,
+    #[envconfig(from = "DISCOVERY_PORTALS_FILE")]
+    pub discovery_portals_file: Option<String>,
+    #[envconfig(from = "AMENITIES_DICTIONARY_FILE")]
+    pub amenities_dictionary_file: Option<String>,
+    /// GeoJSON or CSV file of the metro stops, with name and lines
+    #[envconfig(from = "TRANSIT_STOPS_FILE")]
+    pub transit_stops_file: Option<String>,
+    /// GTFS static feed zip, for the commute times
+    #[envconfig(from = "GTFS_FILE")]
+    pub gtfs_file: Option<String>,
+    /// GeoJSON file of the official neighborhoods, like Milan's NIL
+    #[envconfig(from = "NEIGHBORHOODS_FILE")]
+    pub neighborhoods_file: Option<String>,
+    /// OpenStreetMap `.pbf` extract, for the amenities around the houses
+    #[envconfig(from = "OSM_FILE")]
+    pub osm_file: Option<String>,
+    /// Comma separated meters, the amenities are counted within each
+    #[envconfig(from = "NEARBY_RADII", default = "300,500,1000")]
+    pub nearby_radii: String,
+    /// Comma separated paths or urls of RSS/Atom feeds
+    #[envconfig(from = "INGESTION_FEEDS")]
+    pub ingestion_feeds: Option<String>,
+    /// A `.eml`/`.mbox` file or a directory containing them
+    #[envconfig(from = "INGESTION_MAIL_PATH")]
+    pub ingestion_mail_path: Option<String>,
 }
 
 impl Config {
     pub(crate) fn try_from_env() -> Result<Self, Error> {
         Config::init_from_env()
     }
+
+    pub(crate) fn ingestion_feeds(&self) -> Vec<String> {
+        self.ingestion_feeds
+            .iter()
+            .flat_map(|f| f.split(','))
+            .map(|f| f.trim().to_owned())
+            .filter(|f| !f.is_empty())
+            .collect()
+    }
+
+    pub(crate) fn nearby_radii(&self) -> Vec<u32> {
+        self.nearby_radii
+            .split(',')
+            .filter_map(|r| r.trim().parse().ok())
+            .collect()
+    }
 }
 
 #[derive(Envconfig)]
@@ -28,4 +71,28 @@ pub struct MongoConfig {
     pub database: String,
     #[envconfig(from = "MONGO_DB_HOUSE_COLLECTION")]
     pub house_collection: String,
+    #[envconfig(from = "MONGO_DB_AGENCY_COLLECTION", default = "agencies")]
+    pub agency_collection: String,
+    #[envconfig(from = "MONGO_DB_CANDIDATE_COLLECTION", default = "candidates")]
+    pub candidate_collection: String,
+    #[envconfig(from = "MONGO_DB_DESTINATION_COLLECTION", default = "destinations")]
+    pub destination_collection: String,
+    #[envconfig(from = "MONGO_DB_POI_COLLECTION", default = "pois")]
+    pub poi_collection: String,
+    #[envconfig(from = "MONGO_DB_AREA_COLLECTION", default = "areas")]
+    pub area_collection: String,
+    #[envconfig(
+        from = "MONGO_DB_SCORING_PROFILE_COLLECTION",
+        default = "scoring_profiles"
+    )]
+    pub scoring_profile_collection: String,
+    #[envconfig(from = "MONGO_DB_LAYER_COLLECTION", default = "layers")]
+    pub layer_collection: String,
+    #[envconfig(from = "MONGO_DB_LAYER_FEATURE_COLLECTION", default = "layer_features")]
+    pub layer_feature_collection: String,
+    #[envconfig(
+        from = "MONGO_DB_PRICE_SNAPSHOT_COLLECTION",
+        default = "price_snapshots"
+    )]
+    pub price_snapshot_collection: String,
 }
@@ -1,15 +1,41 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
 use itertools::Itertools;
-use reqwest::{header, ClientBuilder};
+use reqwest::{cookie::Jar, header, Client, ClientBuilder, Url};
 use serde::{Deserialize, Serialize};
 use tracing::{event, Level};
 
+use crate::{
+    amenity_tagger::AmenityTagger, fair_price, neighborhoods::Neighborhoods,
+    preference::VoteFeatures, similarity::SimilarityFeatures, transit_stops::TransitStops,
+};
+
 #[derive(Debug)]
 pub enum DiscoveryError {
     NotFound(String),
+    InvalidUrl(String),
+    WarmUpFailed(String),
     ReqwestError(reqwest::Error),
     ReqwestErrorMiddleware(reqwest_middleware::Error),
 }
 
+impl fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NotFound(url) => write!(f, "listing not found: {}", url),
+            Self::InvalidUrl(url) => write!(f, "invalid url: {}", url),
+            Self::WarmUpFailed(reason) => write!(f, "warm-up failed: {}", reason),
+            Self::ReqwestError(e) => write!(f, "request failed: {}", e),
+            Self::ReqwestErrorMiddleware(e) => write!(f, "request failed: {}", e),
+        }
+    }
+}
+
 impl From<reqwest::Error> for DiscoveryError {
     fn from(r: reqwest::Error) -> Self {
         Self::ReqwestError(r)
@@ -21,41 +47,218 @@ impl From<reqwest_middleware::Error> for DiscoveryError {
     }
 }
 
+/// A step executed against a portal before fetching a listing,
+/// so the portal hands us the cookies it needs to serve the full markup.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WarmUp {
+    /// GET the given path, usually the homepage, to pick up session cookies
+    Visit { path: String },
+    /// POST the given form to the given path, usually the consent endpoint
+    Post {
+        path: String,
+        #[serde(default)]
+        form: Vec<(String, String)>,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Portal {
+    /// Matches the listing host and all its subdomains
+    pub host: String,
+    #[serde(default)]
+    pub warm_ups: Vec<WarmUp>,
+    /// Path prefixes of the listing pages, used to pick them out of feeds and emails
+    #[serde(default)]
+    pub listing_paths: Vec<String>,
+}
+
+impl Portal {
+    fn matches(&self, host: &str) -> bool {
+        host == self.host || host.ends_with(&format!(".{}", self.host))
+    }
+}
+
+fn default_portals() -> Vec<Portal> {
+    vec![
+        Portal {
+            host: "immobiliare.it".to_owned(),
+            warm_ups: vec![WarmUp::Visit {
+                path: "/".to_owned(),
+            }],
+            listing_paths: vec!["/annunci/".to_owned()],
+        },
+        Portal {
+            host: "idealista.it".to_owned(),
+            warm_ups: vec![],
+            listing_paths: vec!["/immobile/".to_owned()],
+        },
+    ]
+}
+
+/// Reads the portals from a JSON file, falling back to the built-in ones
+pub fn load_portals(path: Option<&str>) -> Vec<Portal> {
+    let path = match path {
+        None => return default_portals(),
+        Some(path) => path,
+    };
+
+    let content = std::fs::read_to_string(path).unwrap();
+    serde_json::from_str(&content).unwrap()
+}
+
+/// The portals serve the full markup to the browsers only
+pub const USER_AGENT: &str = " Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/99.0.4844.74 Safari/537.36 Edg/99.0.1150.46";
+
+/// Portal sessions expire: past this the warm-up runs again
+const WARM_UP_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// When each host last warmed up, behind its own lock
+type HostWarmUps = HashMap<String, Arc<tokio::sync::Mutex<Option<Instant>>>>;
+
 #[derive(Clone)]
-pub struct DiscoveryService;
+pub struct DiscoveryService {
+    client: Client,
+    cookies: Arc<Jar>,
+    portals: Arc<Vec<Portal>>,
+    // When each host last warmed up: its cookies live in the client jar.
+    // The lock of a host is held while warming up, so concurrent discovers
+    // of the same host wait for a single warm-up.
+    warmed_up: Arc<Mutex<HostWarmUps>>,
+    warm_up_ttl: Duration,
+}
 
 impl DiscoveryService {
-    pub fn new() -> Self {
-        Self
+    pub fn new(portals: Vec<Portal>) -> Self {
+        let mut headers = header::HeaderMap::new();
+        headers.insert(
+            "accept-language",
+            header::HeaderValue::from_static("en-US,en;q=0.9"),
+        );
+        headers.insert(
+            "accept",
+            header::HeaderValue::from_static("text/html,application/xhtml+xml,application/xml;q=0.9,image/webp,image/apng,*/*;q=0.8,application/signed-exchange;v=b3;q=0.9"),
+        );
+
+        // The jar keys the cookies by domain, so every host gets its own store
+        // which lives as long as the service does.
+        let cookies = Arc::new(Jar::default());
+        let client = ClientBuilder::new()
+            .brotli(true)
+            .gzip(true)
+            .deflate(true)
+            .cookie_provider(cookies.clone())
+            .user_agent(USER_AGENT)
+            .default_headers(headers)
+            .build()
+            .unwrap();
+
+        Self {
+            client,
+            cookies,
+            portals: Arc::new(portals),
+            warmed_up: Arc::new(Mutex::new(HashMap::new())),
+            warm_up_ttl: WARM_UP_TTL,
+        }
+    }
+
+    /// The client scraping the portals, with their user agent and cookies
+    pub fn client(&self) -> Client {
+        self.client.clone()
+    }
+
+    /// The cookies of the portals, for the clients which cannot be shared
+    pub fn cookies(&self) -> Arc<Jar> {
+        self.cookies.clone()
     }
 
     pub async fn discover(&self, url: &str) -> Result<DiscoveryResult, DiscoveryError> {
         event!(Level::INFO, url = %url, "discovering");
 
-        fetch_data(url).await
+        self.warm_up(url).await?;
+
+        fetch_data(&self.client, url).await
+    }
+
+    /// Returns the canonical listing url (no query string nor fragment)
+    /// if the url points to a listing page of a known portal
+    pub fn listing_url(&self, url: &str) -> Option<String> {
+        let mut url = Url::parse(url).ok()?;
+        let host = url.host_str()?;
+
+        let portal = self.portals.iter().find(|p| p.matches(host))?;
+        if !portal
+            .listing_paths
+            .iter()
+            .any(|p| url.path().starts_with(p))
+        {
+            return None;
+        }
+
+        url.set_query(None);
+        url.set_fragment(None);
+        Some(url.to_string())
+    }
+
+    async fn warm_up(&self, url: &str) -> Result<(), DiscoveryError> {
+        let url = Url::parse(url).map_err(|_| DiscoveryError::InvalidUrl(url.to_owned()))?;
+        let host = match url.host_str() {
+            None => return Err(DiscoveryError::InvalidUrl(url.to_string())),
+            Some(host) => host.to_owned(),
+        };
+
+        let portal = match self.portals.iter().find(|p| p.matches(&host)) {
+            None => return Ok(()),
+            Some(portal) => portal,
+        };
+
+        let host_lock = self
+            .warmed_up
+            .lock()
+            .unwrap()
+            .entry(host.clone())
+            .or_default()
+            .clone();
+        let mut warmed_up_at = host_lock.lock().await;
+        if warmed_up_at.is_some_and(|at| at.elapsed() < self.warm_up_ttl) {
+            return Ok(());
+        }
+
+        for warm_up in &portal.warm_ups {
+            event!(Level::INFO, host = %host, warm_up = ?warm_up, "warming up");
+
+            let req = match warm_up {
+                WarmUp::Visit { path } => self.client.get(join(&url, path)?),
+                WarmUp::Post { path, form } => self.client.post(join(&url, path)?).form(form),
+            };
+
+            let response = req.send().await.map_err(|e| {
+                DiscoveryError::WarmUpFailed(format!("{} {:?}: {}", host, warm_up, e))
+            })?;
+
+            let status = response.status();
+            if !status.is_success() {
+                event!(Level::WARN, host = %host, status = %status, "warm up failed");
+
+                return Err(DiscoveryError::WarmUpFailed(format!(
+                    "{} {:?}: {}",
+                    host, warm_up, status
+                )));
+            }
+        }
+
+        *warmed_up_at = Some(Instant::now());
+
+        Ok(())
     }
 }
 
-async fn fetch_data(s: &str) -> Result<DiscoveryResult, DiscoveryError> {
-    let mut headers = header::HeaderMap::new();
-    headers.insert(
-        "accept-language",
-        header::HeaderValue::from_static("en-US,en;q=0.9"),
-    );
-    headers.insert(
-        "accept",
-        header::HeaderValue::from_static("text/html,application/xhtml+xml,application/xml;q=0.9,image/webp,image/apng,*/*;q=0.8,application/signed-exchange;v=b3;q=0.9"),
-    );
-
-    let client = ClientBuilder::new()
-        .brotli(true)
-        .gzip(true)
-        .deflate(true)
-        .user_agent(" Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/99.0.4844.74 Safari/537.36 Edg/99.0.1150.46")
-        .default_headers(headers)
-        .build()
-        .unwrap();
+fn join(url: &Url, path: &str) -> Result<Url, DiscoveryError> {
+    url.join(path)
+        .map_err(|_| DiscoveryError::InvalidUrl(format!("{}{}", url, path)))
+}
 
+async fn fetch_data(client: &Client, s: &str) -> Result<DiscoveryResult, DiscoveryError> {
     let req = client.get(s);
     event!(Level::INFO, req = ?req, "req");
 
@@ -84,10 +287,13 @@ async fn fetch_data(s: &str) -> Result<DiscoveryResult, DiscoveryError> {
     extract2(&body, &mut discovery_result);
     event!(Level::INFO, discovery_result = ?discovery_result, "extraction");
 
+    extract_gallery(&body, &mut discovery_result);
+    event!(Level::INFO, discovery_result = ?discovery_result, "extraction");
+
     Ok(discovery_result)
 }
 
-#[derive(Default, Debug, PartialEq, Serialize)]
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct DiscoveryResult {
     city: Option<String>,
     zone: Option<String>,
@@ -97,6 +303,120 @@ pub struct DiscoveryResult {
     rooms_number: Option<u8>,
     square_meters: Option<u32>,
     cost: Option<u32>,
+    photos: Vec<String>,
+    floor_plans: Vec<String>,
+    description: Option<String>,
+    advertiser: Option<Advertiser>,
+}
+
+impl DiscoveryResult {
+    /// Same as for a saved house: the amenities from the description,
+    /// the neighborhood and the metro from the position
+    pub fn vote_features(
+        &self,
+        amenity_tagger: &AmenityTagger,
+        neighborhoods: &Neighborhoods,
+        transit_stops: &TransitStops,
+    ) -> VoteFeatures {
+        let metro_distance = self
+            .position()
+            .and_then(|(lat, lng)| transit_stops.nearest(lat, lng))
+            .map(|s| s.distance);
+
+        VoteFeatures {
+            listing: self.listing(amenity_tagger, neighborhoods),
+            metro_distance,
+        }
+    }
+
+    pub fn similarity_features(
+        &self,
+        amenity_tagger: &AmenityTagger,
+        neighborhoods: &Neighborhoods,
+    ) -> SimilarityFeatures {
+        SimilarityFeatures {
+            listing: self.listing(amenity_tagger, neighborhoods),
+            position: self.position(),
+        }
+    }
+
+    fn listing(
+        &self,
+        amenity_tagger: &AmenityTagger,
+        neighborhoods: &Neighborhoods,
+    ) -> fair_price::Listing {
+        let neighborhood = self
+            .position()
+            .and_then(|(lat, lng)| neighborhoods.find(lat, lng));
+        fair_price::Listing {
+            square_meters: self.square_meters,
+            rooms_number: self.rooms_number,
+            floor: None,
+            zone: neighborhood.or_else(|| self.zone.clone()),
+            amenities: amenity_tagger.tag(self.description.as_deref()),
+            cost: self.cost,
+        }
+    }
+
+    pub fn position(&self) -> Option<(f64, f64)> {
+        Some((self.lat?, self.lng?))
+    }
+
+    pub fn cost(&self) -> Option<u32> {
+        self.cost
+    }
+
+    pub fn square_meters(&self) -> Option<u32> {
+        self.square_meters
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum AdvertiserKind {
+    Private,
+    Agency,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Advertiser {
+    pub name: String,
+    pub kind: AdvertiserKind,
+    pub phone: Option<String>,
+    pub page_url: Option<String>,
+}
+
+fn extract_gallery(body: &str, discovery_result: &mut DiscoveryResult) {
+    if !discovery_result.photos.is_empty() || !discovery_result.floor_plans.is_empty() {
+        return;
+    }
+
+    for tag in body
+        .split("<img")
+        .skip(1)
+        .filter_map(|t| t.split('>').next())
+    {
+        if !tag.contains("gallery") {
+            continue;
+        }
+        let url = ["data-ondemand-img=\"", "data-src=\"", "src=\""]
+            .iter()
+            .find_map(|attr| {
+                tag.split_once(attr)
+                    .and_then(|(_, rest)| rest.split_once('"'))
+                    .map(|(url, _)| url)
+            });
+        let url = match url {
+            Some(url) if url.starts_with("http") => url.to_string(),
+            _ => continue,
+        };
+
+        if tag.to_lowercase().contains("planimetria") {
+            discovery_result.floor_plans.push(url);
+        } else {
+            discovery_result.photos.push(url);
+        }
+    }
 }
 
 fn extract2(body: &str, discovery_result: &mut DiscoveryResult) {
@@ -188,10 +508,24 @@ fn extract_data(body: &str, discovery_result: &mut DiscoveryResult) {
         Ok(mc) => mc,
     };
 
+    discovery_result.advertiser = map_config
+        .listing
+        .advertiser
+        .take()
+        .and_then(ListingAdvertiser::into_advertiser);
+
     let p = match map_config.listing.properties.pop() {
         None => return,
         Some(p) => p,
     };
+
+    discovery_result.description = p.description;
+
+    if let Some(multimedia) = p.multimedia {
+        discovery_result.photos = large_urls(multimedia.photos);
+        discovery_result.floor_plans = large_urls(multimedia.floorplans);
+    }
+
     let location = match p.location {
         None => return,
         Some(location) => location,
@@ -259,13 +593,92 @@ struct MapConfig {
 #[derive(Deserialize, Debug)]
 struct Listing {
     properties: Vec<Property>,
+    advertiser: Option<ListingAdvertiser>,
+}
+#[derive(Deserialize, Debug)]
+struct ListingAdvertiser {
+    agency: Option<ListingAgency>,
+    supervisor: Option<ListingSupervisor>,
+}
+#[derive(Deserialize, Debug)]
+struct ListingAgency {
+    #[serde(rename(deserialize = "type"))]
+    kind: Option<String>,
+    #[serde(rename(deserialize = "displayName"))]
+    display_name: Option<String>,
+    #[serde(rename(deserialize = "agencyUrl"))]
+    agency_url: Option<String>,
+    phones: Option<Vec<Phone>>,
+}
+#[derive(Deserialize, Debug)]
+struct ListingSupervisor {
+    #[serde(rename(deserialize = "displayName"))]
+    display_name: Option<String>,
+    phones: Option<Vec<Phone>>,
+}
+#[derive(Deserialize, Debug)]
+struct Phone {
+    value: Option<String>,
+}
+
+impl ListingAdvertiser {
+    fn into_advertiser(self) -> Option<Advertiser> {
+        if let Some(agency) = self.agency {
+            let kind = match agency.kind.as_deref() {
+                Some("private") => AdvertiserKind::Private,
+                _ => AdvertiserKind::Agency,
+            };
+            return agency.display_name.map(|name| Advertiser {
+                name,
+                kind,
+                phone: first_phone(agency.phones),
+                page_url: agency.agency_url,
+            });
+        }
+
+        // Without an agency the listing is published by the owner
+        let supervisor = self.supervisor?;
+        supervisor.display_name.map(|name| Advertiser {
+            name,
+            kind: AdvertiserKind::Private,
+            phone: first_phone(supervisor.phones),
+            page_url: None,
+        })
+    }
+}
+
+fn first_phone(phones: Option<Vec<Phone>>) -> Option<String> {
+    phones?.into_iter().find_map(|p| p.value)
 }
 #[derive(Deserialize, Debug)]
 struct Property {
     location: Option<Location>,
+    multimedia: Option<Multimedia>,
+    description: Option<String>,
     // surfaceValue: Option<String>,
 }
 #[derive(Deserialize, Debug)]
+struct Multimedia {
+    photos: Option<Vec<Photo>>,
+    floorplans: Option<Vec<Photo>>,
+}
+#[derive(Deserialize, Debug)]
+struct Photo {
+    urls: PhotoUrls,
+}
+#[derive(Deserialize, Debug)]
+struct PhotoUrls {
+    large: Option<String>,
+}
+
+fn large_urls(photos: Option<Vec<Photo>>) -> Vec<String> {
+    photos
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|p| p.urls.large)
+        .collect()
+}
+#[derive(Deserialize, Debug)]
 struct Location {
     latitude: Option<f64>,
     longitude: Option<f64>,
@@ -296,11 +709,16 @@ mod tests {
                 .header("content-type", "text/html")
                 .body(include_str!("page.test.html"));
         });
-        let service = DiscoveryService;
+        let service = DiscoveryService::new(vec![]);
         let a = service.discover(&server.url(url)).await.unwrap();
 
         server_mock.assert();
 
+        let photos = [
+            1143474522, 1143474534, 1143474526, 1143474510, 1143474502, 1143474500, 1143474498,
+            1143474492, 1143474484, 1143474504, 1143474524, 1143474506, 1143474530, 1143474494,
+            1143474528, 1143474488, 1143474490, 1143474486, 1143474508,
+        ];
         assert_eq!(
             a,
             DiscoveryResult {
@@ -311,7 +729,37 @@ mod tests {
                 lng: Some(9.1775),
                 rooms_number: Some(2),
                 square_meters: Some(60),
-                cost: Some(2100)
+                cost: Some(2100),
+                photos: photos
+                    .iter()
+                    .map(|id| format!("https://pwm.foo-cdn.it/image/{}/xxl.jpg", id))
+                    .collect(),
+                floor_plans: vec![],
+                description: Some(
+                    "Dergano M3, via Pellegrino Rossi, due ampi locali in locazione di mq \
+                     60, al piano quinto con ascensore composto di ingresso, ampio \
+                     soggiorno con cucina a vista, 1 camera da letto matrimoniale, 1 bagno \
+                     finestrato con doccia. L'immobile è completamente arredato: cucina \
+                     completa con pensili, frigorifero, forno; soggiorno con divano, tavolo \
+                     da pranzo con sedie, camera da letto con letto e armadio, lavatrice. \
+                     Balcone che si accede dal soggiorno e dalla camera da letto. Stabile \
+                     anni 60. Riscaldamento centralizzato con termosifoni e termovalvole. \
+                     Canone mensile euro 1.000,00 più spese condominiali euro 130,00 s.c. \
+                     La zona è servita dalla metropolitana linea 3 alla fermata Dergano e \
+                     da mezzi di collegamento, autobus. Nella zona sono presenti \
+                     ristoranti, bar e locali di intrattenimento. Disponibile per contratto \
+                     uso transitorio. Classe energetica G 219,00 KW/mqa Per informazioni \
+                     contattare Marazzi Gestioni Immobiliari - cell. 351.5747712"
+                        .to_string()
+                ),
+                advertiser: Some(Advertiser {
+                    name: "MGI".to_string(),
+                    kind: AdvertiserKind::Agency,
+                    phone: Some("+390287362612".to_string()),
+                    page_url: Some(
+                        "https://www.foo.it/agenzie-immobiliari/369006/mgi-milano/".to_string()
+                    ),
+                }),
             }
         );
     }
@@ -328,7 +776,7 @@ mod tests {
                 .header("content-type", "text/html")
                 .body(include_str!("page2.test.html"));
         });
-        let service = DiscoveryService;
+        let service = DiscoveryService::new(vec![]);
         let a = service.discover(&server.url(url)).await.unwrap();
 
         server_mock.assert();
@@ -343,8 +791,184 @@ mod tests {
                 lng: Some(9.1451057),
                 rooms_number: Some(2),
                 square_meters: Some(70),
-                cost: Some(1200)
+                cost: Some(1200),
+                ..DiscoveryResult::default()
             }
         );
     }
+
+    #[tokio::test]
+    async fn test_flow_gallery() {
+        use httpmock::prelude::*;
+        let server = MockServer::start();
+
+        let url = "/foo";
+        let server_mock = server.mock(|when, then| {
+            when.method(GET).path(url);
+            then.status(200)
+                .header("content-type", "text/html")
+                .body(include_str!("page3.test.html"));
+        });
+        let service = DiscoveryService::new(vec![]);
+        let a = service.discover(&server.url(url)).await.unwrap();
+
+        server_mock.assert();
+
+        let image = |id| {
+            format!(
+                "https://img.foo.it/blur/WEB_DETAIL/0/id.pro.es.image.master/4d/5e/6f/{}.jpg",
+                id
+            )
+        };
+        assert_eq!(
+            a,
+            DiscoveryResult {
+                city: Some("Milano".to_string()),
+                zone: Some("Giambellino".to_string()),
+                street: Some("Via Giambellino, 58".to_string()),
+                lat: Some(45.4539012),
+                lng: Some(9.1427551),
+                rooms_number: Some(3),
+                square_meters: Some(85),
+                cost: Some(1450),
+                photos: vec![image(2001), image(2002)],
+                floor_plans: vec![image(2003)],
+                ..DiscoveryResult::default()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_warm_up() {
+        use httpmock::prelude::*;
+        let server = MockServer::start();
+
+        let home_mock = server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(200)
+                .header("set-cookie", "session=the-session; Path=/");
+        });
+        let consent_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/consent")
+                .cookie("session", "the-session")
+                .x_www_form_urlencoded_tuple("accept", "all");
+            then.status(200).header("set-cookie", "consent=yes; Path=/");
+        });
+        let url = "/foo";
+        let server_mock = server.mock(|when, then| {
+            when.method(GET)
+                .path(url)
+                .cookie("session", "the-session")
+                .cookie("consent", "yes");
+            then.status(200)
+                .header("content-type", "text/html")
+                .body(include_str!("page.test.html"));
+        });
+        let service = DiscoveryService::new(vec![Portal {
+            host: "127.0.0.1".to_owned(),
+            listing_paths: vec![],
+            warm_ups: vec![
+                WarmUp::Visit {
+                    path: "/".to_owned(),
+                },
+                WarmUp::Post {
+                    path: "/consent".to_owned(),
+                    form: vec![("accept".to_owned(), "all".to_owned())],
+                },
+            ],
+        }]);
+        let listing = server.url(url);
+        let (first, second) = tokio::join!(service.discover(&listing), service.discover(&listing));
+        first.unwrap();
+        second.unwrap();
+        service.discover(&listing).await.unwrap();
+
+        // Warm-ups run only once per host, even when discovering concurrently:
+        // the jar keeps the cookies
+        home_mock.assert_hits(1);
+        consent_mock.assert_hits(1);
+        server_mock.assert_hits(3);
+    }
+
+    #[tokio::test]
+    async fn test_warm_up_expired() {
+        use httpmock::prelude::*;
+        let server = MockServer::start();
+
+        let home_mock = server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(200);
+        });
+        let url = "/foo";
+        let server_mock = server.mock(|when, then| {
+            when.method(GET).path(url);
+            then.status(200)
+                .header("content-type", "text/html")
+                .body(include_str!("page.test.html"));
+        });
+        let service = DiscoveryService {
+            warm_up_ttl: Duration::ZERO,
+            ..DiscoveryService::new(vec![Portal {
+                host: "127.0.0.1".to_owned(),
+                listing_paths: vec![],
+                warm_ups: vec![WarmUp::Visit {
+                    path: "/".to_owned(),
+                }],
+            }])
+        };
+        service.discover(&server.url(url)).await.unwrap();
+        service.discover(&server.url(url)).await.unwrap();
+
+        // The session is over: warm up again
+        home_mock.assert_hits(2);
+        server_mock.assert_hits(2);
+    }
+
+    #[tokio::test]
+    async fn test_warm_up_failure() {
+        use httpmock::prelude::*;
+        let server = MockServer::start();
+
+        let consent_mock = server.mock(|when, then| {
+            when.method(POST).path("/consent");
+            then.status(500);
+        });
+        let url = "/foo";
+        let server_mock = server.mock(|when, then| {
+            when.method(GET).path(url);
+            then.status(200)
+                .header("content-type", "text/html")
+                .body(include_str!("page.test.html"));
+        });
+        let service = DiscoveryService::new(vec![Portal {
+            host: "127.0.0.1".to_owned(),
+            listing_paths: vec![],
+            warm_ups: vec![WarmUp::Post {
+                path: "/consent".to_owned(),
+                form: vec![],
+            }],
+        }]);
+        let err = service.discover(&server.url(url)).await.unwrap_err();
+
+        consent_mock.assert();
+        server_mock.assert_hits(0);
+        assert!(matches!(err, DiscoveryError::WarmUpFailed(_)));
+    }
+
+    #[test]
+    fn test_listing_url() {
+        let service = DiscoveryService::new(default_portals());
+
+        assert_eq!(
+            service
+                .listing_url("https://www.immobiliare.it/annunci/93679770/?utm_source=alert#map"),
+            Some("https://www.immobiliare.it/annunci/93679770/".to_string())
+        );
+        assert_eq!(
+            service.listing_url("https://www.immobiliare.it/ricerca/"),
+            None
+        );
+        assert_eq!(service.listing_url("https://www.foo.it/annunci/1/"), None);
+    }
 }
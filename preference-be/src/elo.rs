@@ -0,0 +1,112 @@
+use serde::{Deserialize, Serialize};
+
+pub const INITIAL_RATING: f64 = 1500.0;
+/// How much a comparison moves the ratings
+const K: f64 = 32.0;
+/// A house starts moving twice as fast, to find its place sooner
+const PROVISIONAL_COMPARISONS: u32 = 5;
+
+/// A house ranked against the others by a user's picks
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Rating {
+    pub rating: f64,
+    pub comparisons: u32,
+}
+
+impl Default for Rating {
+    fn default() -> Self {
+        Self {
+            rating: INITIAL_RATING,
+            comparisons: 0,
+        }
+    }
+}
+
+impl Rating {
+    /// The probability to be picked over the other
+    pub fn expected(&self, other: &Rating) -> f64 {
+        1.0 / (1.0 + 10f64.powf((other.rating - self.rating) / 400.0))
+    }
+
+    fn k(&self) -> f64 {
+        if self.comparisons < PROVISIONAL_COMPARISONS {
+            K * 2.0
+        } else {
+            K
+        }
+    }
+
+    fn after(&self, score: f64, expected: f64) -> Self {
+        let rating = self.rating + self.k() * (score - expected);
+        Self {
+            rating: (rating * 10.0).round() / 10.0,
+            comparisons: self.comparisons + 1,
+        }
+    }
+}
+
+/// The new ratings of the picked house and of the other one
+pub fn update(winner: Rating, loser: Rating) -> (Rating, Rating) {
+    (
+        winner.after(1.0, winner.expected(&loser)),
+        loser.after(0.0, loser.expected(&winner)),
+    )
+}
+
+/// The least compared house against the one closest in rating:
+/// the pick that tells the most
+pub fn pick_pair(ratings: &[Rating]) -> Option<(usize, usize)> {
+    let first = (0..ratings.len()).min_by_key(|&i| ratings[i].comparisons)?;
+    let second = (0..ratings.len())
+        .filter(|&j| j != first)
+        .min_by(|&a, &b| {
+            let distance = |j: usize| (ratings[j].rating - ratings[first].rating).abs();
+            distance(a)
+                .total_cmp(&distance(b))
+                .then(ratings[a].comparisons.cmp(&ratings[b].comparisons))
+        })?;
+    Some((first, second))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rating(rating: f64, comparisons: u32) -> Rating {
+        Rating {
+            rating,
+            comparisons,
+        }
+    }
+
+    #[test]
+    fn test_update() {
+        let (winner, loser) = update(Rating::default(), Rating::default());
+        assert_eq!(winner, rating(1532.0, 1));
+        assert_eq!(loser, rating(1468.0, 1));
+
+        // Beating a much weaker house tells little
+        let (winner, loser) = update(rating(1800.0, 20), rating(1400.0, 20));
+        assert_eq!(winner, rating(1802.9, 21));
+        assert_eq!(loser, rating(1397.1, 21));
+
+        // The upset moves them a lot
+        let (winner, loser) = update(rating(1400.0, 20), rating(1800.0, 20));
+        assert_eq!(winner, rating(1429.1, 21));
+        assert_eq!(loser, rating(1770.9, 21));
+    }
+
+    #[test]
+    fn test_pick_pair() {
+        assert_eq!(pick_pair(&[]), None);
+        assert_eq!(pick_pair(&[Rating::default()]), None);
+
+        let ratings = [
+            rating(1600.0, 8),
+            rating(1450.0, 2),
+            rating(1480.0, 9),
+            rating(1300.0, 4),
+        ];
+        assert_eq!(pick_pair(&ratings), Some((1, 2)));
+    }
+}
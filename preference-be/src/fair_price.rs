@@ -0,0 +1,191 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::regression::Ridge;
+
+/// Priced houses needed before trusting the estimate
+const MIN_SAMPLES: usize = 10;
+/// Ridge penalty on the standardized features: a zone seen once should not
+/// explain its house's price alone
+const PENALTY: f64 = 1.0;
+
+/// What a house is priced on
+#[derive(Debug, Clone, Default)]
+pub struct Listing {
+    pub square_meters: Option<u32>,
+    pub rooms_number: Option<u8>,
+    pub floor: Option<i8>,
+    /// The official neighborhood, or the zone of the portal without one
+    pub zone: Option<String>,
+    pub amenities: BTreeMap<String, bool>,
+    pub cost: Option<u32>,
+}
+
+impl Listing {
+    /// Whether the house is in each zone and has each amenity,
+    /// unknown for the amenities the description does not tell
+    pub fn flags<'a>(
+        &'a self,
+        zones: &'a [String],
+        amenities: &'a [String],
+    ) -> impl Iterator<Item = Option<f64>> + 'a {
+        let flag = |b: bool| if b { 1.0 } else { 0.0 };
+
+        zones
+            .iter()
+            .map(move |z| Some(flag(self.zone.as_ref() == Some(z))))
+            .chain(
+                amenities
+                    .iter()
+                    .map(move |a| self.amenities.get(a).map(|&b| flag(b))),
+            )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FairPrice {
+    /// What the houses alike ask
+    pub predicted: f64,
+    /// Cost minus predicted: negative when cheaper
+    pub residual: f64,
+    /// How much cheaper than predicted, as a fraction of it:
+    /// 0.1 is 10% below, negative when overpriced
+    pub deal_score: f64,
+    /// How many priced houses the estimate comes from
+    pub samples: usize,
+}
+
+/// A ridge regression of the cost on square meters, rooms, floor, zone and amenities
+#[derive(Debug)]
+pub struct FairPriceModel {
+    zones: Vec<String>,
+    amenities: Vec<String>,
+    ridge: Ridge,
+    samples: usize,
+}
+
+impl FairPriceModel {
+    /// None with too few priced houses
+    pub fn fit(listings: &[Listing]) -> Option<Self> {
+        let priced: Vec<&Listing> = listings
+            .iter()
+            .filter(|l| l.cost.is_some() && l.square_meters.is_some_and(|s| s > 0))
+            .collect();
+        if priced.len() < MIN_SAMPLES {
+            return None;
+        }
+
+        let zones: BTreeSet<_> = priced.iter().filter_map(|l| l.zone.clone()).collect();
+        let amenities: BTreeSet<_> = priced
+            .iter()
+            .flat_map(|l| l.amenities.keys().cloned())
+            .collect();
+        let zones: Vec<_> = zones.into_iter().collect();
+        let amenities: Vec<_> = amenities.into_iter().collect();
+
+        let rows: Vec<_> = priced
+            .iter()
+            .map(|l| features(&zones, &amenities, l))
+            .collect();
+        let costs: Vec<f64> = priced.iter().map(|l| l.cost.unwrap() as f64).collect();
+
+        Some(Self {
+            ridge: Ridge::fit(&rows, &costs, PENALTY)?,
+            zones,
+            amenities,
+            samples: priced.len(),
+        })
+    }
+
+    /// None without the square meters
+    pub fn predict(&self, listing: &Listing) -> Option<f64> {
+        listing.square_meters.filter(|&s| s > 0)?;
+
+        Some(
+            self.ridge
+                .predict(&features(&self.zones, &self.amenities, listing)),
+        )
+    }
+
+    /// Rounded, so a refit moving the estimates by cents changes nothing
+    pub fn fair_price(&self, listing: &Listing) -> Option<FairPrice> {
+        let cost = listing.cost? as f64;
+        let predicted = self.predict(listing)?.round();
+        if predicted <= 0.0 {
+            return None;
+        }
+
+        Some(FairPrice {
+            predicted,
+            residual: cost - predicted,
+            deal_score: ((predicted - cost) / predicted * 1000.0).round() / 1000.0,
+            samples: self.samples,
+        })
+    }
+}
+
+/// The zones and amenities of the houses fitted on, as flags
+fn features(zones: &[String], amenities: &[String], listing: &Listing) -> Vec<Option<f64>> {
+    let mut features = vec![
+        listing.square_meters.map(|s| s as f64),
+        listing.rooms_number.map(|r| r as f64),
+        listing.floor.map(|f| f as f64),
+    ];
+    features.extend(listing.flags(zones, amenities));
+    features
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listing(square_meters: u32, zone: &str, elevator: bool, cost: Option<u32>) -> Listing {
+        Listing {
+            square_meters: Some(square_meters),
+            rooms_number: Some((square_meters / 30) as u8),
+            floor: Some((square_meters % 5) as i8),
+            zone: Some(zone.to_string()),
+            amenities: BTreeMap::from([("elevator".to_string(), elevator)]),
+            cost,
+        }
+    }
+
+    #[test]
+    fn test_fair_price() {
+        // 15 a square meter, 300 more in Isola, 100 more with an elevator
+        let listings: Vec<_> = (0..40)
+            .map(|i| {
+                let square_meters = 40 + i * 2;
+                let zone = if i % 3 == 0 { "Isola" } else { "Dergano" };
+                let elevator = i % 2 == 0;
+                let cost = 15 * square_meters
+                    + if zone == "Isola" { 300 } else { 0 }
+                    + if elevator { 100 } else { 0 };
+                listing(square_meters, zone, elevator, Some(cost))
+            })
+            .collect();
+
+        assert!(FairPriceModel::fit(&listings[..MIN_SAMPLES - 1]).is_none());
+        let model = FairPriceModel::fit(&listings).unwrap();
+
+        let fair = model
+            .fair_price(&listing(70, "Isola", true, Some(1450)))
+            .unwrap();
+        assert_eq!(fair.samples, 40);
+        assert!((fair.predicted - 1450.0).abs() < 1450.0 * 0.05);
+        assert!(fair.deal_score.abs() < 0.05);
+
+        let overpriced = model
+            .fair_price(&listing(70, "Dergano", false, Some(1500)))
+            .unwrap();
+        assert!(overpriced.residual > 300.0);
+        assert!(overpriced.deal_score < -0.2);
+
+        // Unknown zone and amenities weigh nothing
+        let mut unknown = listing(70, "Bicocca", false, None);
+        unknown.amenities.clear();
+        assert!(model.predict(&unknown).is_some());
+        assert_eq!(model.fair_price(&unknown), None);
+    }
+}
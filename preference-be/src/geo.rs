@@ -0,0 +1,432 @@
+use std::collections::HashMap;
+
+use mongodb::bson::{doc, Document};
+use serde::{Deserialize, Serialize};
+
+const EARTH_RADIUS: f64 = 6_371_008.8;
+/// Streets are not straight: the walk is this much longer than the crow flies
+const WALKING_DETOUR: f64 = 1.3;
+/// Meters per second, about 5 km/h
+pub const WALKING_SPEED: f64 = 1.4;
+/// Meters per second, about 15 km/h
+pub const CYCLING_SPEED: f64 = 4.2;
+
+/// Great-circle distance in meters
+pub fn distance(lat1: f64, lng1: f64, lat2: f64, lng2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lng = (lng2 - lng1).to_radians();
+
+    let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lng / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * a.sqrt().asin()
+}
+
+/// Side of the cells of the grid, in degrees: about 500 m
+const CELL: f64 = 0.005;
+
+fn cell(lat: f64, lng: f64) -> (i32, i32) {
+    ((lat / CELL).floor() as i32, (lng / CELL).floor() as i32)
+}
+
+/// Spatial index of points, by their position in the indexed list
+#[derive(Debug, Default)]
+pub struct Grid {
+    cells: HashMap<(i32, i32), Vec<usize>>,
+}
+
+impl Grid {
+    pub fn new(points: impl IntoIterator<Item = (f64, f64)>) -> Self {
+        let mut cells: HashMap<_, Vec<usize>> = HashMap::new();
+        for (i, (lat, lng)) in points.into_iter().enumerate() {
+            cells.entry(cell(lat, lng)).or_default().push(i);
+        }
+        Self { cells }
+    }
+
+    /// The points in the square around a point: the caller checks the distance
+    pub fn around(&self, lat: f64, lng: f64, meters: f64) -> impl Iterator<Item = usize> + '_ {
+        let d_lat = meters / 111_320.0;
+        // Near the poles a few meters span all the longitudes
+        let d_lng = (meters / (111_320.0 * lat.to_radians().cos())).min(180.0);
+        let (min, max) = (
+            cell(lat - d_lat, lng - d_lng),
+            cell(lat + d_lat, lng + d_lng),
+        );
+
+        (min.0..=max.0)
+            .flat_map(move |x| (min.1..=max.1).map(move |y| (x, y)))
+            .flat_map(|c| self.cells.get(&c).into_iter().flatten().copied())
+    }
+}
+
+/// Estimated walking distance in meters
+pub fn walking_distance(lat1: f64, lng1: f64, lat2: f64, lng2: f64) -> f64 {
+    distance(lat1, lng1, lat2, lng2) * WALKING_DETOUR
+}
+
+const GEOHASH_ALPHABET: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+/// The geohash cell of a point: longitude and latitude halved in turn,
+/// five bits per character
+pub fn geohash(lat: f64, lng: f64, precision: usize) -> String {
+    let (mut lat_range, mut lng_range) = ((-90.0, 90.0), (-180.0, 180.0));
+    let mut hash = String::with_capacity(precision);
+    let mut bits = 0;
+    for i in 0..precision * 5 {
+        let (range, value) = if i % 2 == 0 {
+            (&mut lng_range, lng)
+        } else {
+            (&mut lat_range, lat)
+        };
+        let mid = (range.0 + range.1) / 2.0;
+        bits <<= 1;
+        if value >= mid {
+            bits |= 1;
+            range.0 = mid;
+        } else {
+            range.1 = mid;
+        }
+        if i % 5 == 4 {
+            hash.push(GEOHASH_ALPHABET[bits] as char);
+            bits = 0;
+        }
+    }
+    hash
+}
+
+/// West, south, east, north of a geohash cell
+pub fn geohash_bounds(hash: &str) -> Option<[f64; 4]> {
+    let (mut lat_range, mut lng_range) = ((-90.0, 90.0), (-180.0, 180.0));
+    let mut i = 0;
+    for c in hash.bytes() {
+        let bits = GEOHASH_ALPHABET.iter().position(|&a| a == c)?;
+        for shift in (0..5).rev() {
+            let range = if i % 2 == 0 {
+                &mut lng_range
+            } else {
+                &mut lat_range
+            };
+            let mid = (range.0 + range.1) / 2.0;
+            if bits >> shift & 1 == 1 {
+                range.0 = mid;
+            } else {
+                range.1 = mid;
+            }
+            i += 1;
+        }
+    }
+    Some([lng_range.0, lat_range.0, lng_range.1, lat_range.1])
+}
+
+/// GeoJSON point, as Mongo wants it for the 2dsphere index.
+/// Mind the order: longitude first.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum GeoPoint {
+    Point { coordinates: [f64; 2] },
+}
+
+impl GeoPoint {
+    pub fn new(lat: f64, lng: f64) -> Self {
+        Self::Point {
+            coordinates: [lng, lat],
+        }
+    }
+
+    pub fn from_lat_lng(lat: Option<f64>, lng: Option<f64>) -> Option<Self> {
+        Some(Self::new(lat?, lng?))
+    }
+}
+
+/// GeoJSON polygon: the first ring is the border, the others are holes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum GeoPolygon {
+    Polygon { coordinates: Vec<Vec<[f64; 2]>> },
+}
+
+#[derive(Debug)]
+pub struct InvalidGeometry(pub String);
+
+/// Mongo refuses the points out of range, in the 2dsphere index
+pub fn check_position(lat: f64, lng: f64) -> Result<(), InvalidGeometry> {
+    if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lng) {
+        return Err(InvalidGeometry(format!(
+            "invalid coordinate {}, {}",
+            lng, lat
+        )));
+    }
+    Ok(())
+}
+
+impl GeoPolygon {
+    /// The rectangle of a map viewport
+    pub fn bounding_box(west: f64, south: f64, east: f64, north: f64) -> Self {
+        Self::Polygon {
+            coordinates: vec![vec![
+                [west, south],
+                [east, south],
+                [east, north],
+                [west, north],
+                [west, south],
+            ]],
+        }
+    }
+
+    /// Checks the coordinates and closes the rings the map left open,
+    /// Mongo refuses them otherwise
+    pub fn validate(self) -> Result<Self, InvalidGeometry> {
+        let Self::Polygon { coordinates } = self;
+        if coordinates.is_empty() {
+            return Err(InvalidGeometry("polygon without rings".to_owned()));
+        }
+
+        let mut rings = Vec::with_capacity(coordinates.len());
+        for mut ring in coordinates {
+            for &[lng, lat] in &ring {
+                check_position(lat, lng)?;
+            }
+            ring.dedup();
+            if ring.len() > 1 && ring.first() == ring.last() {
+                ring.pop();
+            }
+            if ring.len() < 3 {
+                return Err(InvalidGeometry(format!(
+                    "a ring needs at least 3 points, got {}",
+                    ring.len()
+                )));
+            }
+            if crosses_itself(&ring) {
+                return Err(InvalidGeometry("a ring crosses itself".to_owned()));
+            }
+            ring.push(ring[0]);
+            rings.push(ring);
+        }
+
+        Ok(Self::Polygon { coordinates: rings })
+    }
+
+    /// Ray casting: inside the border and outside the holes
+    pub fn contains(&self, lat: f64, lng: f64) -> bool {
+        let Self::Polygon { coordinates } = self;
+        let mut rings = coordinates.iter().map(|ring| ring_contains(ring, lat, lng));
+
+        rings.next().unwrap_or(false) && !rings.any(|inside| inside)
+    }
+
+    pub fn to_document(&self) -> Document {
+        let Self::Polygon { coordinates } = self;
+        let rings: Vec<Vec<Vec<f64>>> = coordinates
+            .iter()
+            .map(|ring| ring.iter().map(|c| c.to_vec()).collect())
+            .collect();
+        doc! { "type": "Polygon", "coordinates": rings }
+    }
+}
+
+/// GeoJSON polygon or multipolygon, as open data draws the areas
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum GeoArea {
+    Polygon {
+        coordinates: Vec<Vec<[f64; 2]>>,
+    },
+    MultiPolygon {
+        coordinates: Vec<Vec<Vec<[f64; 2]>>>,
+    },
+}
+
+impl GeoArea {
+    pub fn polygons(self) -> Vec<GeoPolygon> {
+        let polygons = match self {
+            Self::Polygon { coordinates } => vec![coordinates],
+            Self::MultiPolygon { coordinates } => coordinates,
+        };
+        polygons
+            .into_iter()
+            .map(|coordinates| GeoPolygon::Polygon { coordinates })
+            .collect()
+    }
+
+    pub fn validate(self) -> Result<Self, InvalidGeometry> {
+        let mut polygons = vec![];
+        for polygon in self.polygons() {
+            let GeoPolygon::Polygon { coordinates } = polygon.validate()?;
+            polygons.push(coordinates);
+        }
+
+        Ok(match polygons.len() {
+            1 => Self::Polygon {
+                coordinates: polygons.remove(0),
+            },
+            _ => Self::MultiPolygon {
+                coordinates: polygons,
+            },
+        })
+    }
+}
+
+/// Whether two edges of the open ring, not next to each other, touch
+fn crosses_itself(ring: &[[f64; 2]]) -> bool {
+    let n = ring.len();
+    let edge = |i: usize| (ring[i], ring[(i + 1) % n]);
+
+    (0..n).any(|i| {
+        // The last edge is next to the first one
+        let last = if i == 0 { n - 1 } else { n };
+        (i + 2..last).any(|j| {
+            let (a, b) = edge(i);
+            let (c, d) = edge(j);
+            segments_touch(a, b, c, d)
+        })
+    })
+}
+
+fn segments_touch(a: [f64; 2], b: [f64; 2], c: [f64; 2], d: [f64; 2]) -> bool {
+    let orientation = |p: [f64; 2], q: [f64; 2], r: [f64; 2]| {
+        ((q[0] - p[0]) * (r[1] - p[1]) - (q[1] - p[1]) * (r[0] - p[0])).signum()
+    };
+    // r on the segment p q, knowing the three are aligned
+    let within = |p: [f64; 2], q: [f64; 2], r: [f64; 2]| {
+        r[0] >= p[0].min(q[0])
+            && r[0] <= p[0].max(q[0])
+            && r[1] >= p[1].min(q[1])
+            && r[1] <= p[1].max(q[1])
+    };
+
+    let (o1, o2) = (orientation(a, b, c), orientation(a, b, d));
+    let (o3, o4) = (orientation(c, d, a), orientation(c, d, b));
+    if o1 * o2 < 0.0 && o3 * o4 < 0.0 {
+        return true;
+    }
+
+    (o1 == 0.0 && within(a, b, c))
+        || (o2 == 0.0 && within(a, b, d))
+        || (o3 == 0.0 && within(c, d, a))
+        || (o4 == 0.0 && within(c, d, b))
+}
+
+fn ring_contains(ring: &[[f64; 2]], lat: f64, lng: f64) -> bool {
+    let mut inside = false;
+    for (&[x1, y1], &[x2, y2]) in ring.iter().zip(ring.iter().cycle().skip(1)) {
+        if (y1 > lat) != (y2 > lat) && lng < x1 + (lat - y1) * (x2 - x1) / (y2 - y1) {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distance() {
+        // Duomo - Cadorna
+        let d = distance(45.4641, 9.1919, 45.4683, 9.1755);
+        assert!((d - 1361.5).abs() < 1.0, "{}", d);
+        assert_eq!(distance(45.4641, 9.1919, 45.4641, 9.1919), 0.0);
+    }
+
+    #[test]
+    fn test_grid() {
+        let points = [(45.4641, 9.1919), (45.4683, 9.1755), (45.4850, 9.2160)];
+        let grid = Grid::new(points);
+
+        let mut around: Vec<usize> = grid.around(45.4641, 9.1919, 1500.0).collect();
+        around.sort();
+        assert_eq!(around, [0, 1]);
+        assert_eq!(grid.around(45.0, 9.0, 1500.0).count(), 0);
+
+        let pole = Grid::new([(90.0, 0.0), (89.999, 120.0)]);
+        assert_eq!(pole.around(90.0, 0.0, 500.0).count(), 2);
+    }
+
+    #[test]
+    fn test_geohash() {
+        assert_eq!(geohash(57.64911, 10.40744, 11), "u4pruydqqvj");
+        assert_eq!(geohash(45.4641, 9.1919, 6), "u0nd9h");
+
+        let [west, south, east, north] = geohash_bounds("u0nd9h").unwrap();
+        assert!(west <= 9.1919 && 9.1919 < east);
+        assert!(south <= 45.4641 && 45.4641 < north);
+        assert_eq!(geohash_bounds("u0a"), None);
+    }
+
+    #[test]
+    fn test_point() {
+        let point = GeoPoint::new(45.46, 9.19);
+
+        assert_eq!(
+            serde_json::to_value(&point).unwrap(),
+            serde_json::json!({ "type": "Point", "coordinates": [9.19, 45.46] })
+        );
+        assert_eq!(GeoPoint::from_lat_lng(Some(45.46), None), None);
+    }
+
+    #[test]
+    fn test_polygon_validate() {
+        let polygon: GeoPolygon = serde_json::from_value(serde_json::json!({
+            "type": "Polygon",
+            "coordinates": [[[9.1, 45.4], [9.2, 45.4], [9.2, 45.5]]],
+        }))
+        .unwrap();
+
+        let GeoPolygon::Polygon { coordinates } = polygon.validate().unwrap();
+        assert_eq!(coordinates[0].len(), 4);
+        assert_eq!(coordinates[0][0], coordinates[0][3]);
+
+        let line = GeoPolygon::Polygon {
+            coordinates: vec![vec![[9.1, 45.4], [9.2, 45.4]]],
+        };
+        assert!(line.validate().is_err());
+
+        let out_of_range = GeoPolygon::Polygon {
+            coordinates: vec![vec![[45.4, 9.1], [45.4, 9.2], [200.0, 9.2]]],
+        };
+        assert!(out_of_range.validate().is_err());
+
+        let empty = GeoPolygon::Polygon {
+            coordinates: vec![vec![]],
+        };
+        assert!(empty.validate().is_err());
+
+        let bow_tie = GeoPolygon::Polygon {
+            coordinates: vec![vec![[9.1, 45.4], [9.2, 45.5], [9.2, 45.4], [9.1, 45.5]]],
+        };
+        assert!(bow_tie.validate().is_err());
+
+        let closed_twice = GeoPolygon::Polygon {
+            coordinates: vec![vec![
+                [9.1, 45.4],
+                [9.2, 45.4],
+                [9.2, 45.4],
+                [9.2, 45.5],
+                [9.1, 45.4],
+            ]],
+        };
+        let GeoPolygon::Polygon { coordinates } = closed_twice.validate().unwrap();
+        assert_eq!(coordinates[0].len(), 4);
+    }
+
+    #[test]
+    fn test_polygon_contains() {
+        let square = GeoPolygon::Polygon {
+            coordinates: vec![
+                vec![
+                    [9.1, 45.4],
+                    [9.3, 45.4],
+                    [9.3, 45.6],
+                    [9.1, 45.6],
+                    [9.1, 45.4],
+                ],
+                vec![[9.15, 45.45], [9.25, 45.45], [9.25, 45.55], [9.15, 45.45]],
+            ],
+        };
+
+        assert!(square.contains(45.42, 9.2));
+        assert!(!square.contains(45.5, 9.22), "in the hole");
+        assert!(!square.contains(45.7, 9.2));
+        assert!(!square.contains(45.5, 9.0));
+    }
+}
@@ -0,0 +1,493 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::File,
+    io::{Read, Seek},
+};
+
+use serde::{de::DeserializeOwned, Deserialize};
+use tracing::{event, Level};
+use zip::ZipArchive;
+
+use crate::geo;
+
+/// Farthest walk from the start to a stop and from a stop to the destination
+const MAX_ACCESS_WALK: f64 = 1000.0;
+/// Farthest walk between two stops to change line
+const MAX_TRANSFER_WALK: f64 = 300.0;
+/// Nobody commutes for longer than this
+const MAX_TRAVEL_TIME: u32 = 3 * 60 * 60;
+
+#[derive(Debug)]
+pub enum GtfsError {
+    Io(std::io::Error),
+    Zip(zip::result::ZipError),
+    Csv(csv::Error),
+}
+
+impl fmt::Display for GtfsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "io: {}", e),
+            Self::Zip(e) => write!(f, "invalid zip: {}", e),
+            Self::Csv(e) => write!(f, "invalid csv: {}", e),
+        }
+    }
+}
+
+impl From<std::io::Error> for GtfsError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+impl From<zip::result::ZipError> for GtfsError {
+    fn from(e: zip::result::ZipError) -> Self {
+        Self::Zip(e)
+    }
+}
+impl From<csv::Error> for GtfsError {
+    fn from(e: csv::Error) -> Self {
+        Self::Csv(e)
+    }
+}
+
+/// Seconds after midnight of a GTFS time: "25:10:00" is the day after
+pub fn parse_time(time: &str) -> Option<u32> {
+    let mut parts = time.trim().split(':').map(|p| p.parse::<u32>().ok());
+    let hours = parts.next()??;
+    let minutes = parts.next()??;
+    let seconds = parts.next().unwrap_or(Some(0))?;
+    if minutes >= 60 || seconds >= 60 || parts.next().is_some() {
+        return None;
+    }
+    Some(hours * 3600 + minutes * 60 + seconds)
+}
+
+#[derive(Deserialize)]
+struct StopRecord {
+    stop_id: String,
+    stop_lat: f64,
+    stop_lon: f64,
+}
+
+#[derive(Deserialize)]
+struct RouteRecord {
+    route_id: String,
+    #[serde(default)]
+    route_short_name: String,
+    #[serde(default)]
+    route_long_name: String,
+}
+
+#[derive(Deserialize)]
+struct TripRecord {
+    route_id: String,
+    service_id: String,
+    trip_id: String,
+}
+
+#[derive(Deserialize)]
+struct CalendarRecord {
+    service_id: String,
+    monday: u8,
+}
+
+#[derive(Deserialize)]
+struct StopTimeRecord {
+    trip_id: String,
+    arrival_time: Option<String>,
+    departure_time: Option<String>,
+    stop_id: String,
+    stop_sequence: u32,
+}
+
+struct StopPoint {
+    lat: f64,
+    lng: f64,
+}
+
+/// A vehicle going from a stop to the next one without stopping
+struct Connection {
+    from: usize,
+    to: usize,
+    departure: u32,
+    arrival: u32,
+    trip: usize,
+}
+
+#[derive(Clone, Copy)]
+enum Via {
+    Start,
+    Walk(usize),
+    /// Index of the connection where the trip was boarded
+    Ride(usize),
+}
+
+struct Search {
+    arrival: Vec<u32>,
+    via: Vec<Via>,
+    /// Seconds to walk from the stops to the destination
+    egress: HashMap<usize, u32>,
+    /// Arrival at the destination and the stop we left from
+    best: (u32, Option<usize>),
+}
+
+impl Search {
+    fn reach(&mut self, stop: usize, time: u32, via: Via) -> bool {
+        if time >= self.arrival[stop] {
+            return false;
+        }
+        self.arrival[stop] = time;
+        self.via[stop] = via;
+        if let Some(seconds) = self.egress.get(&stop) {
+            if time + seconds < self.best.0 {
+                self.best = (time + seconds, Some(stop));
+            }
+        }
+        true
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Journey {
+    pub minutes: u32,
+    /// The lines taken, in order
+    pub lines: Vec<String>,
+}
+
+/// Earliest arrival router over a GTFS static feed, with the
+/// Connection Scan Algorithm. Only the weekday timetable is kept.
+pub struct Router {
+    stops: Vec<StopPoint>,
+    grid: geo::Grid,
+    /// Stops reachable on foot from every stop, with the seconds needed
+    footpaths: Vec<Vec<(usize, u32)>>,
+    /// Sorted by departure
+    connections: Vec<Connection>,
+    trip_routes: Vec<usize>,
+    route_names: Vec<String>,
+}
+
+fn read<R, T>(
+    archive: &mut ZipArchive<R>,
+    name: &str,
+    mut f: impl FnMut(T),
+) -> Result<(), GtfsError>
+where
+    R: Read + Seek,
+    T: DeserializeOwned,
+{
+    let file = archive.by_name(name)?;
+    for record in csv::Reader::from_reader(file).deserialize() {
+        f(record?);
+    }
+    Ok(())
+}
+
+fn walking_seconds(meters: f64) -> u32 {
+    (meters / geo::WALKING_SPEED).round() as u32
+}
+
+impl Router {
+    /// Reads the GTFS zip. Without a file there is no router and no commute.
+    pub fn load(path: Option<&str>) -> Option<Self> {
+        let path = path?;
+
+        let router = Self::from_zip(File::open(path).unwrap())
+            .unwrap_or_else(|e| panic!("reading the GTFS feed {}: {}", path, e));
+        event!(
+            Level::INFO,
+            stops = router.stops.len(),
+            connections = router.connections.len(),
+            "GTFS feed loaded"
+        );
+
+        Some(router)
+    }
+
+    pub fn from_zip<R: Read + Seek>(reader: R) -> Result<Self, GtfsError> {
+        let mut archive = ZipArchive::new(reader)?;
+
+        let mut stops = vec![];
+        let mut stop_index = HashMap::new();
+        read(&mut archive, "stops.txt", |s: StopRecord| {
+            stop_index.insert(s.stop_id, stops.len());
+            stops.push(StopPoint {
+                lat: s.stop_lat,
+                lng: s.stop_lon,
+            });
+        })?;
+
+        let mut route_names = vec![];
+        let mut route_index = HashMap::new();
+        read(&mut archive, "routes.txt", |r: RouteRecord| {
+            route_index.insert(r.route_id, route_names.len());
+            route_names.push(if r.route_short_name.is_empty() {
+                r.route_long_name
+            } else {
+                r.route_short_name
+            });
+        })?;
+
+        // Feeds with calendar_dates.txt only keep all their trips
+        let mut weekday_services = HashSet::new();
+        if archive.by_name("calendar.txt").is_ok() {
+            read(&mut archive, "calendar.txt", |c: CalendarRecord| {
+                if c.monday == 1 {
+                    weekday_services.insert(c.service_id);
+                }
+            })?;
+        }
+
+        let mut trip_routes = vec![];
+        let mut trip_index = HashMap::new();
+        read(&mut archive, "trips.txt", |t: TripRecord| {
+            if !weekday_services.is_empty() && !weekday_services.contains(&t.service_id) {
+                return;
+            }
+            if let Some(&route) = route_index.get(&t.route_id) {
+                trip_index.insert(t.trip_id, trip_routes.len());
+                trip_routes.push(route);
+            }
+        })?;
+
+        let mut trip_stops: Vec<Vec<(u32, usize, u32, u32)>> = vec![vec![]; trip_routes.len()];
+        read(&mut archive, "stop_times.txt", |st: StopTimeRecord| {
+            let (Some(&trip), Some(&stop)) =
+                (trip_index.get(&st.trip_id), stop_index.get(&st.stop_id))
+            else {
+                return;
+            };
+            // Stops without times are not time points: the vehicle passes by
+            let arrival = st.arrival_time.as_deref().and_then(parse_time);
+            let departure = st.departure_time.as_deref().and_then(parse_time);
+            if let (Some(arrival), Some(departure)) = (arrival.or(departure), departure.or(arrival))
+            {
+                trip_stops[trip].push((st.stop_sequence, stop, arrival, departure));
+            }
+        })?;
+
+        let mut connections = vec![];
+        for (trip, mut times) in trip_stops.into_iter().enumerate() {
+            times.sort_by_key(|t| t.0);
+            for pair in times.windows(2) {
+                let (_, from, _, departure) = pair[0];
+                let (_, to, arrival, _) = pair[1];
+                connections.push(Connection {
+                    from,
+                    to,
+                    departure,
+                    arrival,
+                    trip,
+                });
+            }
+        }
+        connections.sort_by_key(|c| (c.departure, c.arrival));
+
+        let mut router = Self {
+            stops,
+            grid: geo::Grid::default(),
+            footpaths: vec![],
+            connections,
+            trip_routes,
+            route_names,
+        };
+        router.grid = geo::Grid::new(router.stops.iter().map(|s| (s.lat, s.lng)));
+        router.footpaths = (0..router.stops.len())
+            .map(|i| {
+                let stop = &router.stops[i];
+                router
+                    .stops_within(stop.lat, stop.lng, MAX_TRANSFER_WALK)
+                    .into_iter()
+                    .filter(|(j, _)| *j != i)
+                    .collect()
+            })
+            .collect();
+
+        Ok(router)
+    }
+
+    /// Stops within a walk of `meters`, with the seconds needed
+    fn stops_within(&self, lat: f64, lng: f64, meters: f64) -> Vec<(usize, u32)> {
+        self.grid
+            .around(lat, lng, meters)
+            .filter_map(|i| {
+                let stop = &self.stops[i];
+                let distance = geo::walking_distance(lat, lng, stop.lat, stop.lng);
+                (distance <= meters).then(|| (i, walking_seconds(distance)))
+            })
+            .collect()
+    }
+
+    /// Fastest journey leaving at `departure` seconds after midnight
+    pub fn journey(&self, from: (f64, f64), to: (f64, f64), departure: u32) -> Option<Journey> {
+        let latest = departure + MAX_TRAVEL_TIME;
+        let egress: HashMap<usize, u32> = self
+            .stops_within(to.0, to.1, MAX_ACCESS_WALK)
+            .into_iter()
+            .collect();
+
+        let mut search = Search {
+            arrival: vec![u32::MAX; self.stops.len()],
+            via: vec![Via::Start; self.stops.len()],
+            egress,
+            // Walking all the way is a journey too
+            best: (
+                departure + walking_seconds(geo::walking_distance(from.0, from.1, to.0, to.1)),
+                None,
+            ),
+        };
+        let mut boarded: Vec<Option<usize>> = vec![None; self.trip_routes.len()];
+
+        for (stop, seconds) in self.stops_within(from.0, from.1, MAX_ACCESS_WALK) {
+            search.reach(stop, departure + seconds, Via::Start);
+        }
+
+        let first = self
+            .connections
+            .partition_point(|c| c.departure < departure);
+        for (i, c) in self.connections.iter().enumerate().skip(first) {
+            if c.departure >= search.best.0.min(latest) {
+                break;
+            }
+
+            let board = match boarded[c.trip] {
+                Some(board) => board,
+                None if search.arrival[c.from] <= c.departure => {
+                    boarded[c.trip] = Some(i);
+                    i
+                }
+                None => continue,
+            };
+            if search.reach(c.to, c.arrival, Via::Ride(board)) {
+                for &(stop, seconds) in &self.footpaths[c.to] {
+                    search.reach(stop, c.arrival + seconds, Via::Walk(c.to));
+                }
+            }
+        }
+
+        let Search { via, best, .. } = search;
+        let (end, mut stop) = best;
+        if end > latest {
+            return None;
+        }
+
+        let mut lines: Vec<String> = vec![];
+        while let Some(s) = stop {
+            stop = match via[s] {
+                Via::Start => None,
+                Via::Walk(from) => Some(from),
+                Via::Ride(board) => {
+                    let c = &self.connections[board];
+                    lines.push(self.route_names[self.trip_routes[c.trip]].clone());
+                    Some(c.from)
+                }
+            };
+        }
+        lines.reverse();
+        lines.dedup();
+
+        Some(Journey {
+            minutes: (end - departure).div_ceil(60),
+            lines,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use zip::{write::FileOptions, ZipWriter};
+
+    use super::*;
+
+    fn feed() -> Router {
+        let files = [
+            (
+                "stops.txt",
+                "stop_id,stop_name,stop_lat,stop_lon\n\
+                A,Duomo,45.4642,9.1899\n\
+                B,Cadorna,45.4683,9.1755\n\
+                C,Loreto,45.4850,9.2160\n",
+            ),
+            (
+                "routes.txt",
+                "route_id,route_short_name,route_long_name,route_type\n\
+                1,M1,Sesto - Rho,1\n\
+                2,,Cadorna - Loreto,3\n",
+            ),
+            (
+                "calendar.txt",
+                "service_id,monday,tuesday,wednesday,thursday,friday,saturday,sunday,start_date,end_date\n\
+                WD,1,1,1,1,1,0,0,20240101,20241231\n\
+                SUN,0,0,0,0,0,0,1,20240101,20241231\n",
+            ),
+            (
+                "trips.txt",
+                "route_id,service_id,trip_id\n\
+                1,WD,t1\n\
+                2,WD,t2\n\
+                2,SUN,t3\n",
+            ),
+            (
+                "stop_times.txt",
+                "trip_id,arrival_time,departure_time,stop_id,stop_sequence\n\
+                t1,08:05:00,08:05:00,A,1\n\
+                t1,08:10:00,08:10:00,B,2\n\
+                t2,08:15:00,08:15:00,B,1\n\
+                t2,08:30:00,08:30:00,C,2\n\
+                t3,08:11:00,08:11:00,B,1\n\
+                t3,08:20:00,08:20:00,C,2\n",
+            ),
+        ];
+
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        for (name, content) in files {
+            zip.start_file(name, FileOptions::default()).unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        let zip = zip.finish().unwrap();
+
+        Router::from_zip(Cursor::new(zip.into_inner())).unwrap()
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("08:30:00"), Some(8 * 3600 + 30 * 60));
+        assert_eq!(parse_time("8:30"), Some(8 * 3600 + 30 * 60));
+        assert_eq!(parse_time("25:00:00"), Some(25 * 3600));
+        assert_eq!(parse_time("08:75:00"), None);
+        assert_eq!(parse_time("eight"), None);
+    }
+
+    #[test]
+    fn test_journey() {
+        let router = feed();
+        // The Sunday trip is not in the weekday timetable
+        assert_eq!(router.connections.len(), 2);
+
+        let house = (45.4630, 9.1899);
+        let office = (45.4860, 9.2160);
+
+        let journey = router.journey(house, office, parse_time("08:00").unwrap());
+        assert_eq!(
+            journey,
+            Some(Journey {
+                minutes: 32,
+                lines: vec!["M1".to_string(), "Cadorna - Loreto".to_string()],
+            })
+        );
+
+        // Missed the metro: the only way left is walking
+        let journey = router
+            .journey(house, office, parse_time("08:06").unwrap())
+            .unwrap();
+        assert!(journey.lines.is_empty());
+        assert!(journey.minutes > 50);
+
+        // Next door
+        let journey = router.journey(house, (45.4632, 9.1899), 0).unwrap();
+        assert_eq!(journey.minutes, 1);
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::{geo, house_service::ListingType, statistics_service::percentile};
+
+/// A house price, where it is
+#[derive(Debug, Clone, PartialEq)]
+pub struct PriceSample {
+    pub listing_type: ListingType,
+    pub lat: f64,
+    pub lng: f64,
+    pub cost_per_square_meter: f64,
+    /// Delisted: it still tells what the area costs
+    pub removed: bool,
+    /// Another price the portal showed for a listing, see `PriceSnapshotsService`
+    pub snapshot: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct HeatmapCell {
+    pub listing_type: ListingType,
+    pub geohash: String,
+    /// West, south, east, north, to draw it
+    pub bounds: [f64; 4],
+    pub count: usize,
+    pub removed: usize,
+    pub snapshots: usize,
+    pub median: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+/// Cost per square meter statistics on the geohash cells of a precision,
+/// apart for the rents and the sales
+pub fn heatmap(samples: &[PriceSample], precision: usize) -> Vec<HeatmapCell> {
+    let mut cells: BTreeMap<(ListingType, String), Vec<&PriceSample>> = BTreeMap::new();
+    for sample in samples {
+        cells
+            .entry((
+                sample.listing_type,
+                geo::geohash(sample.lat, sample.lng, precision),
+            ))
+            .or_default()
+            .push(sample);
+    }
+
+    cells
+        .into_iter()
+        .map(|((listing_type, geohash), samples)| {
+            let mut costs: Vec<f64> = samples.iter().map(|s| s.cost_per_square_meter).collect();
+            costs.sort_by(f64::total_cmp);
+
+            HeatmapCell {
+                listing_type,
+                bounds: geo::geohash_bounds(&geohash).unwrap(),
+                geohash,
+                count: costs.len(),
+                removed: samples.iter().filter(|s| s.removed).count(),
+                snapshots: samples.iter().filter(|s| s.snapshot).count(),
+                median: percentile(&costs, 0.5),
+                min: costs[0],
+                max: costs[costs.len() - 1],
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(lat: f64, lng: f64, cost_per_square_meter: f64, removed: bool) -> PriceSample {
+        PriceSample {
+            listing_type: ListingType::Rent,
+            lat,
+            lng,
+            cost_per_square_meter,
+            removed,
+            snapshot: false,
+        }
+    }
+
+    #[test]
+    fn test_heatmap() {
+        let samples = [
+            // Duomo
+            sample(45.4641, 9.1919, 30.0, false),
+            sample(45.4642, 9.1920, 20.0, true),
+            sample(45.4640, 9.1918, 25.0, false),
+            sample(45.4641, 9.1921, 40.0, false),
+            // Dergano
+            sample(45.5050, 9.1800, 15.0, false),
+            // Dergano, before the price went down
+            PriceSample {
+                snapshot: true,
+                ..sample(45.5051, 9.1801, 18.0, false)
+            },
+            // Duomo, to buy
+            PriceSample {
+                listing_type: ListingType::Sale,
+                ..sample(45.4641, 9.1919, 9000.0, false)
+            },
+        ];
+
+        let cells = heatmap(&samples, 6);
+        assert_eq!(cells.len(), 3);
+
+        let duomo = cells
+            .iter()
+            .find(|c| c.geohash == "u0nd9h" && c.listing_type == ListingType::Rent)
+            .unwrap();
+        assert_eq!((duomo.count, duomo.removed), (4, 1));
+        assert_eq!((duomo.median, duomo.min, duomo.max), (27.5, 20.0, 40.0));
+
+        let dergano = cells.iter().find(|c| c.geohash != "u0nd9h").unwrap();
+        assert_eq!((dergano.count, dergano.snapshots), (2, 1));
+
+        let city = heatmap(&samples, 4);
+        assert_eq!(city.len(), 2);
+        assert_eq!(city[0].median, 22.5);
+        assert_eq!((city[1].count, city[1].median), (1, 9000.0));
+    }
+}
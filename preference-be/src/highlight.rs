@@ -0,0 +1,147 @@
+use serde::Serialize;
+
+/// Words kept around the first match of a long field
+const SNIPPET_CONTEXT: usize = 8;
+
+/// A piece of a field matching the search, with the matching words
+/// wrapped in `<mark>`. The rest of the text is HTML escaped.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Snippet {
+    pub field: String,
+    pub text: String,
+}
+
+/// Terms of a Mongo `$text` search, without the negated ones.
+/// Quoted phrases are split into words: they are highlighted one by one.
+fn terms(q: &str) -> Vec<String> {
+    q.split_whitespace()
+        .filter(|t| !t.starts_with('-'))
+        .flat_map(|t| t.split(|c: char| !c.is_alphanumeric()))
+        .filter(|t| t.chars().count() > 1)
+        .map(|t| stem(&t.to_lowercase()))
+        .collect()
+}
+
+/// Mongo stems the words before matching them, so "rumorosa" finds "rumoroso".
+/// Cutting the inflected ending is close enough for highlighting.
+fn stem(word: &str) -> String {
+    let len = word.chars().count();
+    let cut = match len {
+        0..=4 => 0,
+        5..=6 => 1,
+        _ => 2,
+    };
+    word.chars().take(len - cut).collect()
+}
+
+fn matches(word: &str, terms: &[String]) -> bool {
+    let word = word.to_lowercase();
+    terms.iter().any(|t| word.starts_with(t.as_str()))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Highlights the words of every field matching the search.
+/// Long fields, like the description, are cut around the first match.
+pub fn snippets(q: &str, fields: &[(&str, Option<&str>)]) -> Vec<Snippet> {
+    let terms = terms(q);
+    if terms.is_empty() {
+        return vec![];
+    }
+
+    fields
+        .iter()
+        .filter_map(|(field, text)| {
+            let text = snippet(text.as_deref()?, &terms)?;
+            Some(Snippet {
+                field: field.to_string(),
+                text,
+            })
+        })
+        .collect()
+}
+
+fn snippet(text: &str, terms: &[String]) -> Option<String> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let core = |w: &str| w.trim_matches(|c: char| !c.is_alphanumeric()).to_owned();
+
+    let first = words.iter().position(|w| matches(&core(w), terms))?;
+    let start = first.saturating_sub(SNIPPET_CONTEXT);
+    let end = (first + SNIPPET_CONTEXT + 1).min(words.len());
+
+    let mut snippet: Vec<String> = words[start..end]
+        .iter()
+        .map(|w| {
+            let core = core(w);
+            if core.is_empty() || !matches(&core, terms) {
+                return escape(w);
+            }
+            // Punctuation around the word stays out of the highlight
+            let (before, rest) = w.split_at(w.find(core.as_str()).unwrap());
+            let after = &rest[core.len()..];
+            format!(
+                "{}<mark>{}</mark>{}",
+                escape(before),
+                escape(&core),
+                escape(after)
+            )
+        })
+        .collect();
+    if start > 0 {
+        snippet.insert(0, "…".to_owned());
+    }
+    if end < words.len() {
+        snippet.push("…".to_owned());
+    }
+
+    Some(snippet.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snippets() {
+        let description = "Luminoso trilocale in zona tranquilla, a due passi dal parco Sempione. \
+            La via è poco trafficata ma il lato cucina affaccia su una strada rumorosa <di notte>.";
+
+        let snippets = snippets(
+            "parco rumoroso -metro",
+            &[
+                ("comment", Some("Vicino al parco")),
+                ("street", Some("Via Metro 3")),
+                ("zone", None),
+                ("description", Some(description)),
+            ],
+        );
+
+        assert_eq!(
+            snippets,
+            vec![
+                Snippet {
+                    field: "comment".to_owned(),
+                    text: "Vicino al <mark>parco</mark>".to_owned(),
+                },
+                Snippet {
+                    field: "description".to_owned(),
+                    text: "… trilocale in zona tranquilla, a due passi dal <mark>parco</mark> \
+                        Sempione. La via è poco trafficata ma il …"
+                        .to_owned(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_snippet_escape() {
+        let snippets = snippets("rumorosa", &[("comment", Some("(strada <rumorosa>)"))]);
+
+        assert_eq!(snippets[0].text, "(strada &lt;<mark>rumorosa</mark>&gt;)");
+    }
+}
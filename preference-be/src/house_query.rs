@@ -0,0 +1,869 @@
+use mongodb::bson::{doc, oid::ObjectId, Bson, DateTime, Document};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cluster,
+    geo::{self, GeoPoint, GeoPolygon, InvalidGeometry},
+    house_service::ListingType,
+    similarity, transit_stops,
+};
+
+/// A ten minutes walk
+const DEFAULT_LINE_DISTANCE: u32 = 800;
+const DEFAULT_GEOHASH_PRECISION: usize = 6;
+/// Cells of a few meters: smaller than a house
+const MAX_GEOHASH_PRECISION: usize = 9;
+
+/// Query parameters of `GET /api/houses`.
+/// Everything is translated into a Mongo filter and sort:
+/// nothing is filtered in memory.
+#[derive(Deserialize, Default)]
+pub struct HousesQuery {
+    /// Comma separated amenities the houses must have
+    pub amenities: Option<String>,
+
+    pub min_cost: Option<u32>,
+    pub max_cost: Option<u32>,
+    pub min_rooms: Option<u32>,
+    pub max_rooms: Option<u32>,
+    pub min_square_meters: Option<u32>,
+    pub max_square_meters: Option<u32>,
+    pub min_vote: Option<u32>,
+    pub max_vote: Option<u32>,
+    pub listing_type: Option<ListingType>,
+    pub city: Option<String>,
+    pub zone: Option<String>,
+    /// Official neighborhood, see `GET /api/neighborhoods`
+    pub neighborhood: Option<String>,
+    #[serde(default)]
+    pub state: HouseState,
+    /// Comma separated metro lines: the houses must be near any of them
+    pub line: Option<String>,
+    /// Meters, for `line`
+    pub line_distance: Option<u32>,
+    /// Comma separated layer names: the houses must be inside a feature of each
+    pub in_layer: Option<String>,
+    /// Comma separated layer names: the houses must be outside all their features
+    pub outside_layer: Option<String>,
+    /// Comma separated `layer.property=value`: the houses must be inside
+    /// a feature with that property
+    pub layer_property: Option<String>,
+    /// Id of a point of interest, for `max_poi_distance` and `sort=poi_distance`
+    pub poi: Option<String>,
+    /// Meters, straight line from `poi`
+    pub max_poi_distance: Option<u32>,
+    /// Id of a scoring profile, for `sort=score`
+    pub profile: Option<String>,
+    /// For `sort=rating`
+    pub user: Option<String>,
+    /// Full-text search over comment, address and description.
+    /// The houses are ranked by relevance and `sort` is ignored.
+    pub q: Option<String>,
+
+    #[serde(default)]
+    pub sort: HouseSort,
+    pub order: Option<SortOrder>,
+    /// Page size: without it all the matching houses are returned
+    pub limit: Option<u32>,
+    /// The `x-next-cursor` header of the previous page
+    pub cursor: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HouseState {
+    #[default]
+    Available,
+    Removed,
+    All,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HouseSort {
+    #[default]
+    InsertedAt,
+    Cost,
+    CostPerSquareMeter,
+    Vote,
+    /// Needs `poi`
+    PoiDistance,
+    /// Needs `profile`
+    Score,
+    /// The pairwise comparisons ranking, needs `user`
+    Rating,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+/// Position after the last house of a page
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Cursor {
+    value: Option<f64>,
+    id: String,
+}
+
+#[derive(Debug)]
+pub struct InvalidQuery(pub String);
+
+/// A user, a layer name or an amenity, which ends up in a field path of the houses:
+/// `votes.<user>`, `layers.<name>`, `amenities.<amenity>`...
+pub fn validate_field_name<'a>(kind: &str, name: &'a str) -> Result<&'a str, InvalidQuery> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid {
+        return Err(InvalidQuery(format!(
+            "invalid {} {:?}: letters, digits, '_' and '-' only",
+            kind, name
+        )));
+    }
+    Ok(name)
+}
+
+impl Cursor {
+    pub fn new(value: Option<f64>, id: ObjectId) -> Self {
+        Self {
+            value,
+            id: id.to_hex(),
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        base64::encode_config(serde_json::to_vec(self).unwrap(), base64::URL_SAFE_NO_PAD)
+    }
+
+    pub fn decode(cursor: &str) -> Result<Self, InvalidQuery> {
+        base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)
+            .ok()
+            .and_then(|c| serde_json::from_slice(&c).ok())
+            .ok_or_else(|| InvalidQuery(cursor.to_owned()))
+    }
+}
+
+impl HousesQuery {
+    pub fn amenities(&self) -> impl Iterator<Item = &str> {
+        self.amenities
+            .iter()
+            .flat_map(|a| a.split(','))
+            .map(|a| a.trim())
+            .filter(|a| !a.is_empty())
+    }
+
+    /// Named as the stops name them: "1" and "m1" are "M1"
+    pub fn lines(&self) -> impl Iterator<Item = String> + '_ {
+        self.line.iter().flat_map(|l| transit_stops::lines(l))
+    }
+
+    fn layer_filters(&self) -> Result<Document, InvalidQuery> {
+        let names = |names: &Option<String>| -> Vec<String> {
+            names
+                .iter()
+                .flat_map(|n| n.split(','))
+                .map(|n| n.trim().to_owned())
+                .filter(|n| !n.is_empty())
+                .collect()
+        };
+        let mut filter = Document::new();
+        for layer in names(&self.in_layer) {
+            validate_field_name("layer name", &layer)?;
+            filter.insert(format!("layers.{}.0", layer), doc! { "$exists": true });
+        }
+        for layer in names(&self.outside_layer) {
+            validate_field_name("layer name", &layer)?;
+            filter.insert(format!("layers.{}.0", layer), doc! { "$exists": false });
+        }
+        for condition in names(&self.layer_property) {
+            let invalid = || InvalidQuery(format!("invalid layer property {:?}", condition));
+            let (path, value) = condition.split_once('=').ok_or_else(invalid)?;
+            let (layer, property) = path.split_once('.').ok_or_else(invalid)?;
+            validate_field_name("layer name", layer)?;
+            // The properties keep the names of the GeoJSON, any Mongo takes as a field
+            if property.is_empty() || property.contains(['.', '$']) {
+                return Err(invalid());
+            }
+
+            // The properties keep the types of the GeoJSON
+            let mut values = vec![Bson::from(value)];
+            if let Ok(n) = value.parse::<i64>() {
+                values.push(n.into());
+            } else if let Ok(n) = value.parse::<f64>() {
+                values.push(n.into());
+            }
+            filter.insert(
+                format!("layers.{}.properties.{}", layer, property),
+                doc! { "$in": values },
+            );
+        }
+        Ok(filter)
+    }
+
+    pub fn line_distance(&self) -> u32 {
+        self.line_distance.unwrap_or(DEFAULT_LINE_DISTANCE)
+    }
+
+    pub fn search(&self) -> Option<&str> {
+        self.q.as_deref().map(str::trim).filter(|q| !q.is_empty())
+    }
+
+    /// Insertion date defaults to the newest first, score and rating to the best first,
+    /// the rest to the cheapest first
+    pub fn order(&self) -> SortOrder {
+        match (self.order, self.sort) {
+            (Some(order), _) => order,
+            (None, HouseSort::InsertedAt | HouseSort::Score | HouseSort::Rating) => SortOrder::Desc,
+            (None, _) => SortOrder::Asc,
+        }
+    }
+
+    /// `None` for the insertion date: the `_id` already sorts by it
+    fn sort_field(&self) -> Option<String> {
+        match self.sort {
+            HouseSort::InsertedAt => None,
+            HouseSort::Cost => Some("cost".to_owned()),
+            HouseSort::CostPerSquareMeter => Some("cost_per_square_meter".to_owned()),
+            HouseSort::Vote => Some("vote".to_owned()),
+            HouseSort::PoiDistance => self.poi_field(),
+            HouseSort::Score => self
+                .profile
+                .as_ref()
+                .map(|profile| format!("scores.{}.total", profile)),
+            HouseSort::Rating => self
+                .user
+                .as_ref()
+                .map(|user| format!("ratings.{}.rating", user)),
+        }
+    }
+
+    fn poi_field(&self) -> Option<String> {
+        self.poi
+            .as_ref()
+            .map(|poi| format!("poi_distances.{}.meters", poi))
+    }
+
+    pub fn filter(&self) -> Result<Document, InvalidQuery> {
+        let mut filter = Document::new();
+
+        // The id ends up in a field path
+        if let Some(poi) = &self.poi {
+            ObjectId::parse_str(poi).map_err(|_| InvalidQuery(poi.clone()))?;
+        } else if self.sort == HouseSort::PoiDistance {
+            return Err(InvalidQuery("sort=poi_distance without poi".to_owned()));
+        }
+        if let Some(profile) = &self.profile {
+            ObjectId::parse_str(profile).map_err(|_| InvalidQuery(profile.clone()))?;
+        } else if self.sort == HouseSort::Score {
+            return Err(InvalidQuery("sort=score without profile".to_owned()));
+        }
+        if let Some(user) = &self.user {
+            validate_field_name("user", user)?;
+        } else if self.sort == HouseSort::Rating {
+            return Err(InvalidQuery("sort=rating without user".to_owned()));
+        }
+
+        match self.state {
+            HouseState::Available => {
+                filter.insert("removed", false);
+            }
+            HouseState::Removed => {
+                filter.insert("removed", true);
+            }
+            HouseState::All => {}
+        }
+
+        for amenity in self.amenities() {
+            validate_field_name("amenity", amenity)?;
+            filter.insert(format!("amenities.{}", amenity), true);
+        }
+
+        insert_range(&mut filter, "cost", self.min_cost, self.max_cost);
+        insert_range(&mut filter, "rooms_number", self.min_rooms, self.max_rooms);
+        insert_range(
+            &mut filter,
+            "square_meters",
+            self.min_square_meters,
+            self.max_square_meters,
+        );
+        insert_range(&mut filter, "vote", self.min_vote, self.max_vote);
+
+        if let Some(listing_type) = self.listing_type {
+            filter.insert(
+                "listing_type",
+                mongodb::bson::to_bson(&listing_type).unwrap(),
+            );
+        }
+        if let Some(city) = &self.city {
+            filter.insert("city", city);
+        }
+        if let Some(zone) = &self.zone {
+            filter.insert("zone", zone);
+        }
+        if let Some(neighborhood) = &self.neighborhood {
+            filter.insert("neighborhood", neighborhood);
+        }
+        let lines: Vec<Document> = self
+            .lines()
+            .map(|line| doc! { format!("line_distances.{}", line): { "$lte": self.line_distance() } })
+            .collect();
+        if !lines.is_empty() {
+            filter.insert("$or", lines);
+        }
+        if let (Some(field), Some(max)) = (self.poi_field(), self.max_poi_distance) {
+            filter.insert(field, doc! { "$lte": max });
+        }
+        if let Some(q) = self.search() {
+            filter.insert("$text", doc! { "$search": q });
+        }
+        filter.extend(self.layer_filters()?);
+
+        if let Some(cursor) = &self.cursor {
+            // The relevance cannot be compared in a filter
+            if self.search().is_some() {
+                return Err(InvalidQuery(cursor.clone()));
+            }
+            let cursor = Cursor::decode(cursor)?;
+            let after = self.after(&cursor)?;
+            filter = doc! { "$and": [filter, after] };
+        }
+
+        Ok(filter)
+    }
+
+    pub fn sort(&self) -> Document {
+        if self.search().is_some() {
+            return doc! { "score": { "$meta": "textScore" }, "_id": -1 };
+        }
+
+        let direction = match self.order() {
+            SortOrder::Asc => 1,
+            SortOrder::Desc => -1,
+        };
+
+        let mut sort = Document::new();
+        if let Some(field) = self.sort_field() {
+            sort.insert(field, direction);
+        }
+        // Tie breaker: the cursor needs a total order
+        sort.insert("_id", direction);
+        sort
+    }
+
+    /// Filter for the houses after the cursor.
+    /// Mongo sorts the missing values before any number,
+    /// which cannot be compared with `$gt`/`$lt`.
+    fn after(&self, cursor: &Cursor) -> Result<Document, InvalidQuery> {
+        let id = ObjectId::parse_str(&cursor.id).map_err(|_| InvalidQuery(cursor.id.clone()))?;
+        let (cmp, asc) = match self.order() {
+            SortOrder::Asc => ("$gt", true),
+            SortOrder::Desc => ("$lt", false),
+        };
+
+        let field = match self.sort_field() {
+            None => return Ok(doc! { "_id": { cmp: id } }),
+            Some(field) => field,
+        };
+        let field = field.as_str();
+
+        let after = match (cursor.value, asc) {
+            (None, true) => doc! { "$or": [
+                { field: null, "_id": { cmp: id } },
+                { field: { "$ne": null } },
+            ] },
+            (None, false) => doc! { field: null, "_id": { cmp: id } },
+            (Some(value), true) => doc! { "$or": [
+                { field: { cmp: value } },
+                { field: value, "_id": { cmp: id } },
+            ] },
+            (Some(value), false) => doc! { "$or": [
+                { field: { cmp: value } },
+                { field: value, "_id": { cmp: id } },
+                { field: null },
+            ] },
+        };
+
+        Ok(after)
+    }
+}
+
+/// Query parameters of `GET /api/houses/near`
+#[derive(Deserialize)]
+pub struct NearQuery {
+    pub lat: f64,
+    pub lng: f64,
+    /// Meters
+    pub radius: f64,
+}
+
+impl NearQuery {
+    pub fn point(&self) -> Result<GeoPoint, InvalidQuery> {
+        if !self.radius.is_finite() || self.radius < 0.0 {
+            return Err(InvalidQuery(format!("invalid radius {}", self.radius)));
+        }
+        geo::check_position(self.lat, self.lng).map_err(|e| InvalidQuery(e.0))?;
+
+        Ok(GeoPoint::new(self.lat, self.lng))
+    }
+}
+
+/// Query parameters of `GET /api/houses/compare`
+#[derive(Deserialize)]
+pub struct CompareQuery {
+    /// Comma separated house ids
+    pub ids: String,
+}
+
+impl CompareQuery {
+    /// Side by side fit on a screen
+    pub const MAX_HOUSES: usize = 4;
+
+    pub fn ids(&self) -> Result<Vec<ObjectId>, InvalidQuery> {
+        let mut ids: Vec<ObjectId> = Vec::new();
+        for id in self
+            .ids
+            .split(',')
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+        {
+            let id = ObjectId::parse_str(id)
+                .map_err(|_| InvalidQuery(format!("invalid house id {:?}", id)))?;
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+
+        if !(2..=Self::MAX_HOUSES).contains(&ids.len()) {
+            return Err(InvalidQuery(format!(
+                "{} houses: compare from 2 to {}",
+                ids.len(),
+                Self::MAX_HOUSES
+            )));
+        }
+        Ok(ids)
+    }
+}
+
+/// Query parameters of `GET /api/houses/{id}/similar`
+#[derive(Deserialize)]
+pub struct SimilarQuery {
+    /// 10 by default
+    pub limit: Option<usize>,
+}
+
+impl SimilarQuery {
+    pub fn limit(&self) -> Result<usize, InvalidQuery> {
+        match self.limit.unwrap_or(similarity::DEFAULT_NEIGHBOURS) {
+            limit @ 1..=similarity::MAX_NEIGHBOURS => Ok(limit),
+            limit => Err(InvalidQuery(format!("limit {}", limit))),
+        }
+    }
+}
+
+/// Query parameters of `GET /api/houses/bbox`, the map viewport
+#[derive(Deserialize)]
+pub struct BoundingBoxQuery {
+    pub west: f64,
+    pub south: f64,
+    pub east: f64,
+    pub north: f64,
+}
+
+impl BoundingBoxQuery {
+    pub fn polygon(&self) -> Result<GeoPolygon, InvalidGeometry> {
+        if self.west >= self.east || self.south >= self.north {
+            return Err(InvalidGeometry(format!(
+                "empty bounding box {},{},{},{}",
+                self.west, self.south, self.east, self.north
+            )));
+        }
+        GeoPolygon::bounding_box(self.west, self.south, self.east, self.north).validate()
+    }
+}
+
+/// Query parameters of `GET /api/houses/clusters`: the map viewport
+#[derive(Deserialize)]
+pub struct ClusterQuery {
+    pub west: f64,
+    pub south: f64,
+    pub east: f64,
+    pub north: f64,
+    pub zoom: u8,
+    /// Also the crawled candidates with a position
+    #[serde(default)]
+    pub candidates: bool,
+}
+
+impl ClusterQuery {
+    pub fn bounding_box(&self) -> BoundingBoxQuery {
+        BoundingBoxQuery {
+            west: self.west,
+            south: self.south,
+            east: self.east,
+            north: self.north,
+        }
+    }
+
+    pub fn zoom(&self) -> Result<u8, InvalidQuery> {
+        if self.zoom > cluster::MAX_ZOOM {
+            return Err(InvalidQuery(format!("zoom {} too deep", self.zoom)));
+        }
+        Ok(self.zoom)
+    }
+}
+
+/// Query parameters of `GET /api/houses/heatmap`
+#[derive(Deserialize)]
+pub struct HeatmapQuery {
+    /// Rents and sales are priced too far apart to share a cell
+    pub listing_type: ListingType,
+    pub west: f64,
+    pub south: f64,
+    pub east: f64,
+    pub north: f64,
+    /// Geohash length, 6 by default: cells of about 1.2 x 0.6 km
+    pub precision: Option<usize>,
+    /// Houses inserted, and other prices seen, from this date, RFC 3339 or "YYYY-MM-DD"
+    pub from: Option<String>,
+    /// Houses inserted, and other prices seen, before this date
+    pub to: Option<String>,
+}
+
+impl HeatmapQuery {
+    pub fn bounding_box(&self) -> BoundingBoxQuery {
+        BoundingBoxQuery {
+            west: self.west,
+            south: self.south,
+            east: self.east,
+            north: self.north,
+        }
+    }
+
+    pub fn precision(&self) -> Result<usize, InvalidQuery> {
+        match self.precision.unwrap_or(DEFAULT_GEOHASH_PRECISION) {
+            precision @ 1..=MAX_GEOHASH_PRECISION => Ok(precision),
+            precision => Err(InvalidQuery(format!("precision {}", precision))),
+        }
+    }
+
+    /// The `_id` starts with the insertion time
+    pub fn window(&self) -> Result<Document, InvalidQuery> {
+        let mut range = Document::new();
+        if let Some(from) = &self.from {
+            range.insert("$gte", id_at(from)?);
+        }
+        if let Some(to) = &self.to {
+            range.insert("$lt", id_at(to)?);
+        }
+        if range.is_empty() {
+            return Ok(doc! {});
+        }
+        Ok(doc! { "_id": range })
+    }
+}
+
+/// The smallest id of a date
+fn id_at(date: &str) -> Result<ObjectId, InvalidQuery> {
+    let time = match date.len() {
+        10 => format!("{}T00:00:00Z", date),
+        _ => date.to_owned(),
+    };
+    let time = DateTime::parse_rfc3339_str(&time).map_err(|_| InvalidQuery(date.to_owned()))?;
+    let seconds =
+        u32::try_from(time.timestamp_millis() / 1000).map_err(|_| InvalidQuery(date.to_owned()))?;
+
+    let mut bytes = [0; 12];
+    bytes[..4].copy_from_slice(&seconds.to_be_bytes());
+    Ok(ObjectId::from_bytes(bytes))
+}
+
+fn insert_range<T: Into<Bson>>(filter: &mut Document, field: &str, min: Option<T>, max: Option<T>) {
+    let mut range = Document::new();
+    if let Some(min) = min {
+        range.insert("$gte", min.into());
+    }
+    if let Some(max) = max {
+        range.insert("$lte", max.into());
+    }
+    if !range.is_empty() {
+        filter.insert(field, range);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let query: HousesQuery = serde_urlencoded::from_str(
+            "min_cost=800&max_cost=1200&min_rooms=2&listing_type=rent&city=Milano&neighborhood=DERGANO&state=all",
+        )
+        .unwrap();
+
+        assert_eq!(
+            query.filter().unwrap(),
+            doc! {
+                "cost": { "$gte": 800, "$lte": 1200 },
+                "rooms_number": { "$gte": 2 },
+                "listing_type": "rent",
+                "city": "Milano",
+                "neighborhood": "DERGANO",
+            }
+        );
+        assert_eq!(query.sort(), doc! { "_id": -1 });
+    }
+
+    #[test]
+    fn test_cursor() {
+        let id = ObjectId::new();
+        let cursor = Cursor::new(Some(1200.0), id).encode();
+
+        let query: HousesQuery =
+            serde_urlencoded::from_str(&format!("sort=cost&cursor={}", cursor)).unwrap();
+
+        assert_eq!(query.sort(), doc! { "cost": 1, "_id": 1 });
+        assert_eq!(
+            query.filter().unwrap(),
+            doc! { "$and": [
+                { "removed": false },
+                { "$or": [
+                    { "cost": { "$gt": 1200.0 } },
+                    { "cost": 1200.0, "_id": { "$gt": id } },
+                ] },
+            ] }
+        );
+
+        let query = HousesQuery {
+            cursor: Some("not-a-cursor".to_string()),
+            ..HousesQuery::default()
+        };
+        assert!(query.filter().is_err());
+    }
+
+    #[test]
+    fn test_amenities() {
+        let query: HousesQuery =
+            serde_urlencoded::from_str("amenities=balcony,%20elevator").unwrap();
+        assert_eq!(
+            query.filter().unwrap(),
+            doc! { "removed": false, "amenities.balcony": true, "amenities.elevator": true }
+        );
+
+        for amenities in ["$where", "balcony.x"] {
+            let query = HousesQuery {
+                amenities: Some(amenities.to_string()),
+                ..HousesQuery::default()
+            };
+            assert!(query.filter().is_err());
+        }
+    }
+
+    #[test]
+    fn test_lines() {
+        let query: HousesQuery = serde_urlencoded::from_str("line=m1,3&line_distance=500").unwrap();
+
+        assert_eq!(
+            query.filter().unwrap(),
+            doc! {
+                "removed": false,
+                "$or": [
+                    { "line_distances.M1": { "$lte": 500 } },
+                    { "line_distances.M3": { "$lte": 500 } },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_poi() {
+        let poi = ObjectId::new().to_hex();
+        let query: HousesQuery = serde_urlencoded::from_str(&format!(
+            "poi={}&max_poi_distance=2000&sort=poi_distance",
+            poi
+        ))
+        .unwrap();
+
+        let field = format!("poi_distances.{}.meters", poi);
+        assert_eq!(
+            query.filter().unwrap(),
+            doc! { "removed": false, field.as_str(): { "$lte": 2000 } }
+        );
+        assert_eq!(query.sort(), doc! { field.as_str(): 1, "_id": 1 });
+
+        let without_poi: HousesQuery = serde_urlencoded::from_str("sort=poi_distance").unwrap();
+        assert!(without_poi.filter().is_err());
+        let not_an_id: HousesQuery = serde_urlencoded::from_str("poi=$where").unwrap();
+        assert!(not_an_id.filter().is_err());
+    }
+
+    #[test]
+    fn test_score() {
+        let profile = ObjectId::new().to_hex();
+        let query: HousesQuery =
+            serde_urlencoded::from_str(&format!("profile={}&sort=score", profile)).unwrap();
+
+        let field = format!("scores.{}.total", profile);
+        assert_eq!(query.filter().unwrap(), doc! { "removed": false });
+        // The best first
+        assert_eq!(query.sort(), doc! { field.as_str(): -1, "_id": -1 });
+
+        let without_profile: HousesQuery = serde_urlencoded::from_str("sort=score").unwrap();
+        assert!(without_profile.filter().is_err());
+        let not_an_id: HousesQuery = serde_urlencoded::from_str("profile=a.b").unwrap();
+        assert!(not_an_id.filter().is_err());
+    }
+
+    #[test]
+    fn test_rating() {
+        let query: HousesQuery = serde_urlencoded::from_str("user=alice&sort=rating").unwrap();
+        assert_eq!(query.filter().unwrap(), doc! { "removed": false });
+        assert_eq!(query.sort(), doc! { "ratings.alice.rating": -1, "_id": -1 });
+
+        let without_user: HousesQuery = serde_urlencoded::from_str("sort=rating").unwrap();
+        assert!(without_user.filter().is_err());
+        let path: HousesQuery = serde_urlencoded::from_str("user=a.b&sort=rating").unwrap();
+        assert!(path.filter().is_err());
+        // Nor a user the votes would refuse
+        let space: HousesQuery = serde_urlencoded::from_str("user=a+b&sort=rating").unwrap();
+        assert!(space.filter().is_err());
+    }
+
+    #[test]
+    fn test_layers() {
+        let query: HousesQuery = serde_urlencoded::from_str(
+            "in_layer=ztl&outside_layer=flood&layer_property=noise.source%3Droad,noise.level%3D65",
+        )
+        .unwrap();
+
+        assert_eq!(
+            query.filter().unwrap(),
+            doc! {
+                "removed": false,
+                "layers.ztl.0": { "$exists": true },
+                "layers.flood.0": { "$exists": false },
+                "layers.noise.properties.source": { "$in": ["road"] },
+                "layers.noise.properties.level": { "$in": ["65", 65_i64] },
+            }
+        );
+
+        let not_a_property: HousesQuery =
+            serde_urlencoded::from_str("layer_property=noise%3D65").unwrap();
+        assert!(not_a_property.filter().is_err());
+        let injection: HousesQuery = serde_urlencoded::from_str("in_layer=ztl.$where").unwrap();
+        assert!(injection.filter().is_err());
+    }
+
+    #[test]
+    fn test_heatmap_window() {
+        let query: HeatmapQuery = serde_urlencoded::from_str(
+            "listing_type=rent&west=9.1&south=45.4&east=9.3&north=45.5&from=2024-01-01&to=2024-02-01T00:00:00Z",
+        )
+        .unwrap();
+
+        let window = query.window().unwrap();
+        let range = window.get_document("_id").unwrap();
+        let from = range.get_object_id("$gte").unwrap();
+        let to = range.get_object_id("$lt").unwrap();
+        assert_eq!(from.timestamp().timestamp_millis(), 1_704_067_200_000);
+        assert_eq!(to.timestamp().timestamp_millis(), 1_706_745_600_000);
+        assert_eq!(query.precision().unwrap(), 6);
+
+        let invalid: HeatmapQuery = serde_urlencoded::from_str(
+            "listing_type=sale&west=9.1&south=45.4&east=9.3&north=45.5&from=yesterday&precision=12",
+        )
+        .unwrap();
+        assert!(invalid.window().is_err());
+        assert!(invalid.precision().is_err());
+
+        // Rents and sales do not mix
+        assert!(serde_urlencoded::from_str::<HeatmapQuery>(
+            "west=9.1&south=45.4&east=9.3&north=45.5"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_validate_field_name() {
+        assert!(validate_field_name("layer name", "noise-2022").is_ok());
+        assert!(validate_field_name("user", "alice_1").is_ok());
+        assert!(validate_field_name("layer name", "flood.zones").is_err());
+        assert!(validate_field_name("user", "$where").is_err());
+        assert!(validate_field_name("user", "").is_err());
+    }
+
+    #[test]
+    fn test_compare_ids() {
+        let (a, b) = (ObjectId::new(), ObjectId::new());
+        let query = CompareQuery {
+            ids: format!("{}, {},{},", a, b, a),
+        };
+        assert_eq!(query.ids().unwrap(), [a, b]);
+
+        let alone = CompareQuery { ids: a.to_hex() };
+        assert!(alone.ids().is_err());
+        let invalid = CompareQuery {
+            ids: format!("{},nope", a),
+        };
+        assert!(invalid.ids().is_err());
+        let many = CompareQuery {
+            ids: (0..5)
+                .map(|_| ObjectId::new().to_hex())
+                .collect::<Vec<_>>()
+                .join(","),
+        };
+        assert!(many.ids().is_err());
+    }
+
+    #[test]
+    fn test_near_point() {
+        let query: NearQuery = serde_urlencoded::from_str("lat=45.46&lng=9.19&radius=500").unwrap();
+        assert_eq!(query.point().unwrap(), GeoPoint::new(45.46, 9.19));
+
+        for invalid in [
+            "lat=45.46&lng=9.19&radius=-1",
+            "lat=45.46&lng=9.19&radius=NaN",
+            "lat=95&lng=9.19&radius=500",
+            "lat=45.46&lng=190&radius=500",
+        ] {
+            let query: NearQuery = serde_urlencoded::from_str(invalid).unwrap();
+            assert!(query.point().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_similar_limit() {
+        let query: SimilarQuery = serde_urlencoded::from_str("").unwrap();
+        assert_eq!(query.limit().unwrap(), similarity::DEFAULT_NEIGHBOURS);
+
+        let query: SimilarQuery = serde_urlencoded::from_str("limit=0").unwrap();
+        assert!(query.limit().is_err());
+        let query: SimilarQuery = serde_urlencoded::from_str("limit=500").unwrap();
+        assert!(query.limit().is_err());
+    }
+
+    #[test]
+    fn test_search() {
+        let query: HousesQuery = serde_urlencoded::from_str("q=parco+rumoroso&sort=cost").unwrap();
+
+        assert_eq!(
+            query.filter().unwrap(),
+            doc! { "removed": false, "$text": { "$search": "parco rumoroso" } }
+        );
+        assert_eq!(
+            query.sort(),
+            doc! { "score": { "$meta": "textScore" }, "_id": -1 }
+        );
+
+        let query = HousesQuery {
+            q: Some("parco".to_string()),
+            cursor: Some(Cursor::new(None, ObjectId::new()).encode()),
+            ..HousesQuery::default()
+        };
+        assert!(query.filter().is_err());
+    }
+}
@@ -1,28 +1,128 @@
-use std::str::FromStr;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt,
+    future::Future,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use futures::TryStreamExt;
+use futures::{future, TryStreamExt};
 use mongodb::{
-    bson::{doc, oid::ObjectId},
-    options::UpdateOptions,
+    bson::{doc, oid::ObjectId, Document},
+    options::{FindOptions, IndexOptions, UpdateOptions},
     results::InsertOneResult,
-    Collection,
+    Collection, IndexModel,
 };
 use serde::{Deserialize, Serialize};
 use tracing::{event, Level};
 
+use crate::{
+    agency_service::{AgenciesService, AgenciesServiceError},
+    amenity_tagger::AmenityTagger,
+    area_service::{AreaFlag, AreasService, AreasServiceError},
+    candidate_service::CandidateDTO,
+    cluster::{Marker, MarkerKind},
+    commute_service::{CommuteDTO, CommuteEntity, CommuteService, CommuteServiceError},
+    comparison::{Better, Comparison, CriterionComparison},
+    discovery_service::{Advertiser, DiscoveryResult},
+    elo::{self, Rating},
+    fair_price::{FairPrice, FairPriceModel, Listing},
+    geo::{self, GeoPoint, GeoPolygon, InvalidGeometry},
+    heatmap::PriceSample,
+    highlight::{self, Snippet},
+    house_query::{
+        validate_field_name, BoundingBoxQuery, CompareQuery, Cursor, HeatmapQuery, HouseSort,
+        HousesQuery, InvalidQuery, NearQuery, SimilarQuery,
+    },
+    layer_service::{LayerMatch, LayerMatchDTO, LayersService, LayersServiceError},
+    media_service::{MediaDTO, MediaEntity, MediaService},
+    nearby_service::NearbyService,
+    neighborhoods::Neighborhoods,
+    osm::AmenityCounts,
+    poi_service::{PoiDistance, PoisService, PoisServiceError},
+    preference::{PredictedVote, VoteFeatures, VoteModel, MAX_VOTE},
+    price_snapshot_service::{
+        PriceSnapshotEntity, PriceSnapshotsService, PriceSnapshotsServiceError,
+    },
+    risk::{RiskAnalyzer, RiskFeatures, RiskFlag},
+    scoring_service::{
+        Criterion, Score, ScoringProfileEntity, ScoringService, ScoringServiceError,
+    },
+    similarity::{self, SimilarityFeatures},
+    transit_stops::{NearestStop, TransitStops},
+};
+
 #[derive(Clone)]
 pub struct HousesService {
     collection: Collection<HouseEntity>,
+    media_service: MediaService,
+    amenity_tagger: AmenityTagger,
+    agencies_service: AgenciesService,
+    transit_stops: TransitStops,
+    commute_service: CommuteService,
+    pois_service: PoisService,
+    nearby_service: NearbyService,
+    neighborhoods: Neighborhoods,
+    layers_service: LayersService,
+    areas_service: AreasService,
+    scoring_service: ScoringService,
+    price_snapshots_service: PriceSnapshotsService,
+    /// The refreshes asked while the running one goes, see `refresh_in_background`
+    pending_refreshes: Arc<std::sync::Mutex<BTreeSet<Refresh>>>,
+    refreshing: Arc<tokio::sync::Mutex<()>>,
+    /// When each house was last fetched again, see `reprice_link`
+    repriced: Arc<std::sync::Mutex<HashMap<ObjectId, Instant>>>,
 }
 
 type Result<T> = std::result::Result<T, HousesServiceError>;
 
+/// Houses written per update by the refreshes
+const WRITE_BATCH: usize = 500;
+/// Reads and writes of a pick before giving up, see `HousesService::compare`
+const COMPARE_ATTEMPTS: usize = 5;
+/// Between two fetches of a house from its portal, not to get banned
+const REPRICE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
 #[derive(Debug)]
 pub enum HousesServiceError {
     MongoDbError(mongodb::error::Error),
     ObjectId(mongodb::bson::oid::Error),
     HouseNotFound(String),
     UnExpectedMongoDbType,
+    AgencyError(AgenciesServiceError),
+    CommuteError(CommuteServiceError),
+    PoiError(PoisServiceError),
+    LayerError(LayersServiceError),
+    AreaError(AreasServiceError),
+    ScoringError(ScoringServiceError),
+    PriceSnapshotError(PriceSnapshotsServiceError),
+    InvalidQuery(String),
+    InvalidGeometry(String),
+    Conflict(String),
+    TooManyRequests(String),
+}
+
+impl fmt::Display for HousesServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::MongoDbError(e) => write!(f, "mongodb: {}", e),
+            Self::ObjectId(e) => write!(f, "invalid id: {}", e),
+            Self::HouseNotFound(id) => write!(f, "house {} not found", id),
+            Self::UnExpectedMongoDbType => write!(f, "unexpected mongodb type"),
+            Self::AgencyError(e) => e.fmt(f),
+            Self::CommuteError(e) => e.fmt(f),
+            Self::PoiError(e) => e.fmt(f),
+            Self::LayerError(e) => e.fmt(f),
+            Self::AreaError(e) => e.fmt(f),
+            Self::ScoringError(e) => e.fmt(f),
+            Self::PriceSnapshotError(e) => e.fmt(f),
+            Self::InvalidQuery(message) => write!(f, "invalid query: {}", message),
+            Self::InvalidGeometry(message) => write!(f, "invalid geometry: {}", message),
+            Self::Conflict(message) => write!(f, "conflict: {}", message),
+            Self::TooManyRequests(message) => write!(f, "too many requests: {}", message),
+        }
+    }
 }
 
 impl From<mongodb::error::Error> for HousesServiceError {
@@ -35,20 +135,788 @@ impl From<mongodb::bson::oid::Error> for HousesServiceError {
         Self::ObjectId(e)
     }
 }
+impl From<AgenciesServiceError> for HousesServiceError {
+    fn from(e: AgenciesServiceError) -> Self {
+        Self::AgencyError(e)
+    }
+}
+impl From<CommuteServiceError> for HousesServiceError {
+    fn from(e: CommuteServiceError) -> Self {
+        Self::CommuteError(e)
+    }
+}
+impl From<PoisServiceError> for HousesServiceError {
+    fn from(e: PoisServiceError) -> Self {
+        Self::PoiError(e)
+    }
+}
+impl From<LayersServiceError> for HousesServiceError {
+    fn from(e: LayersServiceError) -> Self {
+        Self::LayerError(e)
+    }
+}
+impl From<AreasServiceError> for HousesServiceError {
+    fn from(e: AreasServiceError) -> Self {
+        Self::AreaError(e)
+    }
+}
+impl From<ScoringServiceError> for HousesServiceError {
+    fn from(e: ScoringServiceError) -> Self {
+        Self::ScoringError(e)
+    }
+}
+impl From<PriceSnapshotsServiceError> for HousesServiceError {
+    fn from(e: PriceSnapshotsServiceError) -> Self {
+        Self::PriceSnapshotError(e)
+    }
+}
+impl From<InvalidQuery> for HousesServiceError {
+    fn from(e: InvalidQuery) -> Self {
+        Self::InvalidQuery(e.0)
+    }
+}
+impl From<InvalidGeometry> for HousesServiceError {
+    fn from(e: InvalidGeometry) -> Self {
+        Self::InvalidGeometry(e.0)
+    }
+}
 
 impl HousesService {
-    pub fn new(collection: Collection<HouseEntity>) -> Self {
-        Self { collection }
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        collection: Collection<HouseEntity>,
+        media_service: MediaService,
+        amenity_tagger: AmenityTagger,
+        agencies_service: AgenciesService,
+        transit_stops: TransitStops,
+        commute_service: CommuteService,
+        pois_service: PoisService,
+        nearby_service: NearbyService,
+        neighborhoods: Neighborhoods,
+        layers_service: LayersService,
+        areas_service: AreasService,
+        scoring_service: ScoringService,
+        price_snapshots_service: PriceSnapshotsService,
+    ) -> Self {
+        Self {
+            collection,
+            media_service,
+            amenity_tagger,
+            agencies_service,
+            transit_stops,
+            commute_service,
+            pois_service,
+            nearby_service,
+            neighborhoods,
+            layers_service,
+            areas_service,
+            scoring_service,
+            price_snapshots_service,
+            pending_refreshes: Default::default(),
+            refreshing: Default::default(),
+            repriced: Default::default(),
+        }
+    }
+
+    /// Creates the indexes backing the list filters and sorts
+    pub async fn create_indexes(&self) -> Result<()> {
+        let keys = [
+            doc! { "removed": 1, "_id": -1 },
+            doc! { "removed": 1, "cost": 1, "_id": 1 },
+            doc! { "removed": 1, "cost_per_square_meter": 1, "_id": 1 },
+            doc! { "removed": 1, "vote": 1, "_id": 1 },
+            doc! { "removed": 1, "city": 1, "zone": 1 },
+            doc! { "removed": 1, "neighborhood": 1 },
+            doc! { "link": 1 },
+            doc! { "location": "2dsphere" },
+            // The houses of each agency, see `AgenciesService::get_agencies`
+            doc! { "agency_id": 1, "removed": 1 },
+        ];
+        let mut indexes: Vec<IndexModel> = keys
+            .into_iter()
+            .map(|keys| {
+                IndexModel::builder()
+                    .keys(keys)
+                    .options(IndexOptions::builder().background(true).build())
+                    .build()
+            })
+            .collect();
+
+        // Listings and comments are mostly in Italian
+        indexes.push(
+            IndexModel::builder()
+                .keys(doc! {
+                    "comment": "text",
+                    "street": "text",
+                    "zone": "text",
+                    "city": "text",
+                    "description": "text",
+                })
+                .options(
+                    IndexOptions::builder()
+                        .name("houses_text".to_string())
+                        .default_language("italian".to_string())
+                        .background(true)
+                        .build(),
+                )
+                .build(),
+        );
+
+        event!(Level::INFO, "creating indexes");
+        self.collection.create_indexes(indexes, None).await?;
+
+        Ok(())
+    }
+
+    /// Fills the fields added after the houses were inserted
+    pub async fn migrate(&self) -> Result<()> {
+        let res = self
+            .collection
+            .update_many(
+                doc! { "cost_per_square_meter": { "$exists": false } },
+                vec![doc! { "$set": { "cost_per_square_meter": { "$cond": [
+                    { "$and": [
+                        { "$gt": ["$cost", null] },
+                        { "$gt": ["$square_meters", 0] },
+                    ] },
+                    { "$divide": ["$cost", "$square_meters"] },
+                    null,
+                ] } } }],
+                None,
+            )
+            .await?;
+        event!(
+            Level::INFO,
+            modified = res.modified_count,
+            "cost per square meter migrated"
+        );
+
+        let res = self
+            .collection
+            .update_many(
+                // The index refuses the others
+                doc! {
+                    "location": { "$exists": false },
+                    "lat": { "$type": "number", "$gte": -90, "$lte": 90 },
+                    "lng": { "$type": "number", "$gte": -180, "$lte": 180 },
+                },
+                vec![doc! { "$set": { "location": {
+                    "type": "Point",
+                    "coordinates": ["$lng", "$lat"],
+                } } }],
+                None,
+            )
+            .await?;
+        event!(
+            Level::INFO,
+            modified = res.modified_count,
+            "location migrated"
+        );
+
+        // Same guess as ListingType::from_link
+        let res = self
+            .collection
+            .update_many(
+                doc! { "listing_type": { "$exists": false } },
+                vec![doc! { "$set": { "listing_type": { "$switch": {
+                    "branches": [
+                        {
+                            "case": { "$regexMatch": { "input": "$link", "regex": "affitt", "options": "i" } },
+                            "then": "rent",
+                        },
+                        {
+                            "case": { "$regexMatch": { "input": "$link", "regex": "vendit", "options": "i" } },
+                            "then": "sale",
+                        },
+                    ],
+                    "default": null,
+                } } } }],
+                None,
+            )
+            .await?;
+        event!(
+            Level::INFO,
+            modified = res.modified_count,
+            "listing type migrated"
+        );
+
+        Ok(())
     }
 
     pub async fn insert_house(&self, house: HouseDTOInsert) -> Result<HouseDTOInserted> {
-        let house: HouseEntity = house.into();
+        if let (Some(lat), Some(lng)) = (house.lat, house.lng) {
+            geo::check_position(lat, lng)?;
+        }
+
+        // Keep a local copy: the portal drops the pictures with the listing
+        let (photos, floor_plans) = futures::join!(
+            self.media_service.store_all(&house.photos),
+            self.media_service.store_all(&house.floor_plans)
+        );
+
+        let agency = match &house.advertiser {
+            None => None,
+            Some(advertiser) => Some(self.agencies_service.upsert(advertiser).await?),
+        };
+
+        let mut house: HouseEntity = house.into();
+        house.cost_per_square_meter = match (house.cost, house.square_meters) {
+            (Some(cost), Some(square_meters)) if square_meters > 0 => {
+                Some(cost as f64 / square_meters as f64)
+            }
+            _ => None,
+        };
+        house.location = GeoPoint::from_lat_lng(house.lat, house.lng);
+        house.photos = photos;
+        house.floor_plans = floor_plans;
+        house.amenities = self.amenity_tagger.tag(house.description.as_deref());
+        if let (Some(lat), Some(lng)) = (house.lat, house.lng) {
+            house.neighborhood = self.neighborhoods.find(lat, lng);
+            house.nearest_stop = self.transit_stops.nearest(lat, lng);
+            house.line_distances = self.transit_stops.line_distances(lat, lng);
+            house.commutes = self
+                .commute_service
+                .load()
+                .await?
+                .commutes(lat, lng)
+                .await?;
+            house.poi_distances = self.pois_service.load().await?.distances(lat, lng);
+            house.nearby = self.nearby_service.counts(lat, lng);
+            house.layers = self.layers_service.matches(lat, lng).await?;
+            house.areas = self.areas_service.load().await?.flags(lat, lng);
+        }
+        if let Some(agency) = agency {
+            house.agency_id = Some(agency._id);
+            house.agency_blocked = agency.blocked;
+        }
+        house.scores = self.scores(&house).await?;
+        // Against the saved houses, read once: the others are judged again in background
+        let saved: Vec<HouseEntity> = self
+            .collection
+            .find(doc! {}, None)
+            .await?
+            .try_collect()
+            .await?;
+        house.fair_price = Self::fair_price(&house, &saved);
+        house.risk_flags = Self::risk_flags(&house, &saved);
+        let agency_blocked = house.agency_blocked;
+        let risk_flags = house.risk_flags.clone();
+        let no_go_areas: BTreeMap<_, _> = house
+            .areas
+            .iter()
+            .filter(|(_, flag)| flag.is_no_go())
+            .map(|(id, flag)| (id.clone(), flag.clone()))
+            .collect();
 
         event!(Level::INFO, "inserting");
         let res = self.collection.insert_one(house, None).await?;
         event!(Level::INFO, "inserted");
 
-        res.try_into()
+        let mut inserted: HouseDTOInserted = res.try_into()?;
+        if agency_blocked {
+            event!(Level::WARN, house_id = %inserted.id, "inserted a house of a blocked agency");
+            inserted.agency_blocked = true;
+        }
+        if !no_go_areas.is_empty() {
+            event!(Level::WARN, house_id = %inserted.id, areas = ?no_go_areas.keys(), "inserted a house in a no-go area");
+            inserted.no_go_areas = no_go_areas;
+        }
+        if !risk_flags.is_empty() {
+            event!(Level::WARN, house_id = %inserted.id, flags = ?risk_flags, "inserted a suspicious house");
+            inserted.risk_flags = risk_flags;
+        }
+
+        Ok(inserted)
+    }
+
+    /// The houses whose distances can be computed
+    async fn located_houses(&self) -> Result<Vec<(HouseEntity, f64, f64)>> {
+        let cur = self
+            .collection
+            .find(doc! { "location": { "$ne": null } }, None)
+            .await?;
+        let houses: Vec<HouseEntity> = cur.try_collect().await?;
+
+        Ok(houses
+            .into_iter()
+            .filter_map(|h| {
+                let (lat, lng) = (h.lat?, h.lng?);
+                Some((h, lat, lng))
+            })
+            .collect())
+    }
+
+    /// Computes `field` again for each house and writes the values which changed.
+    /// `compute` gives None when the value is the same.
+    async fn refresh_field<I, T, F, Fut>(
+        &self,
+        field: &str,
+        houses: Vec<I>,
+        mut compute: F,
+    ) -> Result<u64>
+    where
+        T: Serialize,
+        F: FnMut(I) -> Fut,
+        Fut: Future<Output = Result<Option<(ObjectId, T)>>>,
+    {
+        let mut changes = Vec::new();
+        for house in houses {
+            if let Some((id, value)) = compute(house).await? {
+                changes.push((id, doc! { field: mongodb::bson::to_bson(&value).unwrap() }));
+            }
+        }
+        let updated = self.set_fields(changes).await?;
+        event!(Level::INFO, field, updated, "refreshed");
+
+        Ok(updated)
+    }
+
+    /// Sets the fields of many houses, a batch per update instead of a round trip
+    /// per house: for each field a `$switch` on the id picks the value of the house.
+    async fn set_fields(&self, changes: Vec<(ObjectId, Document)>) -> Result<u64> {
+        for batch in changes.chunks(WRITE_BATCH) {
+            let ids: Vec<ObjectId> = batch.iter().map(|(id, _)| *id).collect();
+            let fields: BTreeSet<&String> = batch.iter().flat_map(|(_, f)| f.keys()).collect();
+
+            let mut set = Document::new();
+            for field in fields {
+                let branches: Vec<Document> = batch
+                    .iter()
+                    .filter_map(|(id, f)| {
+                        let value = f.get(field)?;
+                        Some(
+                            doc! { "case": { "$eq": ["$_id", id] }, "then": { "$literal": value } },
+                        )
+                    })
+                    .collect();
+                // the houses of the batch without this field keep theirs
+                set.insert(
+                    field,
+                    doc! { "$switch": { "branches": branches, "default": format!("${}", field) } },
+                );
+            }
+
+            self.collection
+                .update_many(
+                    doc! { "_id": { "$in": ids } },
+                    vec![doc! { "$set": set }],
+                    None,
+                )
+                .await?;
+        }
+
+        Ok(changes.len() as u64)
+    }
+
+    /// Flags again the houses inside or outside the areas, after an area changed
+    pub async fn refresh_areas(&self) -> Result<u64> {
+        let houses = self.located_houses().await?;
+        let areas = self.areas_service.load().await?;
+        self.refresh_field("areas", houses, |(house, lat, lng)| {
+            let flags = areas.flags(lat, lng);
+            future::ready(Ok((flags != house.areas).then_some((house._id, flags))))
+        })
+        .await
+    }
+
+    /// Finds again the layer features containing the houses, after a layer changed
+    pub async fn refresh_layers(&self) -> Result<u64> {
+        let houses = self.located_houses().await?;
+        self.refresh_field("layers", houses, |(house, lat, lng)| async move {
+            let layers = self.layers_service.matches(lat, lng).await?;
+            Ok((layers != house.layers).then_some((house._id, layers)))
+        })
+        .await
+    }
+
+    /// Assigns again the official neighborhoods, whose borders may have changed
+    pub async fn refresh_neighborhoods(&self) -> Result<u64> {
+        let houses = self.located_houses().await?;
+        self.refresh_field("neighborhood", houses, |(house, lat, lng)| {
+            let neighborhood = self.neighborhoods.find(lat, lng);
+            future::ready(Ok(
+                (neighborhood != house.neighborhood).then_some((house._id, neighborhood))
+            ))
+        })
+        .await
+    }
+
+    /// Computes again the distances from the metro stops, which may have changed.
+    /// Returns how many houses were updated.
+    pub async fn refresh_transit(&self) -> Result<u64> {
+        let mut changes = Vec::new();
+        for (house, lat, lng) in self.located_houses().await? {
+            let nearest_stop = self.transit_stops.nearest(lat, lng);
+            let line_distances = self.transit_stops.line_distances(lat, lng);
+            if nearest_stop == house.nearest_stop && line_distances == house.line_distances {
+                continue;
+            }
+
+            changes.push((
+                house._id,
+                doc! {
+                    "nearest_stop": mongodb::bson::to_bson(&nearest_stop).unwrap(),
+                    "line_distances": mongodb::bson::to_bson(&line_distances).unwrap(),
+                },
+            ));
+        }
+        let updated = self.set_fields(changes).await?;
+        event!(Level::INFO, updated, "transit distances refreshed");
+
+        Ok(updated)
+    }
+
+    /// Computes again the commutes, after the destinations changed.
+    /// Returns how many houses were updated.
+    pub async fn refresh_commutes(&self) -> Result<u64> {
+        let houses = self.located_houses().await?;
+        let destinations = self.commute_service.load().await?;
+        let destinations = &destinations;
+        self.refresh_field("commutes", houses, |(house, lat, lng)| async move {
+            let commutes = destinations.commutes(lat, lng).await?;
+            Ok((commutes != house.commutes).then_some((house._id, commutes)))
+        })
+        .await
+    }
+
+    /// Computes again the distances from the points of interest, after they changed.
+    /// Returns how many houses were updated.
+    pub async fn refresh_pois(&self) -> Result<u64> {
+        let houses = self.located_houses().await?;
+        let pois = self.pois_service.load().await?;
+        self.refresh_field("poi_distances", houses, |(house, lat, lng)| {
+            let poi_distances = pois.distances(lat, lng);
+            future::ready(Ok(
+                (poi_distances != house.poi_distances).then_some((house._id, poi_distances))
+            ))
+        })
+        .await
+    }
+
+    /// Counts again the amenities around, after the OSM extract is imported
+    pub async fn refresh_nearby(&self) -> Result<u64> {
+        let houses = self.located_houses().await?;
+        self.refresh_field("nearby", houses, |(house, lat, lng)| {
+            let nearby = self.nearby_service.counts(lat, lng);
+            future::ready(Ok((nearby != house.nearby).then_some((house._id, nearby))))
+        })
+        .await
+    }
+
+    /// Fits again the price models, one per listing type, and estimates every house:
+    /// each new price moves the estimates of the others.
+    /// Delisted houses count too, they tell what the market asked.
+    pub async fn refresh_fair_prices(&self) -> Result<u64> {
+        let cur = self.collection.find(doc! {}, None).await?;
+        let houses: Vec<HouseEntity> = cur.try_collect().await?;
+
+        let mut groups: BTreeMap<Option<ListingType>, Vec<Listing>> = BTreeMap::new();
+        for house in &houses {
+            groups
+                .entry(house.listing_type)
+                .or_default()
+                .push(Listing::from(house));
+        }
+        let models: BTreeMap<Option<ListingType>, Option<FairPriceModel>> = groups
+            .into_iter()
+            .map(|(listing_type, listings)| {
+                let model = FairPriceModel::fit(&listings);
+                event!(Level::INFO, listing_type = ?listing_type, houses = listings.len(), fitted = model.is_some(), "fair price model");
+                (listing_type, model)
+            })
+            .collect();
+
+        self.refresh_field("fair_price", houses, |house| {
+            let fair_price = models[&house.listing_type]
+                .as_ref()
+                .and_then(|m| m.fair_price(&Listing::from(&house)));
+            future::ready(Ok(
+                (fair_price != house.fair_price).then_some((house._id, fair_price))
+            ))
+        })
+        .await
+    }
+
+    /// Of a house about to be inserted, by the model of the saved ones of its listing type
+    fn fair_price(house: &HouseEntity, saved: &[HouseEntity]) -> Option<FairPrice> {
+        let listing = Listing::from(house);
+        let listings: Vec<Listing> = saved
+            .iter()
+            .filter(|h| h.listing_type == house.listing_type)
+            .map(Listing::from)
+            .chain([listing.clone()])
+            .collect();
+        FairPriceModel::fit(&listings).and_then(|m| m.fair_price(&listing))
+    }
+
+    /// Of a house about to be inserted, against the saved ones
+    fn risk_flags(house: &HouseEntity, saved: &[HouseEntity]) -> Vec<RiskFlag> {
+        let features: Vec<RiskFeatures> = saved
+            .iter()
+            .chain([house])
+            .map(RiskFeatures::from)
+            .collect();
+        RiskAnalyzer::new(&features).analyze(&house.into())
+    }
+
+    /// Judges again every house: a new one moves the zone medians
+    /// and may reuse the photos of the others.
+    /// Delisted houses count too, a fake one is still a fake.
+    pub async fn refresh_risk_flags(&self) -> Result<u64> {
+        let cur = self.collection.find(doc! {}, None).await?;
+        let houses: Vec<HouseEntity> = cur.try_collect().await?;
+
+        let features: Vec<RiskFeatures> = houses.iter().map(RiskFeatures::from).collect();
+        let analyzer = RiskAnalyzer::new(&features);
+        let judged: Vec<_> = houses
+            .iter()
+            .zip(&features)
+            .filter_map(|(house, features)| {
+                let risk_flags = analyzer.analyze(features);
+                (risk_flags != house.risk_flags).then_some((house._id, risk_flags))
+            })
+            .collect();
+
+        self.refresh_field("risk_flags", judged, |change| {
+            future::ready(Ok(Some(change)))
+        })
+        .await
+    }
+
+    /// Against every profile, keyed by profile id
+    async fn scores(&self, house: &HouseEntity) -> Result<BTreeMap<String, Score>> {
+        let profiles = self.scoring_service.profiles().await?;
+
+        Ok(scores(&profiles, house))
+    }
+
+    /// Scores again every house, after a profile changed
+    pub async fn refresh_scores(&self) -> Result<u64> {
+        let cur = self.collection.find(doc! {}, None).await?;
+        let houses: Vec<HouseEntity> = cur.try_collect().await?;
+        let profiles = self.scoring_service.profiles().await?;
+
+        self.refresh_field("scores", houses, |house| {
+            let scores = scores(&profiles, &house);
+            future::ready(Ok((scores != house.scores).then_some((house._id, scores))))
+        })
+        .await
+    }
+
+    async fn vote_models(&self) -> Result<(VoteModels, Vec<(HouseEntity, VoteFeatures)>)> {
+        let cur = self.collection.find(doc! {}, None).await?;
+        let houses: Vec<HouseEntity> = cur.try_collect().await?;
+        let houses: Vec<_> = houses
+            .into_iter()
+            .map(|h| {
+                let features = VoteFeatures::from(&h);
+                (h, features)
+            })
+            .collect();
+
+        Ok((VoteModels::fit(&houses), houses))
+    }
+
+    /// Learns again the votes of the household and of each of us,
+    /// and guesses them for the houses not voted yet
+    pub async fn refresh_predicted_votes(&self) -> Result<u64> {
+        let (models, houses) = self.vote_models().await?;
+        event!(Level::INFO, household = models.household.is_some(), users = ?models.users.keys(), "vote models");
+
+        let mut changes = Vec::new();
+        for (house, features) in &houses {
+            let predicted = models.predict(features, Some(house));
+            if predicted.predicted_vote == house.predicted_vote
+                && predicted.predicted_votes == house.predicted_votes
+            {
+                continue;
+            }
+
+            changes.push((house._id, mongodb::bson::to_document(&predicted).unwrap()));
+        }
+        let updated = self.set_fields(changes).await?;
+        event!(Level::INFO, updated, "predicted votes refreshed");
+
+        Ok(updated)
+    }
+
+    /// Runs the refreshes off the request, logging their failures.
+    /// One runs at a time; the ones asked meanwhile wait for it and run once,
+    /// however many times they were asked.
+    pub fn refresh_in_background(&self, refreshes: &[Refresh]) {
+        let mut pending = self.pending_refreshes.lock().unwrap();
+        let queued = !pending.is_empty();
+        pending.extend(refreshes);
+        if queued {
+            return;
+        }
+
+        let service = self.clone();
+        tokio::spawn(async move {
+            let _running = service.refreshing.lock().await;
+            let refreshes = std::mem::take(&mut *service.pending_refreshes.lock().unwrap());
+            for refresh in refreshes {
+                if let Err(e) = service.refresh(refresh).await {
+                    event!(Level::ERROR, refresh = ?refresh, "refreshing the houses: {}", e);
+                }
+            }
+        });
+    }
+
+    async fn refresh(&self, refresh: Refresh) -> Result<u64> {
+        match refresh {
+            Refresh::FairPrices => self.refresh_fair_prices().await,
+            Refresh::PredictedVotes => self.refresh_predicted_votes().await,
+            Refresh::RiskFlags => self.refresh_risk_flags().await,
+            Refresh::Commutes => self.refresh_commutes().await,
+            Refresh::Scores => self.refresh_scores().await,
+            Refresh::Pois => self.refresh_pois().await,
+            Refresh::Areas => self.refresh_areas().await,
+            Refresh::Layers => self.refresh_layers().await,
+        }
+    }
+
+    /// The votes a listing would get, before saving it
+    pub async fn predict_votes(&self, discovery: &DiscoveryResult) -> Result<PredictedVotes> {
+        let features = discovery.vote_features(
+            &self.amenity_tagger,
+            &self.neighborhoods,
+            &self.transit_stops,
+        );
+        let (models, _) = self.vote_models().await?;
+
+        Ok(models.predict(&features, None))
+    }
+
+    /// Two available houses for the user to pick the better of,
+    /// None with less than two
+    pub async fn get_comparison_pair(&self, user: &str) -> Result<Option<ComparisonPairDTO>> {
+        let user = validate_field_name("user", user)?;
+        let cur = self
+            .collection
+            .find(doc! { "removed": false }, None)
+            .await?;
+        let houses: Vec<HouseEntity> = cur.try_collect().await?;
+
+        let ratings: Vec<Rating> = houses
+            .iter()
+            .map(|h| h.ratings.get(user).copied().unwrap_or_default())
+            .collect();
+        let (left, right) = match elo::pick_pair(&ratings) {
+            None => return Ok(None),
+            Some(pair) => pair,
+        };
+
+        let mut houses: Vec<Option<HouseEntity>> = houses.into_iter().map(Some).collect();
+        Ok(Some(ComparisonPairDTO {
+            left: houses[left].take().unwrap().into(),
+            right: houses[right].take().unwrap().into(),
+        }))
+    }
+
+    /// Updates the ratings of the user with the pick
+    pub async fn compare(&self, comparison: ComparisonDTOInsert) -> Result<ComparisonDTO> {
+        let user = validate_field_name("user", &comparison.user)?;
+        if comparison.winner == comparison.loser {
+            return Err(HousesServiceError::InvalidQuery(
+                "a house cannot be compared with itself".to_owned(),
+            ));
+        }
+        let field = format!("ratings.{}", user);
+
+        // Another pick of the same user may move the same houses meanwhile:
+        // each rating is written only if it is still the one read
+        for _ in 0..COMPARE_ATTEMPTS {
+            let (winner_id, read_winner) = self.rating(&comparison.winner, user).await?;
+            let (loser_id, read_loser) = self.rating(&comparison.loser, user).await?;
+
+            let (winner, loser) = elo::update(read_winner, read_loser);
+
+            if !self
+                .set_rating(winner_id, &field, read_winner, winner)
+                .await?
+            {
+                continue;
+            }
+            if !self.set_rating(loser_id, &field, read_loser, loser).await? {
+                // Put the winner back, unless moved again, and read both again
+                self.set_rating(winner_id, &field, winner, read_winner)
+                    .await?;
+                continue;
+            }
+
+            event!(Level::INFO, user = %user, winner = %comparison.winner, loser = %comparison.loser, "compared");
+            return Ok(ComparisonDTO { winner, loser });
+        }
+
+        Err(HousesServiceError::Conflict(format!(
+            "{} and {} are being compared too often, try again",
+            comparison.winner, comparison.loser
+        )))
+    }
+
+    async fn rating(&self, house_id: &str, user: &str) -> Result<(ObjectId, Rating)> {
+        let id = ObjectId::from_str(house_id)?;
+        let house = self
+            .collection
+            .find_one(doc! { "_id": id, "removed": false }, None)
+            .await?
+            .ok_or_else(|| HousesServiceError::HouseNotFound(house_id.to_owned()))?;
+
+        Ok((id, house.ratings.get(user).copied().unwrap_or_default()))
+    }
+
+    /// Returns false when the rating is not `read` anymore.
+    /// Every pick counts one more comparison: the count tells if the rating moved.
+    async fn set_rating(
+        &self,
+        id: ObjectId,
+        field: &str,
+        read: Rating,
+        rating: Rating,
+    ) -> Result<bool> {
+        let mut filter = doc! { "_id": id, "removed": false };
+        if read.comparisons == 0 {
+            filter.insert(field, doc! { "$exists": false });
+        } else {
+            filter.insert(format!("{}.comparisons", field), read.comparisons);
+        }
+
+        // Back to no rating, when a pick is undone
+        let update = if rating.comparisons == 0 {
+            doc! { "$unset": { field: "" } }
+        } else {
+            doc! { "$set": { field: mongodb::bson::to_bson(&rating).unwrap() } }
+        };
+        let res = self.collection.update_one(filter, update, None).await?;
+
+        Ok(res.matched_count == 1)
+    }
+
+    /// The houses side by side, in the order of the ids, and who wins what
+    pub async fn compare_houses(&self, query: &CompareQuery) -> Result<HousesComparisonDTO> {
+        let ids = query.ids()?;
+
+        event!(Level::INFO, houses = %query.ids, "comparing");
+        let cur = self
+            .collection
+            .find(doc! { "_id": { "$in": &ids } }, None)
+            .await?;
+        let mut found: Vec<HouseEntity> = cur.try_collect().await?;
+
+        let mut houses = Vec::with_capacity(ids.len());
+        for id in &ids {
+            match found.iter().position(|h| h._id == *id) {
+                None => return Err(HousesServiceError::HouseNotFound(id.to_hex())),
+                Some(i) => houses.push(found.swap_remove(i)),
+            }
+        }
+
+        let comparison = Comparison::new(houses.len(), comparison_criteria(&houses));
+        Ok(HousesComparisonDTO {
+            houses: houses.into_iter().map(HouseDTO::from).collect(),
+            comparison,
+        })
     }
 
     pub async fn remove_house(&self, house_id: String) -> Result<()> {
@@ -72,23 +940,251 @@ impl HousesService {
         Ok(())
     }
 
-    pub async fn get_houses(&self) -> Result<Vec<HouseDTO>> {
+    pub async fn get_houses(&self, query: HousesQuery) -> Result<HousesPage> {
+        let filter = query.filter()?;
+        // One more to know if there is a next page
+        let options = FindOptions::builder()
+            .sort(query.sort())
+            .projection(
+                query
+                    .search()
+                    .map(|_| doc! { "score": { "$meta": "textScore" } }),
+            )
+            .limit(query.limit.map(|l| l as i64 + 1))
+            .build();
+
+        let cur = self.collection.find(filter, options).await?;
+        let mut houses: Vec<HouseEntity> = cur.try_collect().await?;
+
+        event!(Level::INFO, count = houses.len(), "found");
+
+        let mut next_cursor = None;
+        if let Some(limit) = query.limit {
+            if houses.len() > limit as usize {
+                houses.truncate(limit as usize);
+                // Search results have a single page
+                if query.search().is_none() {
+                    next_cursor = houses
+                        .last()
+                        .map(|h| Cursor::new(h.sort_value(&query), h._id).encode());
+                }
+            }
+        }
+
+        let houses = houses
+            .into_iter()
+            .map(|h| {
+                let search = query.search().map(|q| SearchMatchDTO {
+                    score: h.score.unwrap_or_default(),
+                    snippets: h.snippets(q),
+                });
+                HouseDTO { search, ..h.into() }
+            })
+            .collect();
+
+        Ok(HousesPage {
+            houses,
+            next_cursor,
+        })
+    }
+
+    /// Houses within `radius` meters, the nearest first
+    pub async fn get_houses_near(&self, query: NearQuery) -> Result<Vec<HouseDTO>> {
+        let point = query.point()?;
+        let filter = doc! {
+            "removed": false,
+            "location": { "$nearSphere": {
+                "$geometry": mongodb::bson::to_bson(&point).unwrap(),
+                "$maxDistance": query.radius,
+            } },
+        };
+
+        self.find_houses(filter).await
+    }
+
+    /// Houses inside a polygon drawn on the map
+    pub async fn get_houses_within(&self, polygon: GeoPolygon) -> Result<Vec<HouseDTO>> {
+        let polygon = polygon.validate()?;
+
+        self.find_within(polygon).await
+    }
+
+    /// Houses inside the map viewport
+    pub async fn get_houses_in_bbox(&self, query: BoundingBoxQuery) -> Result<Vec<HouseDTO>> {
+        let polygon = query.polygon()?;
+
+        self.find_within(polygon).await
+    }
+
+    async fn find_within(&self, polygon: GeoPolygon) -> Result<Vec<HouseDTO>> {
+        let filter = doc! {
+            "removed": false,
+            "location": { "$geoWithin": { "$geometry": polygon.to_document() } },
+        };
+
+        self.find_houses(filter).await
+    }
+
+    /// Just the positions, for the map
+    pub async fn get_markers_in_bbox(&self, query: &BoundingBoxQuery) -> Result<Vec<Marker>> {
+        let polygon = query.polygon()?;
+        let filter = doc! {
+            "removed": false,
+            "location": { "$geoWithin": { "$geometry": polygon.to_document() } },
+        };
+        let options = FindOptions::builder()
+            .projection(doc! { "lat": 1, "lng": 1, "cost": 1 })
+            .build();
+
         let cur = self
             .collection
-            .find(
-                doc! {
-                    "removed": false
-                },
-                None,
-            )
+            .clone_with_type::<HousePosition>()
+            .find(filter, options)
+            .await?;
+        let houses: Vec<HousePosition> = cur.try_collect().await?;
+
+        Ok(houses
+            .into_iter()
+            .filter_map(|h| {
+                Some(Marker {
+                    id: h._id.to_hex(),
+                    kind: MarkerKind::House,
+                    lat: h.lat?,
+                    lng: h.lng?,
+                    cost: h.cost,
+                })
+            })
+            .collect())
+    }
+
+    /// The prices in the viewport, delisted houses included
+    pub async fn get_price_samples(&self, query: &HeatmapQuery) -> Result<Vec<PriceSample>> {
+        let polygon = query.bounding_box().polygon()?;
+        let mut filter = doc! {
+            "location": { "$geoWithin": { "$geometry": polygon.to_document() } },
+            "cost_per_square_meter": { "$ne": null },
+            "listing_type": mongodb::bson::to_bson(&query.listing_type).unwrap(),
+        };
+        filter.extend(query.window()?);
+        let options = FindOptions::builder()
+            .projection(doc! {
+                "listing_type": 1,
+                "lat": 1,
+                "lng": 1,
+                "cost_per_square_meter": 1,
+                "removed": 1,
+            })
+            .build();
+
+        let cur = self
+            .collection
+            .clone_with_type::<HousePrice>()
+            .find(filter, options)
             .await?;
+        let houses: Vec<HousePrice> = cur.try_collect().await?;
+
+        Ok(houses
+            .into_iter()
+            .filter_map(|h| {
+                Some(PriceSample {
+                    listing_type: h.listing_type?,
+                    lat: h.lat?,
+                    lng: h.lng?,
+                    cost_per_square_meter: h.cost_per_square_meter?,
+                    removed: h.removed,
+                    snapshot: false,
+                })
+            })
+            .collect())
+    }
+
+    async fn find_houses(&self, filter: Document) -> Result<Vec<HouseDTO>> {
+        let cur = self.collection.find(filter, None).await?;
         let houses: Vec<HouseEntity> = cur.try_collect().await?;
 
         event!(Level::INFO, count = houses.len(), "found");
 
-        let houses = houses.into_iter().map(HouseDTO::from).collect();
+        Ok(houses.into_iter().map(HouseDTO::from).collect())
+    }
+
+    /// Returns the links, among the given canonical ones, already saved as houses.
+    /// The saved links may still have the query string or the fragment
+    /// the portal added, `?utm_source=...`: they are compared without.
+    pub async fn existing_links(&self, links: &[String]) -> Result<HashSet<String>> {
+        if links.is_empty() {
+            return Ok(HashSet::new());
+        }
+
+        let cur = self.collection.find(links_filter(links), None).await?;
+        let houses: Vec<HouseEntity> = cur.try_collect().await?;
 
-        Ok(houses)
+        Ok(houses
+            .iter()
+            .filter_map(|h| h.link.split(['?', '#']).next())
+            .filter(|l| links.iter().any(|link| link == l))
+            .map(str::to_owned)
+            .collect())
+    }
+
+    /// The link to fetch again for the price the portal shows now,
+    /// at most once every `REPRICE_INTERVAL` for a house
+    pub async fn reprice_link(&self, id: &str) -> Result<String> {
+        let obj_id = ObjectId::from_str(id)?;
+        let house = self
+            .collection
+            .find_one(doc! { "_id": obj_id }, None)
+            .await?
+            .ok_or_else(|| HousesServiceError::HouseNotFound(id.to_owned()))?;
+
+        let now = Instant::now();
+        let mut repriced = self.repriced.lock().unwrap();
+        repriced.retain(|_, at| now.duration_since(*at) < REPRICE_INTERVAL);
+        if repriced.contains_key(&obj_id) {
+            return Err(HousesServiceError::TooManyRequests(format!(
+                "house {} fetched less than {} minutes ago",
+                id,
+                REPRICE_INTERVAL.as_secs() / 60
+            )));
+        }
+        repriced.insert(obj_id, now);
+
+        Ok(house.link)
+    }
+
+    /// Records the price the portal shows now next to the saved one,
+    /// which stays: replacing it is up to the user
+    pub async fn record_price(&self, id: &str, discovery: &DiscoveryResult) -> Result<Reprice> {
+        let obj_id = ObjectId::from_str(id)?;
+        let house = self
+            .collection
+            .find_one(doc! { "_id": obj_id }, None)
+            .await?
+            .ok_or_else(|| HousesServiceError::HouseNotFound(id.to_owned()))?;
+
+        // What we saved about the house may be more accurate than the portal
+        let snapshot = PriceSnapshotEntity::new(
+            &house.link,
+            house
+                .listing_type
+                .or_else(|| ListingType::from_link(&house.link)),
+            house.lat.zip(house.lng).or(discovery.position()),
+            discovery.cost(),
+            house.square_meters.or(discovery.square_meters()),
+        );
+        let recorded = match snapshot {
+            Some(snapshot) => {
+                self.price_snapshots_service
+                    .record(&snapshot, house.cost)
+                    .await?
+            }
+            None => false,
+        };
+
+        Ok(Reprice {
+            cost: house.cost,
+            portal_cost: discovery.cost(),
+            recorded,
+        })
     }
 
     pub async fn get_house_by_id(&self, id: String) -> Result<HouseDTO> {
@@ -113,14 +1209,101 @@ impl HousesService {
         Ok(h.into())
     }
 
+    /// The saved houses and the candidates most alike the house, the most alike first
+    pub async fn get_similar_houses(
+        &self,
+        id: String,
+        candidates: Vec<CandidateDTO>,
+        query: &SimilarQuery,
+    ) -> Result<Vec<SimilarDTO>> {
+        let limit = query.limit()?;
+        let obj_id = ObjectId::from_str(&id)?;
+
+        event!(Level::INFO, house_id = %id, "finding similar");
+        let target = self
+            .collection
+            .find_one(doc! { "_id": obj_id }, None)
+            .await?
+            .ok_or(HousesServiceError::HouseNotFound(id))?;
+
+        // A rent is not alike a sale, and its cost would skew the scales of the costs
+        let listing_type = target
+            .listing_type
+            .or_else(|| ListingType::from_link(&target.link));
+        let mut filter = doc! { "removed": false, "_id": { "$ne": target._id } };
+        if let Some(listing_type) = listing_type {
+            filter.insert(
+                "listing_type",
+                mongodb::bson::to_bson(&listing_type).unwrap(),
+            );
+        }
+        let cur = self.collection.find(filter, None).await?;
+        let houses: Vec<HouseEntity> = cur.try_collect().await?;
+
+        let candidates: Vec<CandidateDTO> = candidates
+            .into_iter()
+            .filter(|c| {
+                listing_type.is_none_or(|t| ListingType::from_link(&c.link) == Some(t))
+                    // The candidate of the house itself, if it was saved from the inbox
+                    && !same_listing(&target.link, &c.link)
+            })
+            .collect();
+
+        let features: Vec<SimilarityFeatures> = houses
+            .iter()
+            .map(SimilarityFeatures::from)
+            .chain(candidates.iter().map(|c| {
+                c.discovery
+                    .similarity_features(&self.amenity_tagger, &self.neighborhoods)
+            }))
+            .collect();
+        let neighbours = similarity::nearest(&(&target).into(), &features, limit);
+
+        let mut items: Vec<Option<SimilarItem>> = houses
+            .into_iter()
+            .map(|h| SimilarItem::House(Box::new(h.into())))
+            .chain(
+                candidates
+                    .into_iter()
+                    .map(|c| SimilarItem::Candidate(Box::new(c))),
+            )
+            .map(Some)
+            .collect();
+        Ok(neighbours
+            .into_iter()
+            .map(|n| SimilarDTO {
+                similarity: n.similarity,
+                item: items[n.index].take().unwrap(),
+            })
+            .collect())
+    }
+
     pub async fn update_house_by_id(&self, id: String, update_field: UpdateHouseDTO) -> Result<()> {
         let obj_id = ObjectId::from_str(&id)?;
 
         let filter = doc! {"_id": obj_id};
-        let update = doc! { "$set": {
-            "vote": update_field.vote,
-            "comment": update_field.comment,
-        } };
+        // Before the write: a vote out of a `u8` would make the house unreadable
+        let vote = update_field.vote.map(validate_vote).transpose()?;
+        let update = match &update_field.user {
+            None => doc! { "$set": {
+                "vote": vote.map(i32::from),
+                "comment": update_field.comment,
+            } },
+            // Each of us votes on their own, the comment is shared
+            Some(user) => {
+                let field = format!("votes.{}", validate_field_name("user", user)?);
+                match vote {
+                    Some(vote) => doc! { "$set": {
+                        field: i32::from(vote),
+                        "comment": update_field.comment,
+                    } },
+                    None => doc! {
+                        "$set": { "comment": update_field.comment },
+                        "$unset": { field: "" },
+                    },
+                }
+            }
+        };
 
         event!(Level::INFO, house_id = %id, "update");
         let ret = self
@@ -141,6 +1324,8 @@ pub struct HouseDTOInsert {
     pub link: String,
     pub vote: Option<u8>,
     pub comment: Option<String>,
+    /// Guessed from the link when missing
+    pub listing_type: Option<ListingType>,
 
     // Came from discovery_service::DiscoveryResult
     pub city: Option<String>,
@@ -150,7 +1335,155 @@ pub struct HouseDTOInsert {
     pub lng: Option<f64>,
     pub rooms_number: Option<u8>,
     pub square_meters: Option<u32>,
+    /// 0 for the ground floor
+    pub floor: Option<i8>,
     pub cost: Option<u32>,
+    #[serde(default)]
+    pub photos: Vec<String>,
+    #[serde(default)]
+    pub floor_plans: Vec<String>,
+    pub description: Option<String>,
+    pub advertiser: Option<Advertiser>,
+}
+
+impl From<&HouseEntity> for Listing {
+    fn from(h: &HouseEntity) -> Self {
+        Self {
+            square_meters: h.square_meters,
+            rooms_number: h.rooms_number,
+            floor: h.floor,
+            // The portals spell the zones each their own way
+            zone: h.neighborhood.clone().or_else(|| h.zone.clone()),
+            amenities: h.amenities.clone(),
+            cost: h.cost,
+        }
+    }
+}
+
+impl From<&HouseEntity> for VoteFeatures {
+    fn from(h: &HouseEntity) -> Self {
+        Self {
+            listing: h.into(),
+            metro_distance: h.nearest_stop.as_ref().map(|s| s.distance),
+        }
+    }
+}
+
+impl<'a> From<&'a HouseEntity> for RiskFeatures<'a> {
+    fn from(h: &'a HouseEntity) -> Self {
+        Self {
+            id: h._id.to_hex(),
+            listing_type: h.listing_type,
+            // The portals spell the zones each their own way
+            zone: h.neighborhood.as_deref().or(h.zone.as_deref()),
+            street: h.street.as_deref(),
+            position: h.lat.zip(h.lng),
+            cost_per_square_meter: h.cost_per_square_meter,
+            description: h.description.as_deref(),
+            photo_hashes: h
+                .photos
+                .iter()
+                .filter_map(MediaEntity::photo_hash)
+                .collect(),
+        }
+    }
+}
+
+impl From<&HouseEntity> for SimilarityFeatures {
+    fn from(h: &HouseEntity) -> Self {
+        Self {
+            listing: h.into(),
+            position: h.lat.zip(h.lng),
+        }
+    }
+}
+
+/// The votes guessed for a house, of the household and of each of us
+#[derive(Debug, Default, Serialize)]
+pub struct PredictedVotes {
+    pub predicted_vote: Option<PredictedVote>,
+    pub predicted_votes: BTreeMap<String, PredictedVote>,
+}
+
+/// One model for the household votes and one for each user
+struct VoteModels {
+    household: Option<VoteModel>,
+    users: BTreeMap<String, VoteModel>,
+}
+
+impl VoteModels {
+    fn fit(houses: &[(HouseEntity, VoteFeatures)]) -> Self {
+        let household: Vec<_> = houses
+            .iter()
+            .filter_map(|(h, f)| Some((f, h.vote?)))
+            .collect();
+
+        let mut by_user: BTreeMap<&str, Vec<(&VoteFeatures, u8)>> = BTreeMap::new();
+        for (house, features) in houses {
+            for (user, &vote) in &house.votes {
+                by_user.entry(user).or_default().push((features, vote));
+            }
+        }
+
+        Self {
+            household: VoteModel::fit(&household),
+            users: by_user
+                .into_iter()
+                .filter_map(|(user, voted)| Some((user.to_string(), VoteModel::fit(&voted)?)))
+                .collect(),
+        }
+    }
+
+    /// Only for the voters who did not vote the house
+    fn predict(&self, features: &VoteFeatures, house: Option<&HouseEntity>) -> PredictedVotes {
+        PredictedVotes {
+            predicted_vote: match house.and_then(|h| h.vote) {
+                Some(_) => None,
+                None => self.household.as_ref().map(|m| m.predict(features)),
+            },
+            predicted_votes: self
+                .users
+                .iter()
+                .filter(|(user, _)| house.is_none_or(|h| !h.votes.contains_key(*user)))
+                .map(|(user, m)| (user.clone(), m.predict(features)))
+                .collect(),
+        }
+    }
+}
+
+/// What `HousesService::refresh_in_background` computes again, in this order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Refresh {
+    Areas,
+    Layers,
+    Commutes,
+    Pois,
+    FairPrices,
+    PredictedVotes,
+    RiskFlags,
+    Scores,
+}
+
+/// To rent or to buy: the prices are not comparable
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ListingType {
+    Rent,
+    Sale,
+}
+
+impl ListingType {
+    /// The Italian portals say it in the url: ".../affitto-case/...", "/vendita-case/..."
+    pub fn from_link(link: &str) -> Option<Self> {
+        let link = link.to_lowercase();
+        if link.contains("affitt") {
+            Some(Self::Rent)
+        } else if link.contains("vendit") {
+            Some(Self::Sale)
+        } else {
+            None
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -158,45 +1491,149 @@ pub struct HouseDTOInsert {
 pub struct HouseEntity {
     _id: ObjectId,
     link: String,
+    /// Of the household
     vote: Option<u8>,
+    /// Of each of us, by user
+    #[serde(default)]
+    votes: BTreeMap<String, u8>,
+    /// Of the household, until it votes
+    predicted_vote: Option<PredictedVote>,
+    /// By user, for the houses they did not vote yet
+    #[serde(default)]
+    predicted_votes: BTreeMap<String, PredictedVote>,
+    /// By user, from picking the better of two houses
+    #[serde(default)]
+    ratings: BTreeMap<String, Rating>,
     comment: Option<String>,
+    listing_type: Option<ListingType>,
 
     removed: bool,
 
     // Came from discovery_service::DiscoveryResult
     city: Option<String>,
+    /// As the portal calls it
     zone: Option<String>,
+    /// The official one containing the house, see `Neighborhoods`
+    neighborhood: Option<String>,
     street: Option<String>,
     lat: Option<f64>,
     lng: Option<f64>,
+    /// Same as `lat` and `lng`, for the 2dsphere index
+    location: Option<GeoPoint>,
     rooms_number: Option<u8>,
     square_meters: Option<u32>,
+    floor: Option<i8>,
     cost: Option<u32>,
+    cost_per_square_meter: Option<f64>,
+    /// Against the other houses of the same listing type
+    fair_price: Option<FairPrice>,
+    #[serde(default)]
+    photos: Vec<MediaEntity>,
+    #[serde(default)]
+    floor_plans: Vec<MediaEntity>,
+    description: Option<String>,
+    #[serde(default)]
+    amenities: BTreeMap<String, bool>,
+    nearest_stop: Option<NearestStop>,
+    /// Walking distance from the nearest stop of every metro line
+    #[serde(default)]
+    line_distances: BTreeMap<String, f64>,
+    #[serde(default)]
+    commutes: Vec<CommuteEntity>,
+    /// Keyed by poi id
+    #[serde(default)]
+    poi_distances: BTreeMap<String, PoiDistance>,
+    /// Supermarkets, parks... within each configured radius
+    #[serde(default)]
+    nearby: Vec<AmenityCounts>,
+    /// The features containing the house, by layer name
+    #[serde(default)]
+    layers: BTreeMap<String, Vec<LayerMatch>>,
+    /// Inside or outside the saved areas, keyed by area id
+    #[serde(default)]
+    areas: BTreeMap<String, AreaFlag>,
+    /// Against each scoring profile, keyed by profile id
+    #[serde(default)]
+    scores: BTreeMap<String, Score>,
+    /// Why it may be a fake listing
+    #[serde(default)]
+    risk_flags: Vec<RiskFlag>,
+
+    agency_id: Option<ObjectId>,
+    #[serde(default)]
+    agency_blocked: bool,
+
+    /// Text search relevance, projected only when searching
+    #[serde(default, skip_serializing)]
+    score: Option<f64>,
 }
 
 impl From<HouseDTOInsert> for HouseEntity {
     fn from(h: HouseDTOInsert) -> Self {
+        let listing_type = h.listing_type.or_else(|| ListingType::from_link(&h.link));
         Self {
             _id: ObjectId::new(),
             link: h.link,
             vote: h.vote,
+            votes: BTreeMap::new(),
+            predicted_vote: None,
+            predicted_votes: BTreeMap::new(),
+            ratings: BTreeMap::new(),
+            listing_type,
             comment: h.comment,
             removed: false,
             city: h.city,
             zone: h.zone,
+            neighborhood: None,
             street: h.street,
             lat: h.lat,
             lng: h.lng,
+            location: None,
             rooms_number: h.rooms_number,
             square_meters: h.square_meters,
+            floor: h.floor,
             cost: h.cost,
+            cost_per_square_meter: None,
+            fair_price: None,
+            photos: vec![],
+            floor_plans: vec![],
+            description: h.description,
+            amenities: BTreeMap::new(),
+            nearest_stop: None,
+            line_distances: BTreeMap::new(),
+            commutes: vec![],
+            poi_distances: BTreeMap::new(),
+            nearby: vec![],
+            layers: BTreeMap::new(),
+            areas: BTreeMap::new(),
+            scores: BTreeMap::new(),
+            risk_flags: vec![],
+            agency_id: None,
+            agency_blocked: false,
+            score: None,
         }
     }
 }
 
+/// The outcome of `HousesService::record_price`
+#[derive(Serialize)]
+pub struct Reprice {
+    /// Saved
+    pub cost: Option<u32>,
+    /// Shown now by the portal
+    pub portal_cost: Option<u32>,
+    /// Whether it was a price not seen before
+    pub recorded: bool,
+}
+
 #[derive(Serialize)]
 pub struct HouseDTOInserted {
     pub id: String,
+    pub agency_blocked: bool,
+    /// The no-go areas the house is in, keyed by area id
+    pub no_go_areas: BTreeMap<String, AreaFlag>,
+    /// Why it may be a fake listing
+    pub risk_flags: Vec<RiskFlag>,
 }
 
 impl TryFrom<InsertOneResult> for HouseDTOInserted {
@@ -204,7 +1641,12 @@ impl TryFrom<InsertOneResult> for HouseDTOInserted {
 
     fn try_from(value: InsertOneResult) -> Result<Self> {
         match value.inserted_id.as_object_id() {
-            Some(id) => Ok(HouseDTOInserted { id: id.to_hex() }),
+            Some(id) => Ok(HouseDTOInserted {
+                id: id.to_hex(),
+                agency_blocked: false,
+                no_go_areas: BTreeMap::new(),
+                risk_flags: vec![],
+            }),
             None => Err(HousesServiceError::UnExpectedMongoDbType),
         }
     }
@@ -215,17 +1657,52 @@ pub struct HouseDTO {
     pub id: String,
     pub link: String,
     pub vote: Option<u8>,
+    votes: BTreeMap<String, u8>,
+    predicted_vote: Option<PredictedVote>,
+    predicted_votes: BTreeMap<String, PredictedVote>,
+    ratings: BTreeMap<String, Rating>,
     pub comment: Option<String>,
+    listing_type: Option<ListingType>,
 
     // Came from discovery_service::DiscoveryResult
     city: Option<String>,
     zone: Option<String>,
+    neighborhood: Option<String>,
     street: Option<String>,
     lat: Option<f64>,
     lng: Option<f64>,
     rooms_number: Option<u8>,
     square_meters: Option<u32>,
+    floor: Option<i8>,
     cost: Option<u32>,
+    cost_per_square_meter: Option<f64>,
+    fair_price: Option<FairPrice>,
+    photos: Vec<MediaDTO>,
+    floor_plans: Vec<MediaDTO>,
+    description: Option<String>,
+    amenities: BTreeMap<String, bool>,
+    nearest_stop: Option<NearestStop>,
+    line_distances: BTreeMap<String, f64>,
+    commutes: Vec<CommuteDTO>,
+    /// Keyed by poi id, of every user
+    poi_distances: BTreeMap<String, PoiDistance>,
+    nearby: Vec<AmenityCounts>,
+    layers: BTreeMap<String, Vec<LayerMatchDTO>>,
+    /// Keyed by area id, of every user
+    areas: BTreeMap<String, AreaFlag>,
+    /// Keyed by scoring profile id
+    scores: BTreeMap<String, Score>,
+    risk_flags: Vec<RiskFlag>,
+    agency_id: Option<String>,
+    agency_blocked: bool,
+    /// Only when searching with `q`
+    search: Option<SearchMatchDTO>,
+}
+
+#[derive(Serialize)]
+pub struct SearchMatchDTO {
+    score: f64,
+    snippets: Vec<Snippet>,
 }
 
 impl From<HouseEntity> for HouseDTO {
@@ -234,23 +1711,308 @@ impl From<HouseEntity> for HouseDTO {
             id: e._id.to_hex(),
             link: e.link,
             vote: e.vote,
+            votes: e.votes,
+            predicted_vote: e.predicted_vote,
+            predicted_votes: e.predicted_votes,
+            ratings: e.ratings,
             comment: e.comment,
+            listing_type: e.listing_type,
             city: e.city,
             zone: e.zone,
+            neighborhood: e.neighborhood,
             street: e.street,
             lat: e.lat,
             lng: e.lng,
             rooms_number: e.rooms_number,
             square_meters: e.square_meters,
+            floor: e.floor,
             cost: e.cost,
+            cost_per_square_meter: e.cost_per_square_meter,
+            fair_price: e.fair_price,
+            photos: e.photos.into_iter().map(MediaDTO::from).collect(),
+            floor_plans: e.floor_plans.into_iter().map(MediaDTO::from).collect(),
+            description: e.description,
+            amenities: e.amenities,
+            nearest_stop: e.nearest_stop,
+            line_distances: e.line_distances,
+            commutes: e.commutes.into_iter().map(CommuteDTO::from).collect(),
+            poi_distances: e.poi_distances,
+            nearby: e.nearby,
+            layers: e
+                .layers
+                .into_iter()
+                .map(|(layer, matches)| {
+                    (
+                        layer,
+                        matches.into_iter().map(LayerMatchDTO::from).collect(),
+                    )
+                })
+                .collect(),
+            areas: e.areas,
+            scores: e.scores,
+            risk_flags: e.risk_flags,
+            agency_id: e.agency_id.map(|id| id.to_hex()),
+            agency_blocked: e.agency_blocked,
+            search: None,
         }
     }
 }
 
+impl HouseEntity {
+    fn snippets(&self, q: &str) -> Vec<Snippet> {
+        highlight::snippets(
+            q,
+            &[
+                ("comment", self.comment.as_deref()),
+                ("street", self.street.as_deref()),
+                ("zone", self.zone.as_deref()),
+                ("city", self.city.as_deref()),
+                ("description", self.description.as_deref()),
+            ],
+        )
+    }
+
+    fn sort_value(&self, query: &HousesQuery) -> Option<f64> {
+        match query.sort {
+            HouseSort::InsertedAt => None,
+            HouseSort::Cost => self.cost.map(f64::from),
+            HouseSort::CostPerSquareMeter => self.cost_per_square_meter,
+            HouseSort::Vote => self.vote.map(f64::from),
+            HouseSort::PoiDistance => query
+                .poi
+                .as_ref()
+                .and_then(|poi| self.poi_distances.get(poi))
+                .map(PoiDistance::meters),
+            HouseSort::Score => query
+                .profile
+                .as_ref()
+                .and_then(|profile| self.scores.get(profile))
+                .map(|s| s.total),
+            HouseSort::Rating => query
+                .user
+                .as_ref()
+                .and_then(|user| self.ratings.get(user))
+                .map(|r| r.rating),
+        }
+    }
+
+    fn criterion_value(&self, criterion: &Criterion) -> Option<f64> {
+        match criterion {
+            Criterion::Cost => self.cost.map(f64::from),
+            Criterion::CostPerSquareMeter => self.cost_per_square_meter,
+            Criterion::SquareMeters => self.square_meters.map(f64::from),
+            Criterion::MetroDistance => self.nearest_stop.as_ref().map(|s| s.distance),
+            Criterion::PoiDistance { poi } => self.poi_distances.get(poi).map(PoiDistance::meters),
+            Criterion::Commute { destination } => self
+                .commutes
+                .iter()
+                .find(|c| c.destination_id().to_hex() == *destination)
+                .map(|c| c.minutes() as f64),
+            Criterion::Floor => self.floor.map(f64::from),
+            Criterion::Amenity { amenity } => {
+                self.amenities
+                    .get(amenity)
+                    .map(|&a| if a { 1.0 } else { 0.0 })
+            }
+        }
+    }
+}
+
+pub struct HousesPage {
+    pub houses: Vec<HouseDTO>,
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct HousePosition {
+    _id: ObjectId,
+    lat: Option<f64>,
+    lng: Option<f64>,
+    cost: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct HousePrice {
+    listing_type: Option<ListingType>,
+    lat: Option<f64>,
+    lng: Option<f64>,
+    cost_per_square_meter: Option<f64>,
+    removed: bool,
+}
+
 #[derive(Deserialize)]
 pub struct UpdateHouseDTO {
     comment: Option<String>,
     vote: Option<i32>,
+    /// Votes in `votes` instead of the household `vote`
+    user: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ComparisonPairDTO {
+    left: HouseDTO,
+    right: HouseDTO,
+}
+
+#[derive(Deserialize)]
+pub struct ComparisonDTOInsert {
+    user: String,
+    /// The id of the house picked
+    winner: String,
+    loser: String,
+}
+
+/// The new ratings
+#[derive(Serialize)]
+pub struct ComparisonDTO {
+    winner: Rating,
+    loser: Rating,
+}
+
+#[derive(Serialize)]
+pub struct SimilarDTO {
+    /// From 0 to 1
+    similarity: f64,
+    #[serde(flatten)]
+    item: SimilarItem,
+}
+
+/// A saved house or a candidate of the inbox
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SimilarItem {
+    House(Box<HouseDTO>),
+    Candidate(Box<CandidateDTO>),
+}
+
+#[derive(Serialize)]
+pub struct HousesComparisonDTO {
+    houses: Vec<HouseDTO>,
+    #[serde(flatten)]
+    comparison: Comparison,
+}
+
+/// Everything the houses can be told apart by.
+/// Commutes, pois, profiles and users of any of the houses get a row each.
+fn comparison_criteria(houses: &[HouseEntity]) -> Vec<CriterionComparison> {
+    let row = |criterion: String, better, value: &dyn Fn(&HouseEntity) -> Option<f64>| {
+        CriterionComparison::new(criterion, better, houses.iter().map(value).collect())
+    };
+
+    let mut criteria = vec![
+        row("cost".to_owned(), Better::Lower, &|h| h.cost.map(f64::from)),
+        row("cost_per_square_meter".to_owned(), Better::Lower, &|h| {
+            h.cost_per_square_meter
+        }),
+        row("square_meters".to_owned(), Better::Higher, &|h| {
+            h.square_meters.map(f64::from)
+        }),
+        row("rooms_number".to_owned(), Better::Higher, &|h| {
+            h.rooms_number.map(f64::from)
+        }),
+        row("metro_distance".to_owned(), Better::Lower, &|h| {
+            h.nearest_stop.as_ref().map(|s| s.distance)
+        }),
+        row("amenities".to_owned(), Better::Higher, &|h| {
+            Some(h.amenities.values().filter(|&&a| a).count() as f64)
+        }),
+        row("deal_score".to_owned(), Better::Higher, &|h| {
+            h.fair_price.as_ref().map(|f| f.deal_score)
+        }),
+        row("vote".to_owned(), Better::Higher, &|h| {
+            h.vote.map(f64::from)
+        }),
+    ];
+
+    let destinations: BTreeMap<ObjectId, &str> = houses
+        .iter()
+        .flat_map(|h| &h.commutes)
+        .map(|c| (c.destination_id(), c.destination()))
+        .collect();
+    for (id, destination) in destinations {
+        criteria.push(row(
+            format!("commute:{}", destination),
+            Better::Lower,
+            &|h| {
+                h.commutes
+                    .iter()
+                    .find(|c| c.destination_id() == id)
+                    .map(|c| c.minutes() as f64)
+            },
+        ));
+    }
+
+    let pois: BTreeMap<&String, &str> = houses
+        .iter()
+        .flat_map(|h| &h.poi_distances)
+        .map(|(id, d)| (id, d.name()))
+        .collect();
+    for (id, poi) in pois {
+        criteria.push(row(format!("poi:{}", poi), Better::Lower, &|h| {
+            h.poi_distances.get(id).map(PoiDistance::meters)
+        }));
+    }
+
+    let profiles: BTreeMap<&String, &str> = houses
+        .iter()
+        .flat_map(|h| &h.scores)
+        .map(|(id, s)| (id, s.profile.as_str()))
+        .collect();
+    for (id, profile) in profiles {
+        criteria.push(row(format!("score:{}", profile), Better::Higher, &|h| {
+            h.scores.get(id).map(|s| s.total)
+        }));
+    }
+
+    let voters: BTreeSet<&String> = houses.iter().flat_map(|h| h.votes.keys()).collect();
+    for user in voters {
+        criteria.push(row(format!("vote:{}", user), Better::Higher, &|h| {
+            h.votes.get(user).map(|&v| f64::from(v))
+        }));
+    }
+
+    let raters: BTreeSet<&String> = houses.iter().flat_map(|h| h.ratings.keys()).collect();
+    for user in raters {
+        criteria.push(row(format!("rating:{}", user), Better::Higher, &|h| {
+            h.ratings.get(user).map(|r| r.rating)
+        }));
+    }
+
+    criteria
+}
+
+/// Whether a saved link is the canonical one,
+/// maybe followed by a query string or a fragment, as in `links_filter`
+fn same_listing(saved: &str, canonical: &str) -> bool {
+    saved
+        .strip_prefix(canonical)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(['?', '#']))
+}
+
+/// The houses whose link is one of the canonical ones,
+/// maybe followed by a query string or a fragment
+fn links_filter(links: &[String]) -> Document {
+    let alternatives: Vec<String> = links.iter().map(|l| regex::escape(l)).collect();
+    doc! { "link": {
+        "$regex": format!("^(?:{})(?:[?#]|$)", alternatives.join("|")),
+    } }
+}
+
+/// Of a house against every profile, keyed by profile id
+fn scores(profiles: &[ScoringProfileEntity], house: &HouseEntity) -> BTreeMap<String, Score> {
+    profiles
+        .iter()
+        .map(|p| (p.id(), p.score(|c| house.criterion_value(c))))
+        .collect()
+}
+
+fn validate_vote(vote: i32) -> Result<u8> {
+    u8::try_from(vote)
+        .ok()
+        .filter(|&v| f64::from(v) <= MAX_VOTE)
+        .ok_or_else(|| {
+            HousesServiceError::InvalidQuery(format!("vote {} not in 0..={}", vote, MAX_VOTE))
+        })
 }
 
 #[cfg(test)]
@@ -261,37 +2023,244 @@ mod tests {
     use mongodb::Client;
 
     use super::*;
+    use crate::transit_stops::Stop;
 
     #[tokio::test]
     async fn test_flow() {
         pretty_env_logger::try_init().ok();
 
-        let collection = connect_mongo().await;
+        let db = connect_mongo().await;
 
-        let service = HousesService::new(collection);
+        let pois_service = PoisService::new(db.collection("pois"));
+        let service = HousesService::new(
+            db.collection("houses"),
+            MediaService::new(Default::default(), std::env::temp_dir()),
+            AmenityTagger::default(),
+            AgenciesService::new(db.collection("agencies"), "houses".to_string()),
+            TransitStops::new(vec![Stop {
+                name: "DUOMO".to_string(),
+                lines: vec!["M1".to_string(), "M3".to_string()],
+                lat: 45.4642,
+                lng: 9.1899,
+            }]),
+            CommuteService::new(db.collection("destinations"), None),
+            pois_service.clone(),
+            NearbyService::new(None, vec![500]),
+            Neighborhoods::default(),
+            LayersService::new(db.collection("layers"), db.collection("layer_features")),
+            AreasService::new(db.collection("areas")),
+            ScoringService::new(db.collection("scoring_profiles")),
+            PriceSnapshotsService::new(db.collection("price_snapshots")),
+        );
+        service.create_indexes().await.unwrap();
 
         let house = HouseDTOInsert {
             link: "http://the.link/foo".to_string(),
             vote: Some(0),
             comment: Some("the-comment".to_string()),
+            description: Some("Bilocale con ascensore".to_string()),
+            lat: Some(45.4642),
+            lng: Some(9.19),
             ..HouseDTOInsert::default()
         };
 
         let id = service.insert_house(house).await.unwrap();
         let id = id.id;
 
-        let houses = service.get_houses().await.unwrap();
+        let known = service
+            .existing_links(&[
+                "http://the.link/foo".to_string(),
+                "http://the.link/fo".to_string(),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(known, HashSet::from(["http://the.link/foo".to_string()]));
+
+        let houses = service
+            .get_houses(HousesQuery::default())
+            .await
+            .unwrap()
+            .houses;
 
         assert_eq!(houses.len(), 1);
         houses.iter().find(|h| h.id == id).unwrap();
 
+        let with_elevator = HousesQuery {
+            amenities: Some("elevator".to_string()),
+            ..HousesQuery::default()
+        };
+        let houses = service.get_houses(with_elevator).await.unwrap().houses;
+        assert_eq!(houses.len(), 1);
+
+        let with_cellar = HousesQuery {
+            amenities: Some("elevator,cellar".to_string()),
+            ..HousesQuery::default()
+        };
+        let houses = service.get_houses(with_cellar).await.unwrap().houses;
+        assert_eq!(houses.len(), 0);
+
+        let search = HousesQuery {
+            q: Some("ascensori".to_string()),
+            ..HousesQuery::default()
+        };
+        let houses = service.get_houses(search).await.unwrap().houses;
+        assert_eq!(houses.len(), 1);
+        let search = houses[0].search.as_ref().unwrap();
+        assert_eq!(
+            search.snippets[0].text,
+            "Bilocale con <mark>ascensore</mark>"
+        );
+
+        let near = NearQuery {
+            lat: 45.465,
+            lng: 9.19,
+            radius: 500.0,
+        };
+        let houses = service.get_houses_near(near).await.unwrap();
+        assert_eq!(houses.len(), 1);
+        assert_eq!(houses[0].nearest_stop.as_ref().unwrap().name, "DUOMO");
+
+        let near_m3 = HousesQuery {
+            line: Some("M3".to_string()),
+            line_distance: Some(300),
+            ..HousesQuery::default()
+        };
+        let houses = service.get_houses(near_m3).await.unwrap().houses;
+        assert_eq!(houses.len(), 1);
+        let near_m2 = HousesQuery {
+            line: Some("M2".to_string()),
+            ..HousesQuery::default()
+        };
+        let houses = service.get_houses(near_m2).await.unwrap().houses;
+        assert_eq!(houses.len(), 0);
+
+        let poi = pois_service
+            .insert_poi(
+                serde_json::from_value(serde_json::json!({
+                    "user": "alice",
+                    "name": "gym",
+                    "lat": 45.4683,
+                    "lng": 9.1755,
+                }))
+                .unwrap(),
+            )
+            .await
+            .unwrap();
+        service.refresh_pois().await.unwrap();
+        let near_gym = HousesQuery {
+            poi: Some(poi.clone()),
+            max_poi_distance: Some(2000),
+            sort: HouseSort::PoiDistance,
+            ..HousesQuery::default()
+        };
+        let houses = service.get_houses(near_gym).await.unwrap().houses;
+        assert_eq!(houses.len(), 1);
+        assert!(houses[0].poi_distances[&poi].meters() < 2000.0);
+        pois_service.remove_poi(poi).await.unwrap();
+
+        let viewport = BoundingBoxQuery {
+            west: 9.0,
+            south: 45.0,
+            east: 9.1,
+            north: 45.1,
+        };
+        let houses = service.get_houses_in_bbox(viewport).await.unwrap();
+        assert_eq!(houses.len(), 0);
+
         service.remove_house(id).await.unwrap();
 
-        let houses = service.get_houses().await.unwrap();
+        let houses = service
+            .get_houses(HousesQuery::default())
+            .await
+            .unwrap()
+            .houses;
         assert_eq!(houses.len(), 0);
     }
 
-    async fn connect_mongo() -> mongodb::Collection<HouseEntity> {
+    #[test]
+    fn test_validate_vote() {
+        assert_eq!(validate_vote(0).unwrap(), 0);
+        assert_eq!(validate_vote(10).unwrap(), 10);
+        for vote in [-1, 11, 300] {
+            assert!(matches!(
+                validate_vote(vote),
+                Err(HousesServiceError::InvalidQuery(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_same_listing() {
+        let link = "https://www.immobiliare.it/annunci/93679770/";
+        assert!(same_listing(link, link));
+        assert!(same_listing(&format!("{}?utm_source=rss", link), link));
+        assert!(same_listing(&format!("{}#photos", link), link));
+        assert!(!same_listing(
+            "https://www.immobiliare.it/annunci/936797701/",
+            "https://www.immobiliare.it/annunci/93679770"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_blocked_agency() {
+        pretty_env_logger::try_init().ok();
+
+        let db = connect_mongo().await;
+
+        let agencies_service = AgenciesService::new(
+            db.collection("agencies-blocked"),
+            "houses-blocked".to_string(),
+        );
+        let service = HousesService::new(
+            db.collection("houses-blocked"),
+            MediaService::new(Default::default(), std::env::temp_dir()),
+            AmenityTagger::default(),
+            agencies_service.clone(),
+            TransitStops::default(),
+            CommuteService::new(db.collection("destinations-blocked"), None),
+            PoisService::new(db.collection("pois-blocked")),
+            NearbyService::new(None, vec![500]),
+            Neighborhoods::default(),
+            LayersService::new(db.collection("layers"), db.collection("layer_features")),
+            AreasService::new(db.collection("areas")),
+            ScoringService::new(db.collection("scoring_profiles")),
+            PriceSnapshotsService::new(db.collection("price_snapshots")),
+        );
+
+        let house = || HouseDTOInsert {
+            link: "http://the.link/foo".to_string(),
+            advertiser: Some(Advertiser {
+                name: "the-agency".to_string(),
+                kind: crate::discovery_service::AdvertiserKind::Agency,
+                phone: None,
+                page_url: Some("http://the.link/agency".to_string()),
+            }),
+            ..HouseDTOInsert::default()
+        };
+
+        let inserted = service.insert_house(house()).await.unwrap();
+        assert!(!inserted.agency_blocked);
+
+        let agencies = agencies_service.get_agencies().await.unwrap();
+        assert_eq!(agencies.len(), 1);
+        assert_eq!(agencies[0].houses.len(), 1);
+
+        let block = serde_json::from_str(r#"{"blocked": true}"#).unwrap();
+        agencies_service
+            .update_agency_by_id(agencies[0].id.clone(), block)
+            .await
+            .unwrap();
+
+        let inserted = service.insert_house(house()).await.unwrap();
+        assert!(inserted.agency_blocked);
+
+        let agencies = agencies_service.get_agencies().await.unwrap();
+        assert_eq!(agencies.len(), 1);
+        assert!(agencies[0].blocked);
+        assert_eq!(agencies[0].houses.len(), 2);
+    }
+
+    async fn connect_mongo() -> mongodb::Database {
         let mut client_options = ClientOptions::parse_with_resolver_config(
             get_mongo_url(),
             ResolverConfig::cloudflare(),
@@ -308,9 +2277,7 @@ mod tests {
             .unwrap();
         let now = now.as_secs();
         let database_name = format!("test-{}", now);
-        let db = client.database(&database_name);
-
-        db.collection("houses")
+        client.database(&database_name)
     }
 
     fn get_mongo_url() -> String {
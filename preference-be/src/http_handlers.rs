@@ -1,19 +1,50 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
-use warp::{hyper::StatusCode, Rejection, Reply};
+use warp::{http::HeaderValue, hyper::StatusCode, Rejection, Reply};
 
 use crate::{
+    agency_service::{AgenciesService, AgenciesServiceError, UpdateAgencyDTO},
+    area_service::{AreaDTOInsert, AreaFlag, AreasQuery, AreasService, AreasServiceError},
+    candidate_service::{CandidatesService, CandidatesServiceError},
+    cluster,
+    commute_service::{CommuteService, CommuteServiceError, DestinationDTOInsert},
     discovery_service::{DiscoveryError, DiscoveryResult, DiscoveryService},
+    geo::GeoPolygon,
+    heatmap,
+    house_query::{
+        BoundingBoxQuery, ClusterQuery, CompareQuery, HeatmapQuery, HousesQuery, NearQuery,
+        SimilarQuery,
+    },
     house_service::{
-        HouseDTO, HouseDTOInsert, HouseDTOInserted, HousesService, HousesServiceError,
-        UpdateHouseDTO,
+        ComparisonDTOInsert, HouseDTO, HouseDTOInsert, HouseDTOInserted, HousesService,
+        HousesServiceError, PredictedVotes, Refresh, UpdateHouseDTO,
     },
+    ingestion_service::{IngestionError, IngestionService},
+    layer_service::{LayerDTOInsert, LayersService, LayersServiceError},
+    nearby_service::{NearbyService, NearbyServiceError},
+    neighborhoods::Neighborhoods,
+    poi_service::{PoiDTOInsert, PoisQuery, PoisService, PoisServiceError},
+    price_snapshot_service::{PriceSnapshotsService, PriceSnapshotsServiceError},
+    scoring_service::{ScoringProfileDTOInsert, ScoringService, ScoringServiceError},
+    statistics_service::{StatisticsQuery, StatisticsService, StatisticsServiceError},
+    transit_stops::{TransitStops, TransitStopsError},
 };
 
+/// A new price moves the fair price of every house, a new vote the predicted ones,
+/// new photos may be the stolen ones of another house: the others follow in background
 pub async fn insert_house(
     request_body: HouseDTOInsert,
     houses_service: HousesService,
 ) -> Result<impl warp::Reply, Rejection> {
-    Ok(houses_service.insert_house(request_body).await?)
+    let inserted = houses_service.insert_house(request_body).await?;
+    houses_service.refresh_in_background(&[
+        Refresh::FairPrices,
+        Refresh::PredictedVotes,
+        Refresh::RiskFlags,
+    ]);
+
+    Ok(inserted)
 }
 
 pub async fn remove_house(
@@ -25,8 +56,44 @@ pub async fn remove_house(
     Ok(StatusCode::NO_CONTENT)
 }
 
-pub async fn get_houses(houses_service: HousesService) -> Result<impl warp::Reply, Rejection> {
-    let houses = houses_service.get_houses().await?;
+pub async fn get_houses(
+    houses_service: HousesService,
+    query: HousesQuery,
+) -> Result<impl warp::Reply, Rejection> {
+    let page = houses_service.get_houses(query).await?;
+    let mut response = warp::reply::json(&page.houses).into_response();
+
+    // Sent as a header to keep the body a plain array
+    if let Some(cursor) = page.next_cursor {
+        response
+            .headers_mut()
+            .insert("x-next-cursor", HeaderValue::from_str(&cursor).unwrap());
+    }
+
+    Ok(response)
+}
+
+pub async fn get_houses_near(
+    houses_service: HousesService,
+    query: NearQuery,
+) -> Result<impl warp::Reply, Rejection> {
+    let houses = houses_service.get_houses_near(query).await?;
+    Ok(warp::reply::json(&houses))
+}
+
+pub async fn get_houses_within(
+    polygon: GeoPolygon,
+    houses_service: HousesService,
+) -> Result<impl warp::Reply, Rejection> {
+    let houses = houses_service.get_houses_within(polygon).await?;
+    Ok(warp::reply::json(&houses))
+}
+
+pub async fn get_houses_in_bbox(
+    houses_service: HousesService,
+    query: BoundingBoxQuery,
+) -> Result<impl warp::Reply, Rejection> {
+    let houses = houses_service.get_houses_in_bbox(query).await?;
     Ok(warp::reply::json(&houses))
 }
 
@@ -46,14 +113,394 @@ pub async fn update_house_by_id(
     let house = houses_service
         .update_house_by_id(house_id, update_field)
         .await?;
+    houses_service.refresh_in_background(&[Refresh::PredictedVotes]);
     Ok(warp::reply::json(&house))
 }
 
+/// `null` with less than two houses
+pub async fn get_comparison_pair(
+    houses_service: HousesService,
+    query: ComparisonPairQuery,
+) -> Result<impl warp::Reply, Rejection> {
+    let pair = houses_service.get_comparison_pair(&query.user).await?;
+    Ok(warp::reply::json(&pair))
+}
+
+#[derive(Deserialize)]
+pub struct ComparisonPairQuery {
+    user: String,
+}
+
+pub async fn compare(
+    request_body: ComparisonDTOInsert,
+    houses_service: HousesService,
+) -> Result<impl warp::Reply, Rejection> {
+    let ratings = houses_service.compare(request_body).await?;
+    Ok(warp::reply::json(&ratings))
+}
+
+/// The markers of the map viewport, grouped by the zoom level
+pub async fn get_clusters(
+    houses_service: HousesService,
+    candidates_service: CandidatesService,
+    query: ClusterQuery,
+) -> Result<impl warp::Reply, Rejection> {
+    let zoom = query.zoom().map_err(HousesServiceError::from)?;
+    let bbox = query.bounding_box();
+
+    let mut markers = houses_service.get_markers_in_bbox(&bbox).await?;
+    if query.candidates {
+        markers.extend(candidates_service.get_markers_in_bbox(&bbox).await?);
+    }
+
+    Ok(warp::reply::json(&cluster::cluster(&markers, zoom)))
+}
+
+pub async fn compare_houses(
+    houses_service: HousesService,
+    query: CompareQuery,
+) -> Result<impl warp::Reply, Rejection> {
+    let comparison = houses_service.compare_houses(&query).await?;
+    Ok(warp::reply::json(&comparison))
+}
+
+/// Fetches the listing again for the price the portal shows now,
+/// recorded next to the saved one that stays as it is
+pub async fn reprice_house(
+    house_id: String,
+    houses_service: HousesService,
+    discovery_service: DiscoveryService,
+) -> Result<impl warp::Reply, Rejection> {
+    let link = houses_service.reprice_link(&house_id).await?;
+    let discovery = discovery_service.discover(&link).await?;
+    let reprice = houses_service.record_price(&house_id, &discovery).await?;
+    Ok(warp::reply::json(&reprice))
+}
+
+/// Among the saved houses and the candidates of the inbox
+pub async fn get_similar_houses(
+    house_id: String,
+    houses_service: HousesService,
+    candidates_service: CandidatesService,
+    query: SimilarQuery,
+) -> Result<impl warp::Reply, Rejection> {
+    let candidates = candidates_service.get_candidates().await?;
+    let similar = houses_service
+        .get_similar_houses(house_id, candidates, &query)
+        .await?;
+    Ok(warp::reply::json(&similar))
+}
+
+/// Every house counts at its saved price, and once more at every other price
+/// the portal showed for it
+pub async fn get_heatmap(
+    houses_service: HousesService,
+    price_snapshots_service: PriceSnapshotsService,
+    query: HeatmapQuery,
+) -> Result<impl warp::Reply, Rejection> {
+    let precision = query.precision().map_err(HousesServiceError::from)?;
+    let mut samples = houses_service.get_price_samples(&query).await?;
+    samples.extend(price_snapshots_service.get_price_samples(&query).await?);
+
+    Ok(warp::reply::json(&heatmap::heatmap(&samples, precision)))
+}
+
+pub async fn get_statistics(
+    statistics_service: StatisticsService,
+    query: StatisticsQuery,
+) -> Result<impl warp::Reply, Rejection> {
+    let statistics = statistics_service.get_statistics(query).await?;
+    Ok(warp::reply::json(&statistics))
+}
+
+pub async fn get_stops(transit_stops: TransitStops) -> Result<impl warp::Reply, Rejection> {
+    Ok(warp::reply::json(&transit_stops.stops()))
+}
+
+/// Reads the stops file again and computes the distances from the new stops
+pub async fn refresh_stops(
+    transit_stops: TransitStops,
+    houses_service: HousesService,
+) -> Result<impl warp::Reply, Rejection> {
+    let stops = transit_stops.reload().await?;
+    let updated = houses_service.refresh_transit().await?;
+    houses_service.refresh_in_background(&[Refresh::Scores]);
+    Ok(warp::reply::json(&RefreshReport { stops, updated }))
+}
+
+pub async fn get_neighborhoods(
+    neighborhoods: Neighborhoods,
+) -> Result<impl warp::Reply, Rejection> {
+    Ok(warp::reply::json(&neighborhoods.names()))
+}
+
+#[derive(Serialize)]
+struct RefreshReport {
+    stops: usize,
+    updated: u64,
+}
+
+pub async fn get_destinations(
+    commute_service: CommuteService,
+) -> Result<impl warp::Reply, Rejection> {
+    let destinations = commute_service.get_destinations().await?;
+    Ok(warp::reply::json(&destinations))
+}
+
+/// The houses get the commute to the new destination in background
+pub async fn insert_destination(
+    request_body: DestinationDTOInsert,
+    commute_service: CommuteService,
+    houses_service: HousesService,
+) -> Result<impl warp::Reply, Rejection> {
+    let id = commute_service.insert_destination(request_body).await?;
+    houses_service.refresh_in_background(&[Refresh::Commutes, Refresh::Scores]);
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&Inserted { id }),
+        StatusCode::CREATED,
+    ))
+}
+
+pub async fn remove_destination(
+    destination_id: String,
+    commute_service: CommuteService,
+    houses_service: HousesService,
+) -> Result<impl warp::Reply, Rejection> {
+    commute_service.remove_destination(destination_id).await?;
+    houses_service.refresh_in_background(&[Refresh::Commutes, Refresh::Scores]);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn get_pois(
+    pois_service: PoisService,
+    query: PoisQuery,
+) -> Result<impl warp::Reply, Rejection> {
+    let pois = pois_service.get_pois(query).await?;
+    Ok(warp::reply::json(&pois))
+}
+
+/// The houses get the distance from the new poi in background
+pub async fn insert_poi(
+    request_body: PoiDTOInsert,
+    pois_service: PoisService,
+    houses_service: HousesService,
+) -> Result<impl warp::Reply, Rejection> {
+    let id = pois_service.insert_poi(request_body).await?;
+    houses_service.refresh_in_background(&[Refresh::Pois, Refresh::Scores]);
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&Inserted { id }),
+        StatusCode::CREATED,
+    ))
+}
+
+pub async fn remove_poi(
+    poi_id: String,
+    pois_service: PoisService,
+    houses_service: HousesService,
+) -> Result<impl warp::Reply, Rejection> {
+    pois_service.remove_poi(poi_id).await?;
+    houses_service.refresh_in_background(&[Refresh::Pois, Refresh::Scores]);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn get_areas(
+    areas_service: AreasService,
+    query: AreasQuery,
+) -> Result<impl warp::Reply, Rejection> {
+    let areas = areas_service.get_areas(query).await?;
+    Ok(warp::reply::json(&areas))
+}
+
+/// The houses are flagged inside or outside the new area in background
+pub async fn insert_area(
+    request_body: AreaDTOInsert,
+    areas_service: AreasService,
+    houses_service: HousesService,
+) -> Result<impl warp::Reply, Rejection> {
+    let id = areas_service.insert_area(request_body).await?;
+    houses_service.refresh_in_background(&[Refresh::Areas]);
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&Inserted { id }),
+        StatusCode::CREATED,
+    ))
+}
+
+pub async fn remove_area(
+    area_id: String,
+    areas_service: AreasService,
+    houses_service: HousesService,
+) -> Result<impl warp::Reply, Rejection> {
+    areas_service.remove_area(area_id).await?;
+    houses_service.refresh_in_background(&[Refresh::Areas]);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn get_profiles(scoring_service: ScoringService) -> Result<impl warp::Reply, Rejection> {
+    let profiles = scoring_service.get_profiles().await?;
+    Ok(warp::reply::json(&profiles))
+}
+
+/// The houses are scored against the new profile in background
+pub async fn insert_profile(
+    request_body: ScoringProfileDTOInsert,
+    scoring_service: ScoringService,
+    houses_service: HousesService,
+) -> Result<impl warp::Reply, Rejection> {
+    let id = scoring_service.insert_profile(request_body).await?;
+    houses_service.refresh_in_background(&[Refresh::Scores]);
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&Inserted { id }),
+        StatusCode::CREATED,
+    ))
+}
+
+pub async fn remove_profile(
+    profile_id: String,
+    scoring_service: ScoringService,
+    houses_service: HousesService,
+) -> Result<impl warp::Reply, Rejection> {
+    scoring_service.remove_profile(profile_id).await?;
+    houses_service.refresh_in_background(&[Refresh::Scores]);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize)]
+struct Inserted {
+    id: String,
+}
+
+pub async fn get_layers(layers_service: LayersService) -> Result<impl warp::Reply, Rejection> {
+    let layers = layers_service.get_layers().await?;
+    Ok(warp::reply::json(&layers))
+}
+
+/// Importing a layer with an existing name replaces it.
+/// The houses get the features containing them in background.
+pub async fn import_layer(
+    request_body: LayerDTOInsert,
+    layers_service: LayersService,
+    houses_service: HousesService,
+) -> Result<impl warp::Reply, Rejection> {
+    let layer = layers_service.import_layer(request_body).await?;
+    houses_service.refresh_in_background(&[Refresh::Layers]);
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&layer),
+        StatusCode::CREATED,
+    ))
+}
+
+pub async fn remove_layer(
+    layer_id: String,
+    layers_service: LayersService,
+    houses_service: HousesService,
+) -> Result<impl warp::Reply, Rejection> {
+    layers_service.remove_layer(layer_id).await?;
+    houses_service.refresh_in_background(&[Refresh::Layers]);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Reads the OSM extract again and counts the amenities around every house
+pub async fn import_osm(
+    nearby_service: NearbyService,
+    houses_service: HousesService,
+) -> Result<impl warp::Reply, Rejection> {
+    let amenities = nearby_service.import().await?;
+    let updated = houses_service.refresh_nearby().await?;
+
+    Ok(warp::reply::json(&ImportReport { amenities, updated }))
+}
+
+#[derive(Serialize)]
+struct ImportReport {
+    amenities: usize,
+    updated: u64,
+}
+
+pub async fn get_agencies(
+    agencies_service: AgenciesService,
+) -> Result<impl warp::Reply, Rejection> {
+    let agencies = agencies_service.get_agencies().await?;
+    Ok(warp::reply::json(&agencies))
+}
+
+pub async fn update_agency_by_id(
+    agency_id: String,
+    update_field: UpdateAgencyDTO,
+    agencies_service: AgenciesService,
+) -> Result<impl warp::Reply, Rejection> {
+    agencies_service
+        .update_agency_by_id(agency_id, update_field)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn ingest(ingestion_service: IngestionService) -> Result<impl warp::Reply, Rejection> {
+    let report = ingestion_service.ingest().await?;
+    Ok(warp::reply::json(&report))
+}
+
+pub async fn get_candidates(
+    candidates_service: CandidatesService,
+) -> Result<impl warp::Reply, Rejection> {
+    let candidates = candidates_service.get_candidates().await?;
+    Ok(warp::reply::json(&candidates))
+}
+
+pub async fn remove_candidate(
+    candidate_id: String,
+    candidates_service: CandidatesService,
+) -> Result<impl warp::Reply, Rejection> {
+    candidates_service.remove_candidate(candidate_id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// With the votes it would get and the no-go areas it is in, to triage it
 pub async fn discover(
     discovery_service: DiscoveryService,
+    houses_service: HousesService,
+    areas_service: AreasService,
     params: DiscoverQueryParameter,
 ) -> Result<impl warp::Reply, Rejection> {
-    Ok(discovery_service.discover(&params.url).await?)
+    let discovery = discovery_service.discover(&params.url).await?;
+    let predicted = houses_service.predict_votes(&discovery).await?;
+    let no_go_areas = match discovery.position() {
+        None => BTreeMap::new(),
+        Some((lat, lng)) => areas_service
+            .load()
+            .await?
+            .flags(lat, lng)
+            .into_iter()
+            .filter(|(_, flag)| flag.is_no_go())
+            .collect(),
+    };
+
+    Ok(warp::reply::json(&Discovered {
+        discovery,
+        predicted,
+        no_go_areas,
+    }))
+}
+
+#[derive(Serialize)]
+struct Discovered {
+    #[serde(flatten)]
+    discovery: DiscoveryResult,
+    #[serde(flatten)]
+    predicted: PredictedVotes,
+    /// Keyed by area id
+    no_go_areas: BTreeMap<String, AreaFlag>,
 }
 
 #[derive(Deserialize)]
@@ -73,11 +520,94 @@ pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
         code = StatusCode::NOT_FOUND;
         message = "NOT_FOUND".to_owned();
     } else if let Some(err) = err.find::<HousesServiceError>() {
+        code = match err {
+            HousesServiceError::HouseNotFound(_) => StatusCode::NOT_FOUND,
+            HousesServiceError::ObjectId(_)
+            | HousesServiceError::InvalidQuery(_)
+            | HousesServiceError::InvalidGeometry(_) => StatusCode::BAD_REQUEST,
+            HousesServiceError::Conflict(_) => StatusCode::CONFLICT,
+            HousesServiceError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        message = err.to_string();
+    } else if let Some(err) = err.find::<AgenciesServiceError>() {
+        code = match err {
+            AgenciesServiceError::AgencyNotFound(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        message = err.to_string();
+    } else if let Some(err) = err.find::<CommuteServiceError>() {
+        code = match err {
+            CommuteServiceError::DestinationNotFound(_) => StatusCode::NOT_FOUND,
+            CommuteServiceError::InvalidDeparture(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        message = err.to_string();
+    } else if let Some(err) = err.find::<PoisServiceError>() {
+        code = match err {
+            PoisServiceError::PoiNotFound(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        message = err.to_string();
+    } else if let Some(err) = err.find::<AreasServiceError>() {
+        code = match err {
+            AreasServiceError::AreaNotFound(_) => StatusCode::NOT_FOUND,
+            AreasServiceError::InvalidGeometry(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        message = err.to_string();
+    } else if let Some(err) = err.find::<ScoringServiceError>() {
+        code = match err {
+            ScoringServiceError::ProfileNotFound(_) => StatusCode::NOT_FOUND,
+            ScoringServiceError::InvalidProfile(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        message = err.to_string();
+    } else if let Some(err) = err.find::<LayersServiceError>() {
+        code = match err {
+            LayersServiceError::LayerNotFound(_) => StatusCode::NOT_FOUND,
+            LayersServiceError::InvalidLayer(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        message = err.to_string();
+    } else if let Some(err) = err.find::<NearbyServiceError>() {
+        code = match err {
+            NearbyServiceError::NoExtract => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        message = err.to_string();
+    } else if let Some(err) = err.find::<TransitStopsError>() {
+        code = match err {
+            TransitStopsError::NoFile => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        message = err.to_string();
+    } else if let Some(err) = err.find::<StatisticsServiceError>() {
         code = StatusCode::INTERNAL_SERVER_ERROR;
-        message = format!("{:?}", err);
+        message = err.to_string();
+    } else if let Some(err) = err.find::<CandidatesServiceError>() {
+        code = match err {
+            CandidatesServiceError::CandidateNotFound(_) => StatusCode::NOT_FOUND,
+            CandidatesServiceError::InvalidGeometry(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        message = err.to_string();
+    } else if let Some(err) = err.find::<PriceSnapshotsServiceError>() {
+        code = match err {
+            PriceSnapshotsServiceError::InvalidQuery(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        message = err.to_string();
+    } else if let Some(err) = err.find::<IngestionError>() {
+        code = StatusCode::INTERNAL_SERVER_ERROR;
+        message = err.to_string();
     } else if let Some(err) = err.find::<DiscoveryError>() {
-        code = StatusCode::NOT_FOUND;
-        message = format!("{:?}", err);
+        code = match err {
+            DiscoveryError::InvalidUrl(_) => StatusCode::BAD_REQUEST,
+            DiscoveryError::WarmUpFailed(_) => StatusCode::BAD_GATEWAY,
+            _ => StatusCode::NOT_FOUND,
+        };
+        message = err.to_string();
     } else {
         // We should have expected this... Just log and say its a 500
         eprintln!("unhandled rejection: {:?}", err);
@@ -101,6 +631,18 @@ struct ErrorMessage {
 
 impl warp::reject::Reject for HousesServiceError {}
 impl warp::reject::Reject for DiscoveryError {}
+impl warp::reject::Reject for AgenciesServiceError {}
+impl warp::reject::Reject for CandidatesServiceError {}
+impl warp::reject::Reject for CommuteServiceError {}
+impl warp::reject::Reject for PoisServiceError {}
+impl warp::reject::Reject for NearbyServiceError {}
+impl warp::reject::Reject for LayersServiceError {}
+impl warp::reject::Reject for AreasServiceError {}
+impl warp::reject::Reject for ScoringServiceError {}
+impl warp::reject::Reject for StatisticsServiceError {}
+impl warp::reject::Reject for IngestionError {}
+impl warp::reject::Reject for PriceSnapshotsServiceError {}
+impl warp::reject::Reject for TransitStopsError {}
 
 /// Needed for returning the structures directly from the handlers
 impl warp::Reply for HouseDTOInserted {
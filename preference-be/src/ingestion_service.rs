@@ -0,0 +1,561 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+};
+
+use quick_xml::{
+    events::{BytesStart, Event},
+    Reader,
+};
+use regex::Regex;
+use reqwest::Client;
+use serde::Serialize;
+use tracing::{event, Level};
+
+use crate::{
+    candidate_service::{CandidatesService, CandidatesServiceError},
+    discovery_service::DiscoveryService,
+    house_service::{HousesService, HousesServiceError},
+};
+
+/// Collects the listing links from the saved-search feeds and alert emails
+/// of the portals and puts the new ones in the candidate inbox
+#[derive(Clone)]
+pub struct IngestionService {
+    client: Client,
+    discovery_service: DiscoveryService,
+    houses_service: HousesService,
+    candidates_service: CandidatesService,
+    /// Paths or urls of RSS/Atom feeds
+    feeds: Vec<String>,
+    /// A `.eml`/`.mbox` file or a directory containing them
+    mail_path: Option<PathBuf>,
+}
+
+type Result<T> = std::result::Result<T, IngestionError>;
+
+#[derive(Debug)]
+pub enum IngestionError {
+    ReqwestError(reqwest::Error),
+    Io(std::io::Error),
+    Xml(quick_xml::Error),
+    HousesError(HousesServiceError),
+    CandidatesError(CandidatesServiceError),
+}
+
+impl fmt::Display for IngestionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::ReqwestError(e) => write!(f, "request failed: {}", e),
+            Self::Io(e) => write!(f, "io: {}", e),
+            Self::Xml(e) => write!(f, "invalid feed: {}", e),
+            Self::HousesError(e) => e.fmt(f),
+            Self::CandidatesError(e) => e.fmt(f),
+        }
+    }
+}
+
+impl From<reqwest::Error> for IngestionError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::ReqwestError(e)
+    }
+}
+impl From<std::io::Error> for IngestionError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+impl From<quick_xml::Error> for IngestionError {
+    fn from(e: quick_xml::Error) -> Self {
+        Self::Xml(e)
+    }
+}
+impl From<HousesServiceError> for IngestionError {
+    fn from(e: HousesServiceError) -> Self {
+        Self::HousesError(e)
+    }
+}
+impl From<CandidatesServiceError> for IngestionError {
+    fn from(e: CandidatesServiceError) -> Self {
+        Self::CandidatesError(e)
+    }
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct IngestionReport {
+    /// Distinct listing links found in the sources
+    pub found: usize,
+    /// Links already saved as houses or already in the inbox
+    pub already_known: usize,
+    /// Other prices of the candidates another ingestion inserted meanwhile
+    pub prices_recorded: usize,
+    pub inserted: usize,
+    /// Links the discovery was not able to fetch
+    pub failed: usize,
+    /// The feeds not reachable or not valid, whose links are missing
+    pub failed_feeds: Vec<String>,
+}
+
+impl IngestionService {
+    pub fn new(
+        discovery_service: DiscoveryService,
+        houses_service: HousesService,
+        candidates_service: CandidatesService,
+        feeds: Vec<String>,
+        mail_path: Option<PathBuf>,
+    ) -> Self {
+        Self {
+            client: discovery_service.client(),
+            discovery_service,
+            houses_service,
+            candidates_service,
+            feeds,
+            mail_path,
+        }
+    }
+
+    pub async fn ingest(&self) -> Result<IngestionReport> {
+        let mut links = vec![];
+
+        // One feed down should not stop the others
+        let mut failed_feeds = vec![];
+        for feed in &self.feeds {
+            event!(Level::INFO, feed = %feed, "reading feed");
+            let feed_links = read_feed(&self.client, feed)
+                .await
+                .and_then(|content| links_from_feed(&content));
+            match feed_links {
+                Ok(feed_links) => links.extend(feed_links.into_iter().map(|l| (l, feed.clone()))),
+                Err(e) => {
+                    event!(Level::WARN, feed = %feed, error = %e, "feed failed");
+                    failed_feeds.push(feed.clone());
+                }
+            }
+        }
+
+        if let Some(mail_path) = &self.mail_path {
+            for file in mail_files(mail_path)? {
+                event!(Level::INFO, file = ?file, "reading mails");
+                let content = String::from_utf8_lossy(&tokio::fs::read(&file).await?).into_owned();
+                let source = file.to_string_lossy().into_owned();
+
+                let messages = match file.extension().and_then(|e| e.to_str()) {
+                    Some("mbox") => split_mbox(&content),
+                    _ => vec![content],
+                };
+                for message in messages {
+                    links.extend(
+                        links_from_email(&message)
+                            .into_iter()
+                            .map(|l| (l, source.clone())),
+                    );
+                }
+            }
+        }
+
+        let mut seen = HashSet::new();
+        let links: Vec<_> = links
+            .into_iter()
+            .filter_map(|(l, source)| self.discovery_service.listing_url(&l).map(|l| (l, source)))
+            .filter(|(l, _)| seen.insert(l.clone()))
+            .collect();
+
+        let mut report = IngestionReport {
+            found: links.len(),
+            failed_feeds,
+            ..IngestionReport::default()
+        };
+
+        let all: Vec<_> = links.iter().map(|(l, _)| l.clone()).collect();
+        let mut known = self.houses_service.existing_links(&all).await?;
+        known.extend(self.candidates_service.existing_links(&all).await?);
+
+        for (link, source) in links {
+            if known.contains(&link) {
+                report.already_known += 1;
+                continue;
+            }
+
+            match self.discovery_service.discover(&link).await {
+                Ok(discovery) => {
+                    // Another ingestion may have inserted it meanwhile
+                    if self
+                        .candidates_service
+                        .insert_candidate(link.clone(), source, discovery.clone())
+                        .await?
+                    {
+                        report.inserted += 1;
+                        continue;
+                    }
+                    report.already_known += 1;
+                    // The price is here anyway, while the known links are not fetched again
+                    if self
+                        .candidates_service
+                        .record_price(&link, &discovery)
+                        .await?
+                    {
+                        report.prices_recorded += 1;
+                    }
+                }
+                Err(e) => {
+                    event!(Level::WARN, link = %link, error = ?e, "discovery failed");
+                    report.failed += 1;
+                }
+            }
+        }
+
+        event!(Level::INFO, report = ?report, "ingested");
+
+        Ok(report)
+    }
+}
+
+async fn read_feed(client: &Client, feed: &str) -> Result<String> {
+    if feed.starts_with("http://") || feed.starts_with("https://") {
+        // Not to read an error page as a feed
+        let response = client.get(feed).send().await?.error_for_status()?;
+        return Ok(response.text().await?);
+    }
+    Ok(tokio::fs::read_to_string(feed).await?)
+}
+
+fn mail_files(path: &Path) -> Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut files = vec![];
+    for entry in std::fs::read_dir(path)? {
+        let file = entry?.path();
+        if matches!(
+            file.extension().and_then(|e| e.to_str()),
+            Some("eml" | "mbox")
+        ) {
+            files.push(file);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Links of the items of an RSS or Atom feed,
+/// plus the ones inside their descriptions
+fn links_from_feed(xml: &str) -> Result<Vec<String>> {
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+
+    let mut links = vec![];
+    let mut in_link = false;
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) if e.local_name().as_ref() == b"link" => {
+                in_link = true;
+                links.extend(href(&e));
+            }
+            Event::Empty(e) if e.local_name().as_ref() == b"link" => links.extend(href(&e)),
+            Event::End(e) if e.local_name().as_ref() == b"link" => in_link = false,
+            Event::Text(t) => {
+                let text = t.unescape()?;
+                if in_link {
+                    links.push(text.trim().to_owned());
+                } else {
+                    links.extend(urls_in(&text));
+                }
+            }
+            Event::CData(c) => links.extend(urls_in(&String::from_utf8_lossy(&c.into_inner()))),
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(links)
+}
+
+// Atom puts the link in the attribute
+fn href(e: &BytesStart) -> Option<String> {
+    e.attributes()
+        .flatten()
+        .find(|a| a.key.local_name().as_ref() == b"href")
+        .and_then(|a| a.unescape_value().ok())
+        .map(|v| v.into_owned())
+}
+
+fn split_mbox(content: &str) -> Vec<String> {
+    let mut messages = vec![];
+    let mut current = String::new();
+
+    for line in content.lines() {
+        if line.starts_with("From ") {
+            if !current.trim().is_empty() {
+                messages.push(std::mem::take(&mut current));
+            }
+            continue;
+        }
+        current.push_str(line);
+        current.push('\n');
+    }
+    if !current.trim().is_empty() {
+        messages.push(current);
+    }
+
+    messages
+}
+
+fn links_from_email(raw: &str) -> Vec<String> {
+    urls_in(&mime_text(raw))
+}
+
+/// Decoded text of all the parts of a MIME message
+fn mime_text(raw: &str) -> String {
+    let (headers, body) = split_headers(raw);
+    let content_type = header(headers, "content-type").unwrap_or_default();
+
+    if content_type.to_lowercase().starts_with("multipart/") {
+        if let Some(boundary) = boundary(&content_type) {
+            return body
+                .split(&format!("--{}", boundary))
+                .skip(1)
+                .filter(|part| !part.starts_with("--"))
+                .map(|part| {
+                    let part = part
+                        .strip_prefix("\r\n")
+                        .or_else(|| part.strip_prefix('\n'))
+                        .unwrap_or(part);
+                    mime_text(part)
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+        }
+    }
+
+    let encoding = header(headers, "content-transfer-encoding")
+        .unwrap_or_default()
+        .to_lowercase();
+    match encoding.as_str() {
+        "quoted-printable" => decode_quoted_printable(body),
+        "base64" => {
+            let body: String = body.split_whitespace().collect();
+            base64::decode(body)
+                .map(|b| String::from_utf8_lossy(&b).into_owned())
+                .unwrap_or_default()
+        }
+        _ => body.to_owned(),
+    }
+}
+
+fn split_headers(raw: &str) -> (&str, &str) {
+    if let Some(body) = raw.strip_prefix("\r\n").or_else(|| raw.strip_prefix('\n')) {
+        return ("", body);
+    }
+
+    let crlf = raw.find("\r\n\r\n").map(|i| (i, 4));
+    let lf = raw.find("\n\n").map(|i| (i, 2));
+    match crlf.into_iter().chain(lf).min() {
+        Some((i, len)) => (&raw[..i], &raw[i + len..]),
+        None => (raw, ""),
+    }
+}
+
+fn header(headers: &str, name: &str) -> Option<String> {
+    // Headers can be folded on more lines starting with a whitespace
+    let mut unfolded: Vec<String> = vec![];
+    for line in headers.lines() {
+        match unfolded.last_mut() {
+            Some(last) if line.starts_with(char::is_whitespace) => {
+                last.push(' ');
+                last.push_str(line.trim());
+            }
+            _ => unfolded.push(line.to_owned()),
+        }
+    }
+
+    unfolded.into_iter().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        if key.trim().eq_ignore_ascii_case(name) {
+            Some(value.trim().to_owned())
+        } else {
+            None
+        }
+    })
+}
+
+fn boundary(content_type: &str) -> Option<String> {
+    content_type.split(';').find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        if key.trim().eq_ignore_ascii_case("boundary") {
+            Some(value.trim().trim_matches('"').to_owned())
+        } else {
+            None
+        }
+    })
+}
+
+fn decode_quoted_printable(body: &str) -> String {
+    let bytes = body.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'=' {
+            decoded.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+
+        // Soft line break
+        if bytes[i + 1..].starts_with(b"\r\n") {
+            i += 3;
+            continue;
+        }
+        if bytes[i + 1..].starts_with(b"\n") {
+            i += 2;
+            continue;
+        }
+
+        let byte = body
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match byte {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(b'=');
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+static URL: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"https?://[^\s"'<>]+"#).unwrap());
+
+fn urls_in(text: &str) -> Vec<String> {
+    URL.find_iter(text)
+        .map(|m| {
+            m.as_str()
+                .trim_end_matches(['.', ',', ';', ')'])
+                .replace("&amp;", "&")
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_links_from_rss() {
+        let rss = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0">
+  <channel>
+    <title>Ricerca salvata</title>
+    <link>https://www.immobiliare.it/ricerca/</link>
+    <item>
+      <title>Bilocale via Pellegrino Rossi</title>
+      <link>https://www.immobiliare.it/annunci/93679770/?utm_source=rss&amp;utm_medium=feed</link>
+      <description>&lt;a href="https://www.immobiliare.it/annunci/93679771/"&gt;simile&lt;/a&gt;</description>
+    </item>
+  </channel>
+</rss>"#;
+
+        assert_eq!(
+            links_from_feed(rss).unwrap(),
+            vec![
+                "https://www.immobiliare.it/ricerca/".to_string(),
+                "https://www.immobiliare.it/annunci/93679770/?utm_source=rss&utm_medium=feed"
+                    .to_string(),
+                "https://www.immobiliare.it/annunci/93679771/".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_links_from_atom() {
+        let atom = r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>Ricerca salvata</title>
+  <entry>
+    <title>Trilocale Viale Ranzoni</title>
+    <link rel="alternate" href="https://www.idealista.it/immobile/24871234/"/>
+    <summary><![CDATA[Vedi anche https://www.idealista.it/immobile/24871235/.]]></summary>
+  </entry>
+</feed>"#;
+
+        assert_eq!(
+            links_from_feed(atom).unwrap(),
+            vec![
+                "https://www.idealista.it/immobile/24871234/".to_string(),
+                "https://www.idealista.it/immobile/24871235/".to_string(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_feed() {
+        use httpmock::prelude::*;
+        let server = MockServer::start();
+
+        let feed_mock = server.mock(|when, then| {
+            when.method(GET).path("/feed.xml");
+            then.status(200).body("<rss/>");
+        });
+        let error_mock = server.mock(|when, then| {
+            when.method(GET).path("/gone.xml");
+            then.status(404).body("<html>Not found</html>");
+        });
+
+        let client = Client::new();
+        assert_eq!(
+            read_feed(&client, &server.url("/feed.xml")).await.unwrap(),
+            "<rss/>"
+        );
+        assert!(matches!(
+            read_feed(&client, &server.url("/gone.xml")).await,
+            Err(IngestionError::ReqwestError(_))
+        ));
+        assert!(matches!(
+            read_feed(&client, "/no/such/feed.xml").await,
+            Err(IngestionError::Io(_))
+        ));
+
+        feed_mock.assert();
+        error_mock.assert();
+    }
+
+    #[test]
+    fn test_links_from_mbox() {
+        let mbox = "From alert@immobiliare.it Mon Mar 21 08:00:00 2022\n\
+Subject: Nuovi annunci\n\
+Content-Type: multipart/alternative; boundary=\"b1\"\n\
+\n\
+--b1\n\
+Content-Type: text/html; charset=utf-8\n\
+Content-Transfer-Encoding: quoted-printable\n\
+\n\
+<a href=3D\"https://www.immobiliare.it/annunci/9367=\n\
+9770/?from=3Dalert\">Bilocale</a>\n\
+--b1--\n\
+From alert@idealista.it Tue Mar 22 08:00:00 2022\n\
+Subject: Nuovi annunci\n\
+Content-Transfer-Encoding: base64\n\
+\n\
+aHR0cHM6Ly93d3cuaWRlYWxpc3RhLml0L2ltbW9iaWxlLzI0ODcxMjM0Lw==\n";
+
+        let messages = split_mbox(mbox);
+        assert_eq!(messages.len(), 2);
+
+        assert_eq!(
+            links_from_email(&messages[0]),
+            vec!["https://www.immobiliare.it/annunci/93679770/?from=alert".to_string()]
+        );
+        assert_eq!(
+            links_from_email(&messages[1]),
+            vec!["https://www.idealista.it/immobile/24871234/".to_string()]
+        );
+    }
+}
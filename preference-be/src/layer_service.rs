@@ -0,0 +1,346 @@
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document},
+    options::IndexOptions,
+    Collection, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+
+use crate::{
+    geo::{GeoArea, GeoPoint},
+    house_query::validate_field_name,
+};
+
+/// Open data areas to judge the houses against: noise maps, flood zones,
+/// low-emission zones...
+#[derive(Clone)]
+pub struct LayersService {
+    collection: Collection<LayerEntity>,
+    features: Collection<LayerFeatureEntity>,
+}
+
+type Result<T> = std::result::Result<T, LayersServiceError>;
+
+#[derive(Debug)]
+pub enum LayersServiceError {
+    MongoDbError(mongodb::error::Error),
+    ObjectId(mongodb::bson::oid::Error),
+    LayerNotFound(String),
+    InvalidLayer(String),
+}
+
+impl fmt::Display for LayersServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::MongoDbError(e) => write!(f, "mongodb: {}", e),
+            Self::ObjectId(e) => write!(f, "invalid id: {}", e),
+            Self::LayerNotFound(id) => write!(f, "layer {} not found", id),
+            Self::InvalidLayer(message) => write!(f, "invalid layer: {}", message),
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for LayersServiceError {
+    fn from(e: mongodb::error::Error) -> Self {
+        Self::MongoDbError(e)
+    }
+}
+impl From<mongodb::bson::oid::Error> for LayersServiceError {
+    fn from(e: mongodb::bson::oid::Error) -> Self {
+        Self::ObjectId(e)
+    }
+}
+
+impl LayersService {
+    pub fn new(
+        collection: Collection<LayerEntity>,
+        features: Collection<LayerFeatureEntity>,
+    ) -> Self {
+        Self {
+            collection,
+            features,
+        }
+    }
+
+    pub async fn create_indexes(&self) -> Result<()> {
+        let geometry = IndexModel::builder()
+            .keys(doc! { "geometry": "2dsphere" })
+            .build();
+        let layer = IndexModel::builder().keys(doc! { "layer_id": 1 }).build();
+        self.features
+            .create_indexes([geometry, layer], None)
+            .await?;
+
+        let name = IndexModel::builder()
+            .keys(doc! { "name": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        self.collection.create_index(name, None).await?;
+
+        Ok(())
+    }
+
+    /// Replaces the layer with the same name, if any.
+    /// Features which are not areas are skipped: no house is inside a line.
+    pub async fn import_layer(&self, layer: LayerDTOInsert) -> Result<LayerDTO> {
+        let name = validate_field_name("layer name", &layer.name)
+            .map_err(|e| LayersServiceError::InvalidLayer(e.0))?
+            .to_owned();
+        let (features, skipped) = features(&layer.geojson, layer.properties.as_deref())?;
+        if features.is_empty() {
+            return Err(LayersServiceError::InvalidLayer(format!(
+                "no polygons in layer {}",
+                name
+            )));
+        }
+
+        let old = self
+            .collection
+            .find_one(doc! { "name": &name }, None)
+            .await?;
+
+        let entity = LayerEntity {
+            _id: ObjectId::new(),
+            name,
+            properties: layer.properties.unwrap_or_default(),
+            features: features.len() as u64,
+        };
+        let features: Vec<_> = features
+            .into_iter()
+            .map(|(properties, geometry)| LayerFeatureEntity {
+                _id: ObjectId::new(),
+                layer_id: entity._id,
+                layer: entity.name.clone(),
+                properties,
+                geometry,
+            })
+            .collect();
+
+        event!(Level::INFO, name = %entity.name, features = features.len(), skipped, "importing layer");
+        // The new features first: if the index refuses one, the old layer is still there
+        if let Err(e) = self.features.insert_many(features, None).await {
+            self.features
+                .delete_many(doc! { "layer_id": entity._id }, None)
+                .await?;
+            return Err(e.into());
+        }
+        // The name is unique: the old document goes just before the new one
+        if let Some(old) = &old {
+            self.collection
+                .delete_one(doc! { "_id": old._id }, None)
+                .await?;
+        }
+        self.collection.insert_one(&entity, None).await?;
+        if let Some(old) = old {
+            self.features
+                .delete_many(doc! { "layer_id": old._id }, None)
+                .await?;
+        }
+
+        Ok(entity.into())
+    }
+
+    pub async fn get_layers(&self) -> Result<Vec<LayerDTO>> {
+        let cur = self.collection.find(doc! {}, None).await?;
+        let layers: Vec<LayerEntity> = cur.try_collect().await?;
+
+        Ok(layers.into_iter().map(LayerDTO::from).collect())
+    }
+
+    pub async fn remove_layer(&self, layer_id: String) -> Result<()> {
+        let id = ObjectId::from_str(&layer_id)?;
+
+        event!(Level::INFO, layer_id = %layer_id, "removing");
+        if self.remove(id).await? == 0 {
+            event!(Level::WARN, layer_id = %layer_id, "Not found");
+            return Err(LayersServiceError::LayerNotFound(layer_id));
+        }
+
+        Ok(())
+    }
+
+    async fn remove(&self, id: ObjectId) -> Result<u64> {
+        self.features
+            .delete_many(doc! { "layer_id": id }, None)
+            .await?;
+        let res = self.collection.delete_one(doc! { "_id": id }, None).await?;
+
+        Ok(res.deleted_count)
+    }
+
+    /// The features containing a house, by layer name
+    pub async fn matches(&self, lat: f64, lng: f64) -> Result<BTreeMap<String, Vec<LayerMatch>>> {
+        let point = mongodb::bson::to_bson(&GeoPoint::new(lat, lng)).unwrap();
+        let cur = self
+            .features
+            .find(
+                doc! { "geometry": { "$geoIntersects": { "$geometry": point } } },
+                None,
+            )
+            .await?;
+        let features: Vec<LayerFeatureEntity> = cur.try_collect().await?;
+
+        let mut matches: BTreeMap<String, Vec<LayerMatch>> = BTreeMap::new();
+        for f in features {
+            matches.entry(f.layer).or_default().push(LayerMatch {
+                feature_id: f._id,
+                properties: f.properties,
+            });
+        }
+        Ok(matches)
+    }
+}
+
+/// The areas of a GeoJSON FeatureCollection with the wanted properties,
+/// and how many features were skipped
+fn features(
+    geojson: &FeatureCollection,
+    wanted: Option<&[String]>,
+) -> Result<(Vec<(Document, GeoArea)>, usize)> {
+    let mut features = vec![];
+    let mut skipped = 0;
+    for feature in &geojson.features {
+        let area = match feature
+            .geometry
+            .clone()
+            .and_then(|g| serde_json::from_value::<GeoArea>(g).ok())
+        {
+            None => {
+                skipped += 1;
+                continue;
+            }
+            Some(area) => area
+                .validate()
+                .map_err(|e| LayersServiceError::InvalidLayer(e.0))?,
+        };
+
+        let properties = feature
+            .properties
+            .iter()
+            .flatten()
+            .filter(|(k, _)| wanted.is_none_or(|w| w.contains(k)))
+            .map(|(k, v)| (k.clone(), mongodb::bson::to_bson(v).unwrap()))
+            .collect();
+        features.push((properties, area));
+    }
+    Ok((features, skipped))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LayerEntity {
+    _id: ObjectId,
+    name: String,
+    /// The feature properties kept, empty for all
+    properties: Vec<String>,
+    features: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LayerFeatureEntity {
+    _id: ObjectId,
+    layer_id: ObjectId,
+    layer: String,
+    properties: Document,
+    geometry: GeoArea,
+}
+
+#[derive(Deserialize)]
+pub struct FeatureCollection {
+    features: Vec<Feature>,
+}
+
+#[derive(Deserialize)]
+struct Feature {
+    geometry: Option<serde_json::Value>,
+    properties: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+#[derive(Deserialize)]
+pub struct LayerDTOInsert {
+    /// Letters, digits, '_' and '-': it is the key in the houses' `layers`
+    name: String,
+    /// The feature properties to keep, all of them when missing
+    properties: Option<Vec<String>>,
+    geojson: FeatureCollection,
+}
+
+#[derive(Serialize)]
+pub struct LayerDTO {
+    id: String,
+    name: String,
+    properties: Vec<String>,
+    features: u64,
+}
+
+impl From<LayerEntity> for LayerDTO {
+    fn from(e: LayerEntity) -> Self {
+        Self {
+            id: e._id.to_hex(),
+            name: e.name,
+            properties: e.properties,
+            features: e.features,
+        }
+    }
+}
+
+/// A feature of a layer containing the house
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LayerMatch {
+    feature_id: ObjectId,
+    properties: Document,
+}
+
+#[derive(Serialize)]
+pub struct LayerMatchDTO {
+    feature_id: String,
+    properties: Document,
+}
+
+impl From<LayerMatch> for LayerMatchDTO {
+    fn from(m: LayerMatch) -> Self {
+        Self {
+            feature_id: m.feature_id.to_hex(),
+            properties: m.properties,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_features() {
+        let geojson: FeatureCollection = serde_json::from_value(serde_json::json!({
+            "type": "FeatureCollection",
+            "features": [
+                {
+                    "type": "Feature",
+                    "geometry": {
+                        "type": "Polygon",
+                        "coordinates": [[[9.18, 45.46], [9.20, 45.46], [9.20, 45.47]]]
+                    },
+                    "properties": { "level": 65, "source": "road", "id": 12 }
+                },
+                {
+                    "type": "Feature",
+                    "geometry": { "type": "LineString", "coordinates": [[9.18, 45.46], [9.20, 45.46]] },
+                    "properties": { "level": 70 }
+                }
+            ]
+        }))
+        .unwrap();
+
+        let wanted = ["level".to_string(), "source".to_string()];
+        let (kept, skipped) = features(&geojson, Some(&wanted)).unwrap();
+        assert_eq!(skipped, 1);
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].0, doc! { "level": 65_i64, "source": "road" });
+
+        let (kept, _) = features(&geojson, None).unwrap();
+        assert_eq!(kept[0].0.len(), 3);
+    }
+}
@@ -1,19 +1,67 @@
+// The chain of routes is long
+#![recursion_limit = "256"]
+
+mod agency_service;
+mod amenity_tagger;
+mod area_service;
+mod candidate_service;
+mod cluster;
+mod commute_service;
+mod comparison;
 mod config;
 mod discovery_service;
+mod elo;
+mod fair_price;
+mod geo;
+mod gtfs;
+mod heatmap;
+mod highlight;
+mod house_query;
 mod house_service;
 mod http_handlers;
+mod ingestion_service;
+mod layer_service;
+mod media_service;
+mod nearby_service;
+mod neighborhoods;
+mod osm;
+mod poi_service;
+mod preference;
+mod price_snapshot_service;
+mod regression;
+mod risk;
+mod scoring_service;
+mod similarity;
+mod statistics_service;
+mod transit_stops;
 
 use tracing_subscriber::fmt::format::FmtSpan;
 
+use agency_service::AgenciesService;
+use amenity_tagger::AmenityTagger;
+use area_service::AreasService;
+use candidate_service::CandidatesService;
+use commute_service::CommuteService;
 use config::{Config, MongoConfig};
+use gtfs::Router;
 use house_service::HousesService;
+use ingestion_service::IngestionService;
+use layer_service::LayersService;
+use media_service::MediaService;
 use mongodb::{
     options::{ClientOptions, ResolverConfig},
     Client,
 };
+use nearby_service::NearbyService;
+use neighborhoods::Neighborhoods;
+use poi_service::PoisService;
+use price_snapshot_service::PriceSnapshotsService;
+use scoring_service::ScoringService;
+use statistics_service::StatisticsService;
+use transit_stops::TransitStops;
 use warp::Filter;
 
-use crate::discovery_service::DiscoveryService;
+use crate::discovery_service::{load_portals, DiscoveryService};
 
 #[macro_use]
 extern crate log;
@@ -34,11 +82,109 @@ async fn main() {
     let db = connect_to_mongo(&config.mongodb).await.unwrap();
 
     let collection = db.collection(&config.mongodb.house_collection);
-    let houses_service = HousesService::new(collection);
-    let houses_service = warp::any().map(move || houses_service.clone());
+    let statistics_service =
+        StatisticsService::new(db.collection(&config.mongodb.house_collection));
+    let portals = load_portals(config.discovery_portals_file.as_deref());
+    let discovery_service = DiscoveryService::new(portals);
+    let media_service = MediaService::new(discovery_service.cookies(), &config.media_directory);
+    let amenity_tagger = AmenityTagger::load(config.amenities_dictionary_file.as_deref());
+    let neighborhoods = Neighborhoods::load(config.neighborhoods_file.as_deref());
+    let transit_stops = TransitStops::load(config.transit_stops_file.as_deref());
+    let commute_service = CommuteService::new(
+        db.collection(&config.mongodb.destination_collection),
+        Router::load(config.gtfs_file.as_deref()),
+    );
+    let pois_service = PoisService::new(db.collection(&config.mongodb.poi_collection));
+    let areas_service = AreasService::new(db.collection(&config.mongodb.area_collection));
+    let scoring_service =
+        ScoringService::new(db.collection(&config.mongodb.scoring_profile_collection));
+    let layers_service = LayersService::new(
+        db.collection(&config.mongodb.layer_collection),
+        db.collection(&config.mongodb.layer_feature_collection),
+    );
+    let nearby_service = NearbyService::new(config.osm_file.clone(), config.nearby_radii());
+    let agencies_service = AgenciesService::new(
+        db.collection(&config.mongodb.agency_collection),
+        config.mongodb.house_collection.clone(),
+    );
+    let price_snapshots_service =
+        PriceSnapshotsService::new(db.collection(&config.mongodb.price_snapshot_collection));
+    price_snapshots_service.create_indexes().await.unwrap();
+    let houses_service = HousesService::new(
+        collection,
+        media_service,
+        amenity_tagger,
+        agencies_service.clone(),
+        transit_stops.clone(),
+        commute_service.clone(),
+        pois_service.clone(),
+        nearby_service.clone(),
+        neighborhoods.clone(),
+        layers_service.clone(),
+        areas_service.clone(),
+        scoring_service.clone(),
+        price_snapshots_service.clone(),
+    );
+    layers_service.create_indexes().await.unwrap();
+    agencies_service.create_indexes().await.unwrap();
+    houses_service.create_indexes().await.unwrap();
+    houses_service.migrate().await.unwrap();
+    houses_service.refresh_neighborhoods().await.unwrap();
+    houses_service.refresh_transit().await.unwrap();
+    houses_service.refresh_fair_prices().await.unwrap();
+    houses_service.refresh_predicted_votes().await.unwrap();
+    houses_service.refresh_risk_flags().await.unwrap();
+    // The timetable may have changed, but routing every house takes a while
+    let refreshing = houses_service.clone();
+    tokio::spawn(async move {
+        if let Err(e) = refreshing.refresh_commutes().await {
+            error!("refreshing the commutes: {:?}", e);
+        } else if let Err(e) = refreshing.refresh_scores().await {
+            error!("refreshing the scores: {:?}", e);
+        }
+    });
+    // Same for the amenities, reading the extract is slow too
+    if config.osm_file.is_some() {
+        let importing = nearby_service.clone();
+        let refreshing = houses_service.clone();
+        tokio::spawn(async move {
+            if let Err(e) = importing.import().await {
+                error!("importing the OSM extract: {}", e);
+            } else if let Err(e) = refreshing.refresh_nearby().await {
+                error!("refreshing the nearby amenities: {:?}", e);
+            }
+        });
+    }
 
-    let discovery_service = DiscoveryService::new();
+    let candidates_service = CandidatesService::new(
+        db.collection(&config.mongodb.candidate_collection),
+        price_snapshots_service.clone(),
+    );
+    candidates_service.create_indexes().await.unwrap();
+    candidates_service.migrate().await.unwrap();
+    let ingestion_service = IngestionService::new(
+        discovery_service.clone(),
+        houses_service.clone(),
+        candidates_service.clone(),
+        config.ingestion_feeds(),
+        config.ingestion_mail_path.clone().map(Into::into),
+    );
+
+    let houses_service = warp::any().map(move || houses_service.clone());
+    let neighborhoods = warp::any().map(move || neighborhoods.clone());
+    let transit_stops = warp::any().map(move || transit_stops.clone());
+    let commute_service = warp::any().map(move || commute_service.clone());
+    let pois_service = warp::any().map(move || pois_service.clone());
+    let statistics_service = warp::any().map(move || statistics_service.clone());
+    let areas_service = warp::any().map(move || areas_service.clone());
+    let scoring_service = warp::any().map(move || scoring_service.clone());
+    let layers_service = warp::any().map(move || layers_service.clone());
+    let nearby_service = warp::any().map(move || nearby_service.clone());
+    let agencies_service = warp::any().map(move || agencies_service.clone());
     let discovery_service = warp::any().map(move || discovery_service.clone());
+    let candidates_service = warp::any().map(move || candidates_service.clone());
+    let ingestion_service = warp::any().map(move || ingestion_service.clone());
+    let price_snapshots_service = warp::any().map(move || price_snapshots_service.clone());
 
     let insert_house = warp::path!("api" / "houses")
         .and(warp::post())
@@ -54,8 +200,60 @@ async fn main() {
     let get_houses = warp::path!("api" / "houses")
         .and(warp::get())
         .and(houses_service.clone())
+        .and(warp::query::<house_query::HousesQuery>())
         .and_then(http_handlers::get_houses);
 
+    let get_houses_near = warp::path!("api" / "houses" / "near")
+        .and(warp::get())
+        .and(houses_service.clone())
+        .and(warp::query::<house_query::NearQuery>())
+        .and_then(http_handlers::get_houses_near);
+
+    let get_houses_within = warp::path!("api" / "houses" / "within")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(houses_service.clone())
+        .and_then(http_handlers::get_houses_within);
+
+    let get_houses_in_bbox = warp::path!("api" / "houses" / "bbox")
+        .and(warp::get())
+        .and(houses_service.clone())
+        .and(warp::query::<house_query::BoundingBoxQuery>())
+        .and_then(http_handlers::get_houses_in_bbox);
+
+    let get_clusters = warp::path!("api" / "houses" / "clusters")
+        .and(warp::get())
+        .and(houses_service.clone())
+        .and(candidates_service.clone())
+        .and(warp::query::<house_query::ClusterQuery>())
+        .and_then(http_handlers::get_clusters);
+
+    let get_heatmap = warp::path!("api" / "houses" / "heatmap")
+        .and(warp::get())
+        .and(houses_service.clone())
+        .and(price_snapshots_service.clone())
+        .and(warp::query::<house_query::HeatmapQuery>())
+        .and_then(http_handlers::get_heatmap);
+
+    let reprice_house = warp::path!("api" / "houses" / String / "reprice")
+        .and(warp::post())
+        .and(houses_service.clone())
+        .and(discovery_service.clone())
+        .and_then(http_handlers::reprice_house);
+
+    let get_similar_houses = warp::path!("api" / "houses" / String / "similar")
+        .and(warp::get())
+        .and(houses_service.clone())
+        .and(candidates_service.clone())
+        .and(warp::query::<house_query::SimilarQuery>())
+        .and_then(http_handlers::get_similar_houses);
+
+    let compare_houses = warp::path!("api" / "houses" / "compare")
+        .and(warp::get())
+        .and(houses_service.clone())
+        .and(warp::query::<house_query::CompareQuery>())
+        .and_then(http_handlers::compare_houses);
+
     let get_house_by_id = warp::path!("api" / "houses" / String)
         .and(warp::get())
         .and(houses_service.clone())
@@ -67,20 +265,221 @@ async fn main() {
         .and(houses_service.clone())
         .and_then(http_handlers::update_house_by_id);
 
+    let get_statistics = warp::path!("api" / "statistics")
+        .and(warp::get())
+        .and(statistics_service.clone())
+        .and(warp::query::<statistics_service::StatisticsQuery>())
+        .and_then(http_handlers::get_statistics);
+
+    let get_stops = warp::path!("api" / "stops")
+        .and(warp::get())
+        .and(transit_stops.clone())
+        .and_then(http_handlers::get_stops);
+
+    let refresh_stops = warp::path!("api" / "stops" / "refresh")
+        .and(warp::post())
+        .and(transit_stops.clone())
+        .and(houses_service.clone())
+        .and_then(http_handlers::refresh_stops);
+
+    let get_neighborhoods = warp::path!("api" / "neighborhoods")
+        .and(warp::get())
+        .and(neighborhoods.clone())
+        .and_then(http_handlers::get_neighborhoods);
+
+    let get_destinations = warp::path!("api" / "destinations")
+        .and(warp::get())
+        .and(commute_service.clone())
+        .and_then(http_handlers::get_destinations);
+
+    let insert_destination = warp::path!("api" / "destinations")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(commute_service.clone())
+        .and(houses_service.clone())
+        .and_then(http_handlers::insert_destination);
+
+    let remove_destination = warp::path!("api" / "destinations" / String)
+        .and(warp::delete())
+        .and(commute_service.clone())
+        .and(houses_service.clone())
+        .and_then(http_handlers::remove_destination);
+
+    let get_pois = warp::path!("api" / "pois")
+        .and(warp::get())
+        .and(pois_service.clone())
+        .and(warp::query::<poi_service::PoisQuery>())
+        .and_then(http_handlers::get_pois);
+
+    let insert_poi = warp::path!("api" / "pois")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(pois_service.clone())
+        .and(houses_service.clone())
+        .and_then(http_handlers::insert_poi);
+
+    let remove_poi = warp::path!("api" / "pois" / String)
+        .and(warp::delete())
+        .and(pois_service.clone())
+        .and(houses_service.clone())
+        .and_then(http_handlers::remove_poi);
+
+    let get_areas = warp::path!("api" / "areas")
+        .and(warp::get())
+        .and(areas_service.clone())
+        .and(warp::query::<area_service::AreasQuery>())
+        .and_then(http_handlers::get_areas);
+
+    let insert_area = warp::path!("api" / "areas")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(areas_service.clone())
+        .and(houses_service.clone())
+        .and_then(http_handlers::insert_area);
+
+    let remove_area = warp::path!("api" / "areas" / String)
+        .and(warp::delete())
+        .and(areas_service.clone())
+        .and(houses_service.clone())
+        .and_then(http_handlers::remove_area);
+
+    let get_comparison_pair = warp::path!("api" / "comparisons" / "pair")
+        .and(warp::get())
+        .and(houses_service.clone())
+        .and(warp::query::<http_handlers::ComparisonPairQuery>())
+        .and_then(http_handlers::get_comparison_pair);
+
+    let compare = warp::path!("api" / "comparisons")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(houses_service.clone())
+        .and_then(http_handlers::compare);
+
+    let get_profiles = warp::path!("api" / "profiles")
+        .and(warp::get())
+        .and(scoring_service.clone())
+        .and_then(http_handlers::get_profiles);
+
+    let insert_profile = warp::path!("api" / "profiles")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(scoring_service.clone())
+        .and(houses_service.clone())
+        .and_then(http_handlers::insert_profile);
+
+    let remove_profile = warp::path!("api" / "profiles" / String)
+        .and(warp::delete())
+        .and(scoring_service.clone())
+        .and(houses_service.clone())
+        .and_then(http_handlers::remove_profile);
+
+    let get_layers = warp::path!("api" / "layers")
+        .and(warp::get())
+        .and(layers_service.clone())
+        .and_then(http_handlers::get_layers);
+
+    let import_layer = warp::path!("api" / "layers")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(layers_service.clone())
+        .and(houses_service.clone())
+        .and_then(http_handlers::import_layer);
+
+    let remove_layer = warp::path!("api" / "layers" / String)
+        .and(warp::delete())
+        .and(layers_service.clone())
+        .and(houses_service.clone())
+        .and_then(http_handlers::remove_layer);
+
+    let import_osm = warp::path!("api" / "nearby" / "import")
+        .and(warp::post())
+        .and(nearby_service.clone())
+        .and(houses_service.clone())
+        .and_then(http_handlers::import_osm);
+
+    let get_agencies = warp::path!("api" / "agencies")
+        .and(warp::get())
+        .and(agencies_service.clone())
+        .and_then(http_handlers::get_agencies);
+
+    let update_agency_by_id = warp::path!("api" / "agencies" / String)
+        .and(warp::patch())
+        .and(warp::body::json())
+        .and(agencies_service.clone())
+        .and_then(http_handlers::update_agency_by_id);
+
+    let ingest = warp::path!("api" / "ingestion")
+        .and(warp::post())
+        .and(ingestion_service.clone())
+        .and_then(http_handlers::ingest);
+
+    let get_candidates = warp::path!("api" / "candidates")
+        .and(warp::get())
+        .and(candidates_service.clone())
+        .and_then(http_handlers::get_candidates);
+
+    let remove_candidate = warp::path!("api" / "candidates" / String)
+        .and(warp::delete())
+        .and(candidates_service.clone())
+        .and_then(http_handlers::remove_candidate);
+
     let discover = warp::path!("api" / "discover")
         .and(warp::get())
         .and(discovery_service.clone())
+        .and(houses_service.clone())
+        .and(areas_service.clone())
         .and(warp::query::<http_handlers::DiscoverQueryParameter>())
         .and_then(http_handlers::discover);
 
+    let media_files = warp::path("media")
+        .and(warp::get())
+        .and(warp::fs::dir(config.media_directory));
+
     let static_files = warp::get().and(warp::fs::dir(config.static_directory));
 
     let router = insert_house
         .or(get_houses)
+        // Before get_house_by_id, which would take them as ids
+        .or(get_houses_near)
+        .or(get_houses_within)
+        .or(get_houses_in_bbox)
+        .or(get_clusters)
+        .or(get_heatmap)
+        .or(compare_houses)
         .or(get_house_by_id)
+        .or(get_similar_houses)
+        .or(reprice_house)
         .or(update_house_by_id)
         .or(remove_house)
+        .or(get_statistics)
+        .or(get_stops)
+        .or(refresh_stops)
+        .or(get_neighborhoods)
+        .or(get_destinations)
+        .or(insert_destination)
+        .or(remove_destination)
+        .or(get_pois)
+        .or(insert_poi)
+        .or(remove_poi)
+        .or(get_areas)
+        .or(insert_area)
+        .or(remove_area)
+        .or(get_comparison_pair)
+        .or(compare)
+        .or(get_profiles)
+        .or(insert_profile)
+        .or(remove_profile)
+        .or(get_layers)
+        .or(import_layer)
+        .or(remove_layer)
+        .or(import_osm)
+        .or(get_agencies)
+        .or(update_agency_by_id)
+        .or(ingest)
+        .or(get_candidates)
+        .or(remove_candidate)
         .or(discover)
+        .or(media_files)
         .or(static_files)
         .with(warp::trace::request())
         .recover(http_handlers::handle_rejection);
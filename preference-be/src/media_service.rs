@@ -0,0 +1,533 @@
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use futures::StreamExt;
+use mongodb::bson::oid::ObjectId;
+use reqwest::{
+    cookie::Jar,
+    dns::{Addrs, Resolve, Resolving},
+    redirect, Client, ClientBuilder, Url,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+use warp::hyper::client::connect::dns::Name;
+
+use crate::discovery_service::USER_AGENT;
+
+const THUMBNAIL_SIZE: u32 = 320;
+/// Way more than any listing photo
+const MAX_MEDIA_BYTES: usize = 20 * 1024 * 1024;
+const CONCURRENT_DOWNLOADS: usize = 4;
+const MAX_REDIRECTS: usize = 10;
+
+/// The URLs come from the clients: only public hosts are downloaded from,
+/// never the network the server lives in
+#[derive(Clone)]
+pub struct MediaService {
+    client: Client,
+    directory: PathBuf,
+    max_bytes: usize,
+    /// Only for the tests against a local server: the names are always
+    /// resolved to public addresses, see `PublicResolver`
+    allow_private_ips: bool,
+}
+
+#[derive(Debug)]
+pub enum MediaError {
+    ReqwestError(reqwest::Error),
+    NotFound(String),
+    Image(image::ImageError),
+    Io(std::io::Error),
+    Join(tokio::task::JoinError),
+    /// Not http(s), or to a private address
+    Forbidden(String),
+    TooLarge(String),
+}
+
+impl fmt::Display for MediaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::ReqwestError(e) => write!(f, "request failed: {}", e),
+            Self::NotFound(url) => write!(f, "media not found: {}", url),
+            Self::Image(e) => write!(f, "invalid image: {}", e),
+            Self::Io(e) => write!(f, "io: {}", e),
+            Self::Join(e) => write!(f, "thumbnail task failed: {}", e),
+            Self::Forbidden(url) => write!(f, "forbidden url: {}", url),
+            Self::TooLarge(url) => write!(f, "media too large: {}", url),
+        }
+    }
+}
+
+impl From<reqwest::Error> for MediaError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::ReqwestError(e)
+    }
+}
+impl From<image::ImageError> for MediaError {
+    fn from(e: image::ImageError) -> Self {
+        Self::Image(e)
+    }
+}
+impl From<std::io::Error> for MediaError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+impl From<tokio::task::JoinError> for MediaError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        Self::Join(e)
+    }
+}
+
+impl MediaService {
+    /// The cookies are the discovery ones: the portal CDNs see the same browser
+    /// as the listing pages
+    pub fn new(cookies: Arc<Jar>, directory: impl Into<PathBuf>) -> Self {
+        // Every connection, redirects included, goes to an address checked
+        // by the resolver or by the redirect policy
+        let client = ClientBuilder::new()
+            .cookie_provider(cookies)
+            .user_agent(USER_AGENT)
+            .redirect(redirect_policy())
+            .dns_resolver(Arc::new(PublicResolver))
+            .build()
+            .unwrap();
+
+        Self {
+            client,
+            directory: directory.into(),
+            max_bytes: MAX_MEDIA_BYTES,
+            allow_private_ips: false,
+        }
+    }
+
+    /// Downloads all the given images, skipping the ones which cannot be stored:
+    /// the pictures are a nice to have, they should never block an insertion
+    pub async fn store_all(&self, urls: &[String]) -> Vec<MediaEntity> {
+        let downloads: Vec<_> = urls.iter().map(|url| self.try_store(url)).collect();
+        futures::stream::iter(downloads)
+            .buffered(CONCURRENT_DOWNLOADS)
+            .filter_map(futures::future::ready)
+            .collect()
+            .await
+    }
+
+    async fn try_store(&self, url: &str) -> Option<MediaEntity> {
+        self.store(url)
+            .await
+            .map_err(|e| event!(Level::WARN, url = %url, error = %e, "media not stored"))
+            .ok()
+    }
+
+    pub async fn store(&self, url: &str) -> Result<MediaEntity, MediaError> {
+        event!(Level::INFO, url = %url, "downloading media");
+
+        let parsed = Url::parse(url).map_err(|_| MediaError::Forbidden(url.to_owned()))?;
+        if !self.allow_private_ips && forbidden_host(&parsed) {
+            return Err(MediaError::Forbidden(url.to_owned()));
+        }
+
+        let mut response = self.client.get(parsed).send().await.map_err(forbidden_or)?;
+        if !response.status().is_success() {
+            return Err(MediaError::NotFound(url.to_owned()));
+        }
+
+        let too_large = || MediaError::TooLarge(url.to_owned());
+        if response
+            .content_length()
+            .is_some_and(|length| length > self.max_bytes as u64)
+        {
+            return Err(too_large());
+        }
+        // The length can be missing, or lie
+        let mut bytes = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(forbidden_or)? {
+            if bytes.len() + chunk.len() > self.max_bytes {
+                return Err(too_large());
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+
+        let id = ObjectId::new().to_hex();
+        let file_name = format!("{}.{}", id, extension(url));
+        let thumbnail_name = format!("{}.thumb.jpg", id);
+
+        let directory = self.directory.clone();
+        let (file, thumbnail) = (file_name.clone(), thumbnail_name.clone());
+        let hash = tokio::task::spawn_blocking(move || {
+            write_with_thumbnail(&directory, &file, &thumbnail, &bytes)
+        })
+        .await??;
+
+        Ok(MediaEntity {
+            source_url: url.to_owned(),
+            file_name,
+            thumbnail_name,
+            hash: Some(format!("{:016x}", hash)),
+        })
+    }
+}
+
+/// Not http(s), or to an address which is not public.
+/// The names are checked when resolved, by `PublicResolver`.
+fn forbidden_host(url: &Url) -> bool {
+    if !matches!(url.scheme(), "http" | "https") {
+        return true;
+    }
+    match url.host_str() {
+        None => true,
+        Some(host) => ip_address(host).is_some_and(|ip| !is_public(ip)),
+    }
+}
+
+/// Stops the redirects to the forbidden hosts, see `forbidden_host`
+fn redirect_policy() -> redirect::Policy {
+    redirect::Policy::custom(|attempt| {
+        if forbidden_host(attempt.url()) {
+            let host = ForbiddenHost(attempt.url().to_string());
+            attempt.error(host)
+        } else if attempt.previous().len() >= MAX_REDIRECTS {
+            attempt.error("too many redirects")
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+/// Resolves the names as the system does, keeping the public addresses only:
+/// the connection goes to the addresses checked, a DNS rebinding cannot
+/// sneak a private one in
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            // The connector sets the port
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|a| is_public(a.ip()))
+                .collect();
+            if addresses.is_empty() {
+                return Err(ForbiddenHost(name.as_str().to_owned()).into());
+            }
+            let addresses: Addrs = Box::new(addresses.into_iter());
+            Ok(addresses)
+        })
+    }
+}
+
+/// Raised inside reqwest by the resolver and the redirect policy
+#[derive(Debug)]
+struct ForbiddenHost(String);
+
+impl fmt::Display for ForbiddenHost {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "forbidden host: {}", self.0)
+    }
+}
+
+impl std::error::Error for ForbiddenHost {}
+
+/// Forbidden when the resolver or the redirect policy refused the host
+fn forbidden_or(e: reqwest::Error) -> MediaError {
+    let mut source = std::error::Error::source(&e);
+    while let Some(cause) = source {
+        if let Some(ForbiddenHost(host)) = cause.downcast_ref() {
+            return MediaError::Forbidden(host.clone());
+        }
+        source = cause.source();
+    }
+    e.into()
+}
+
+/// None for a name: IPv6 hosts are in brackets in the URLs
+fn ip_address(host: &str) -> Option<IpAddr> {
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .ok()
+}
+
+/// Not loopback, private, link-local, shared, multicast or unspecified
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && (64..128).contains(&b))
+                || a == 0)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(ip.into()),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local, fc00::/7
+                    || (first & 0xfe00) == 0xfc00
+                    // Link-local, fe80::/10
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Returns the `photo_hash` of the image
+fn write_with_thumbnail(
+    directory: &Path,
+    file_name: &str,
+    thumbnail_name: &str,
+    bytes: &[u8],
+) -> Result<u64, MediaError> {
+    // Decode before writing anything: a broken image is not worth keeping
+    let image = image::load_from_memory(bytes)?;
+
+    std::fs::create_dir_all(directory)?;
+    std::fs::write(directory.join(file_name), bytes)?;
+
+    image
+        .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        .into_rgb8()
+        .save_with_format(directory.join(thumbnail_name), image::ImageFormat::Jpeg)?;
+
+    Ok(photo_hash(&image))
+}
+
+/// Difference hash: whether each pixel of a tiny grey copy is brighter than the next.
+/// The same picture resized or recompressed differs by a few bits at most.
+pub fn photo_hash(image: &image::DynamicImage) -> u64 {
+    let tiny = image
+        .resize_exact(9, 8, image::imageops::FilterType::Triangle)
+        .into_luma8();
+
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = tiny.get_pixel(x, y)[0] > tiny.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | brighter as u64;
+        }
+    }
+    hash
+}
+
+fn extension(url: &str) -> &str {
+    let path = url.split(&['?', '#'][..]).next().unwrap_or(url);
+    match path.rsplit_once('.') {
+        Some((_, ext)) if matches!(ext, "jpg" | "jpeg" | "png" | "webp") => ext,
+        _ => "jpg",
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MediaEntity {
+    pub source_url: String,
+    pub file_name: String,
+    pub thumbnail_name: String,
+    /// `photo_hash` in hex, missing for the media stored before
+    #[serde(default)]
+    pub hash: Option<String>,
+}
+
+impl MediaEntity {
+    pub fn photo_hash(&self) -> Option<u64> {
+        u64::from_str_radix(self.hash.as_deref()?, 16).ok()
+    }
+}
+
+#[derive(Serialize)]
+pub struct MediaDTO {
+    pub url: String,
+    pub thumbnail_url: String,
+    pub source_url: String,
+}
+
+impl From<MediaEntity> for MediaDTO {
+    fn from(e: MediaEntity) -> Self {
+        Self {
+            url: format!("/media/{}", e.file_name),
+            thumbnail_url: format!("/media/{}", e.thumbnail_name),
+            source_url: e.source_url,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_store() {
+        use httpmock::prelude::*;
+        let server = MockServer::start();
+
+        let mut png = Vec::new();
+        image::DynamicImage::new_rgb8(800, 600)
+            .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+
+        let server_mock = server.mock(|when, then| {
+            when.method(GET).path("/photo.png");
+            then.status(200).body(png);
+        });
+        let missing_mock = server.mock(|when, then| {
+            when.method(GET).path("/missing.jpg");
+            then.status(404);
+        });
+
+        let directory = std::env::temp_dir().join(format!("media-test-{}", ObjectId::new()));
+        let service = MediaService {
+            allow_private_ips: true,
+            ..MediaService::new(Arc::default(), &directory)
+        };
+
+        let stored = service
+            .store_all(&[server.url("/photo.png"), server.url("/missing.jpg")])
+            .await;
+
+        server_mock.assert();
+        missing_mock.assert();
+        assert_eq!(stored.len(), 1);
+        assert!(stored[0].file_name.ends_with(".png"));
+
+        let thumbnail = image::open(directory.join(&stored[0].thumbnail_name)).unwrap();
+        assert_eq!(thumbnail.width(), THUMBNAIL_SIZE);
+        assert!(directory.join(&stored[0].file_name).exists());
+        assert_eq!(stored[0].photo_hash(), Some(0));
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_store_private_host() {
+        use httpmock::prelude::*;
+        let server = MockServer::start();
+
+        let server_mock = server.mock(|when, then| {
+            when.method(GET);
+            then.status(200).body("secret");
+        });
+
+        let service = MediaService::new(Arc::default(), std::env::temp_dir());
+        for url in [
+            server.url("/photo.png"),
+            format!("http://localhost:{}/photo.png", server.port()),
+            "file:///etc/passwd".to_string(),
+            "http://169.254.169.254/latest/meta-data/".to_string(),
+            "http://[::1]/photo.png".to_string(),
+        ] {
+            assert!(
+                matches!(service.store(&url).await, Err(MediaError::Forbidden(_))),
+                "{}",
+                url
+            );
+        }
+        server_mock.assert_hits(0);
+    }
+
+    #[tokio::test]
+    async fn test_store_redirect_to_private_host() {
+        use httpmock::prelude::*;
+        let server = MockServer::start();
+
+        let redirect_mock = server.mock(|when, then| {
+            when.method(GET).path("/photo.png");
+            then.status(302).header(
+                "location",
+                format!("http://localhost:{}/secret.png", server.port()),
+            );
+        });
+        let secret_mock = server.mock(|when, then| {
+            when.method(GET).path("/secret.png");
+            then.status(200).body("secret");
+        });
+
+        // The first hop only: the redirect is to a name
+        let service = MediaService {
+            allow_private_ips: true,
+            ..MediaService::new(Arc::default(), std::env::temp_dir())
+        };
+        let stored = service.store(&server.url("/photo.png")).await;
+
+        redirect_mock.assert();
+        secret_mock.assert_hits(0);
+        assert!(matches!(stored, Err(MediaError::Forbidden(_))));
+    }
+
+    #[tokio::test]
+    async fn test_store_too_large() {
+        use httpmock::prelude::*;
+        let server = MockServer::start();
+
+        let server_mock = server.mock(|when, then| {
+            when.method(GET).path("/huge.jpg");
+            then.status(200).body(vec![0; 2048]);
+        });
+
+        let service = MediaService {
+            max_bytes: 1024,
+            allow_private_ips: true,
+            ..MediaService::new(Arc::default(), std::env::temp_dir())
+        };
+        let stored = service.store(&server.url("/huge.jpg")).await;
+
+        server_mock.assert();
+        assert!(matches!(stored, Err(MediaError::TooLarge(_))));
+    }
+
+    #[test]
+    fn test_is_public() {
+        for ip in ["93.184.216.34", "2606:2800:220:1::1"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_photo_hash() {
+        let waves = image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(640, 480, |x, y| {
+            let wave = ((x as f64 / 50.0).sin() + (y as f64 / 90.0).cos()) * 60.0 + 128.0;
+            image::Rgb([wave as u8, wave as u8, 128])
+        }));
+        let hash = photo_hash(&waves);
+        assert_ne!(hash, 0);
+
+        // Smaller and recompressed
+        let mut jpeg = Vec::new();
+        waves
+            .thumbnail(320, 240)
+            .write_to(&mut Cursor::new(&mut jpeg), image::ImageFormat::Jpeg)
+            .unwrap();
+        let copy = image::load_from_memory(&jpeg).unwrap();
+        assert!((photo_hash(&copy) ^ hash).count_ones() <= 4);
+
+        assert!((photo_hash(&waves.fliph()) ^ hash).count_ones() > 4);
+    }
+}
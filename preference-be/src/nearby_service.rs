@@ -0,0 +1,115 @@
+use std::{
+    fmt,
+    sync::{Arc, RwLock},
+};
+
+use tracing::{event, Level};
+
+use crate::osm::{Amenities, AmenityCounts, OsmError};
+
+/// Supermarkets, parks, pharmacies and schools around the houses,
+/// from a local OpenStreetMap extract
+#[derive(Clone)]
+pub struct NearbyService {
+    file: Option<String>,
+    radii: Vec<u32>,
+    amenities: Arc<RwLock<Amenities>>,
+}
+
+type Result<T> = std::result::Result<T, NearbyServiceError>;
+
+#[derive(Debug)]
+pub enum NearbyServiceError {
+    NoExtract,
+    OsmError(OsmError),
+    ImportError(tokio::task::JoinError),
+}
+
+impl fmt::Display for NearbyServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NoExtract => write!(f, "no OSM extract configured"),
+            Self::OsmError(e) => e.fmt(f),
+            Self::ImportError(e) => write!(f, "import failed: {}", e),
+        }
+    }
+}
+
+impl From<OsmError> for NearbyServiceError {
+    fn from(e: OsmError) -> Self {
+        Self::OsmError(e)
+    }
+}
+impl From<tokio::task::JoinError> for NearbyServiceError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        Self::ImportError(e)
+    }
+}
+
+impl NearbyService {
+    /// Nothing is imported yet: a country extract takes a while,
+    /// call `import` out of the way
+    pub fn new(file: Option<String>, radii: Vec<u32>) -> Self {
+        Self {
+            file,
+            radii,
+            amenities: Arc::default(),
+        }
+    }
+
+    /// (Re)reads the extract, returns how many amenities it has
+    pub async fn import(&self) -> Result<usize> {
+        let file = self.file.clone().ok_or(NearbyServiceError::NoExtract)?;
+
+        event!(Level::INFO, file = %file, "importing OSM extract");
+        let amenities = tokio::task::spawn_blocking(move || Amenities::load(&file)).await??;
+        let count = amenities.count();
+        *self.amenities.write().unwrap() = amenities;
+
+        Ok(count)
+    }
+
+    /// One count per configured radius, empty before the import
+    pub fn counts(&self, lat: f64, lng: f64) -> Vec<AmenityCounts> {
+        let amenities = self.amenities.read().unwrap();
+        if amenities.count() == 0 {
+            return vec![];
+        }
+
+        self.radii
+            .iter()
+            .map(|&radius| amenities.counts(lat, lng, radius))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::osm::{Amenity, Category};
+
+    use super::*;
+
+    #[test]
+    fn test_counts() {
+        let service = NearbyService::new(None, vec![300, 1000]);
+        assert_eq!(service.counts(45.4641, 9.1919), vec![]);
+
+        *service.amenities.write().unwrap() = Amenities::new(vec![
+            Amenity {
+                category: Category::Pharmacy,
+                lat: 45.4650,
+                lng: 9.1919,
+            },
+            Amenity {
+                category: Category::Pharmacy,
+                lat: 45.4700,
+                lng: 9.1919,
+            },
+        ]);
+
+        let counts = service.counts(45.4641, 9.1919);
+        assert_eq!(counts.len(), 2);
+        assert_eq!((counts[0].radius, counts[0].pharmacies), (300, 1));
+        assert_eq!((counts[1].radius, counts[1].pharmacies), (1000, 2));
+    }
+}
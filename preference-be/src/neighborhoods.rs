@@ -0,0 +1,154 @@
+use std::sync::Arc;
+
+use serde::Deserialize;
+use tracing::{event, Level};
+
+use crate::geo::{GeoArea, GeoPolygon};
+
+/// An official neighborhood, as drawn by the city
+#[derive(Debug, Clone, PartialEq)]
+pub struct Neighborhood {
+    pub name: String,
+    polygons: Vec<GeoPolygon>,
+}
+
+impl Neighborhood {
+    pub fn new(name: String, polygons: Vec<GeoPolygon>) -> Self {
+        Self { name, polygons }
+    }
+
+    fn contains(&self, lat: f64, lng: f64) -> bool {
+        self.polygons.iter().any(|p| p.contains(lat, lng))
+    }
+}
+
+/// The official neighborhoods (Milan's NIL), kept in memory: they are less
+/// than a hundred. The portals all name the zones their own way.
+#[derive(Clone, Debug, Default)]
+pub struct Neighborhoods {
+    neighborhoods: Arc<Vec<Neighborhood>>,
+}
+
+impl Neighborhoods {
+    pub fn new(neighborhoods: Vec<Neighborhood>) -> Self {
+        Self {
+            neighborhoods: Arc::new(neighborhoods),
+        }
+    }
+
+    /// Reads the polygons from a GeoJSON file.
+    /// Without a file the houses only have the zone of the portal.
+    pub fn load(path: Option<&str>) -> Self {
+        let path = match path {
+            None => {
+                event!(Level::WARN, "no neighborhoods file configured");
+                return Self::default();
+            }
+            Some(path) => path,
+        };
+
+        let content = std::fs::read_to_string(path).unwrap();
+        let neighborhoods = neighborhoods_from_geojson(&content);
+        event!(
+            Level::INFO,
+            count = neighborhoods.len(),
+            "neighborhoods loaded"
+        );
+
+        Self::new(neighborhoods)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<_> = self.neighborhoods.iter().map(|n| n.name.as_str()).collect();
+        names.sort();
+        names
+    }
+
+    pub fn find(&self, lat: f64, lng: f64) -> Option<String> {
+        self.neighborhoods
+            .iter()
+            .find(|n| n.contains(lat, lng))
+            .map(|n| n.name.clone())
+    }
+}
+
+/// The Comune di Milano dataset calls the name "NIL"
+#[derive(Deserialize)]
+struct NeighborhoodProperties {
+    #[serde(alias = "NIL", alias = "nome")]
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct FeatureCollection {
+    features: Vec<Feature>,
+}
+
+#[derive(Deserialize)]
+struct Feature {
+    geometry: Option<GeoArea>,
+    properties: NeighborhoodProperties,
+}
+
+fn neighborhoods_from_geojson(content: &str) -> Vec<Neighborhood> {
+    let collection: FeatureCollection = serde_json::from_str(content).unwrap();
+
+    collection
+        .features
+        .into_iter()
+        .filter_map(|f| {
+            let polygons = f
+                .geometry?
+                .polygons()
+                .into_iter()
+                .filter_map(|p| p.validate().ok())
+                .collect();
+            Some(Neighborhood::new(f.properties.name, polygons))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find() {
+        let geojson = r#"{
+            "type": "FeatureCollection",
+            "features": [
+                {
+                    "type": "Feature",
+                    "geometry": {
+                        "type": "Polygon",
+                        "coordinates": [[[9.18, 45.46], [9.20, 45.46], [9.20, 45.47], [9.18, 45.47]]]
+                    },
+                    "properties": { "ID_NIL": 1, "NIL": "DUOMO" }
+                },
+                {
+                    "type": "Feature",
+                    "geometry": {
+                        "type": "MultiPolygon",
+                        "coordinates": [
+                            [[[9.16, 45.49], [9.18, 45.49], [9.18, 45.50], [9.16, 45.49]]],
+                            [[[9.17, 45.50], [9.19, 45.50], [9.19, 45.51], [9.17, 45.50]]]
+                        ]
+                    },
+                    "properties": { "ID_NIL": 2, "NIL": "DERGANO" }
+                }
+            ]
+        }"#;
+        let neighborhoods = Neighborhoods::new(neighborhoods_from_geojson(geojson));
+
+        assert_eq!(neighborhoods.names(), ["DERGANO", "DUOMO"]);
+        assert_eq!(
+            neighborhoods.find(45.4641, 9.1919).as_deref(),
+            Some("DUOMO")
+        );
+        assert_eq!(
+            neighborhoods.find(45.5020, 9.1850).as_deref(),
+            Some("DERGANO")
+        );
+        assert_eq!(neighborhoods.find(45.40, 9.10), None);
+    }
+}
@@ -0,0 +1,641 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+};
+
+use flate2::read::ZlibDecoder;
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+
+use crate::geo;
+
+#[derive(Debug)]
+pub enum OsmError {
+    Io(std::io::Error),
+    Malformed(&'static str),
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for OsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "io: {}", e),
+            Self::Malformed(what) => write!(f, "malformed extract: {}", what),
+            Self::Unsupported(what) => write!(f, "unsupported extract: {}", what),
+        }
+    }
+}
+
+impl From<std::io::Error> for OsmError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+type Result<T> = std::result::Result<T, OsmError>;
+
+/// The amenities counted around the houses
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Category {
+    Supermarket,
+    Park,
+    Pharmacy,
+    School,
+}
+
+impl Category {
+    fn from_tag(key: &[u8], value: &[u8]) -> Option<Self> {
+        match (key, value) {
+            (b"shop", b"supermarket") => Some(Self::Supermarket),
+            (b"leisure", b"park") => Some(Self::Park),
+            (b"amenity", b"pharmacy") => Some(Self::Pharmacy),
+            (b"amenity", b"school") => Some(Self::School),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Amenity {
+    pub category: Category,
+    pub lat: f64,
+    pub lng: f64,
+}
+
+/// How many amenities of each kind are within a radius from a house
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AmenityCounts {
+    /// Meters, as the crow flies
+    pub radius: u32,
+    pub supermarkets: u32,
+    pub parks: u32,
+    pub pharmacies: u32,
+    pub schools: u32,
+}
+
+impl AmenityCounts {
+    fn add(&mut self, category: Category) {
+        let count = match category {
+            Category::Supermarket => &mut self.supermarkets,
+            Category::Park => &mut self.parks,
+            Category::Pharmacy => &mut self.pharmacies,
+            Category::School => &mut self.schools,
+        };
+        *count += 1;
+    }
+}
+
+/// The amenities of an OpenStreetMap extract, indexed by position
+#[derive(Debug, Default)]
+pub struct Amenities {
+    amenities: Vec<Amenity>,
+    grid: geo::Grid,
+}
+
+impl Amenities {
+    pub fn new(amenities: Vec<Amenity>) -> Self {
+        let grid = geo::Grid::new(amenities.iter().map(|a| (a.lat, a.lng)));
+        Self { amenities, grid }
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let amenities = Self::from_pbf(BufReader::new(File::open(path)?))?;
+        event!(
+            Level::INFO,
+            count = amenities.amenities.len(),
+            "OSM amenities loaded"
+        );
+
+        Ok(amenities)
+    }
+
+    /// Reads the file twice: parks are mostly ways, which come after the nodes
+    /// carrying their coordinates
+    pub fn from_pbf<R: Read + Seek>(mut reader: R) -> Result<Self> {
+        let mut amenities = vec![];
+        let mut ways = vec![];
+        read_elements(&mut reader, |element| match element {
+            Element::Node {
+                category: Some(category),
+                lat,
+                lng,
+                ..
+            } => amenities.push(Amenity { category, lat, lng }),
+            Element::Way {
+                category: Some(category),
+                refs,
+            } => ways.push((category, refs)),
+            _ => {}
+        })?;
+
+        let mut positions: HashMap<i64, (f64, f64)> = ways
+            .iter()
+            .flat_map(|(_, refs)| refs.iter().map(|&id| (id, (f64::NAN, f64::NAN))))
+            .collect();
+        if !positions.is_empty() {
+            reader.seek(SeekFrom::Start(0))?;
+            read_elements(&mut reader, |element| {
+                if let Element::Node { id, lat, lng, .. } = element {
+                    if let Some(position) = positions.get_mut(&id) {
+                        *position = (lat, lng);
+                    }
+                }
+            })?;
+        }
+
+        // Close enough for counting: the center of the outline
+        for (category, refs) in ways {
+            let known: Vec<_> = refs
+                .iter()
+                .map(|id| positions[id])
+                .filter(|(lat, _)| !lat.is_nan())
+                .collect();
+            if known.is_empty() {
+                continue;
+            }
+            let n = known.len() as f64;
+            amenities.push(Amenity {
+                category,
+                lat: known.iter().map(|(lat, _)| lat).sum::<f64>() / n,
+                lng: known.iter().map(|(_, lng)| lng).sum::<f64>() / n,
+            });
+        }
+
+        Ok(Self::new(amenities))
+    }
+
+    pub fn count(&self) -> usize {
+        self.amenities.len()
+    }
+
+    pub fn counts(&self, lat: f64, lng: f64, radius: u32) -> AmenityCounts {
+        let mut counts = AmenityCounts {
+            radius,
+            ..Default::default()
+        };
+        for i in self.grid.around(lat, lng, radius as f64) {
+            let amenity = &self.amenities[i];
+            if geo::distance(lat, lng, amenity.lat, amenity.lng) <= radius as f64 {
+                counts.add(amenity.category);
+            }
+        }
+        counts
+    }
+}
+
+enum Element {
+    Node {
+        id: i64,
+        lat: f64,
+        lng: f64,
+        category: Option<Category>,
+    },
+    /// Only the tagged ones get their node references
+    Way {
+        category: Option<Category>,
+        refs: Vec<i64>,
+    },
+}
+
+/// Walks the blocks of a `.pbf` file: a length, a header, a blob
+fn read_elements<R: Read>(reader: &mut R, mut f: impl FnMut(Element)) -> Result<()> {
+    loop {
+        let mut length = [0; 4];
+        match reader.read_exact(&mut length) {
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            r => r?,
+        }
+        let mut header = vec![0; u32::from_be_bytes(length) as usize];
+        reader.read_exact(&mut header)?;
+
+        let mut kind: &[u8] = &[];
+        let mut size = 0;
+        for field in Fields::new(&header) {
+            match field? {
+                (1, Value::Bytes(b)) => kind = b,
+                (3, Value::Varint(v)) => size = v as usize,
+                _ => {}
+            }
+        }
+        let mut blob = vec![0; size];
+        reader.read_exact(&mut blob)?;
+
+        // The other kind is the OSMHeader, nothing to count there
+        if kind == b"OSMData" {
+            let data = decompress(&blob)?;
+            Block::parse(&data)?.elements(&mut f)?;
+        }
+    }
+}
+
+fn decompress(blob: &[u8]) -> Result<Vec<u8>> {
+    let mut raw_size = 0;
+    for field in Fields::new(blob) {
+        match field? {
+            (1, Value::Bytes(raw)) => return Ok(raw.to_vec()),
+            (2, Value::Varint(v)) => raw_size = v as usize,
+            (3, Value::Bytes(zlib)) => {
+                let mut data = Vec::with_capacity(raw_size);
+                ZlibDecoder::new(zlib).read_to_end(&mut data)?;
+                return Ok(data);
+            }
+            (4..=7, _) => return Err(OsmError::Unsupported("blob compression")),
+            _ => {}
+        }
+    }
+    Err(OsmError::Malformed("empty blob"))
+}
+
+struct Block<'a> {
+    strings: Vec<&'a [u8]>,
+    groups: Vec<&'a [u8]>,
+    granularity: i64,
+    lat_offset: i64,
+    lon_offset: i64,
+}
+
+impl<'a> Block<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self> {
+        let mut block = Self {
+            strings: vec![],
+            groups: vec![],
+            granularity: 100,
+            lat_offset: 0,
+            lon_offset: 0,
+        };
+        for field in Fields::new(data) {
+            match field? {
+                (1, Value::Bytes(table)) => {
+                    for string in Fields::new(table) {
+                        if let (1, Value::Bytes(s)) = string? {
+                            block.strings.push(s);
+                        }
+                    }
+                }
+                (2, Value::Bytes(group)) => block.groups.push(group),
+                (17, Value::Varint(v)) => block.granularity = v as i64,
+                (19, Value::Varint(v)) => block.lat_offset = v as i64,
+                (20, Value::Varint(v)) => block.lon_offset = v as i64,
+                _ => {}
+            }
+        }
+        Ok(block)
+    }
+
+    /// Nanodegrees to degrees
+    fn degrees(&self, offset: i64, value: i64) -> f64 {
+        (offset + self.granularity * value) as f64 * 1e-9
+    }
+
+    fn category(&self, key: u64, value: u64) -> Option<Category> {
+        let string = |i: u64| self.strings.get(i as usize).copied().unwrap_or_default();
+        Category::from_tag(string(key), string(value))
+    }
+
+    fn tags_category(&self, keys: &[u64], values: &[u64]) -> Option<Category> {
+        keys.iter()
+            .zip(values)
+            .find_map(|(&k, &v)| self.category(k, v))
+    }
+
+    fn elements(&self, f: &mut impl FnMut(Element)) -> Result<()> {
+        for group in &self.groups {
+            for field in Fields::new(group) {
+                match field? {
+                    (1, Value::Bytes(node)) => f(self.node(node)?),
+                    (2, Value::Bytes(dense)) => self.dense_nodes(dense, f)?,
+                    (3, Value::Bytes(way)) => f(self.way(way)?),
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn node(&self, data: &[u8]) -> Result<Element> {
+        let (mut id, mut lat, mut lng) = (0, 0, 0);
+        let (mut keys, mut values) = (vec![], vec![]);
+        for field in Fields::new(data) {
+            match field? {
+                (1, Value::Varint(v)) => id = zigzag(v),
+                (2, Value::Bytes(b)) => keys = packed(b)?,
+                (3, Value::Bytes(b)) => values = packed(b)?,
+                (8, Value::Varint(v)) => lat = zigzag(v),
+                (9, Value::Varint(v)) => lng = zigzag(v),
+                _ => {}
+            }
+        }
+        Ok(Element::Node {
+            id,
+            lat: self.degrees(self.lat_offset, lat),
+            lng: self.degrees(self.lon_offset, lng),
+            category: self.tags_category(&keys, &values),
+        })
+    }
+
+    /// Ids and coordinates are delta coded, the tags of all the nodes
+    /// are in one list, each node's ending with a 0
+    fn dense_nodes(&self, data: &[u8], f: &mut impl FnMut(Element)) -> Result<()> {
+        let (mut ids, mut lats, mut lngs, mut tags) = (vec![], vec![], vec![], vec![]);
+        for field in Fields::new(data) {
+            match field? {
+                (1, Value::Bytes(b)) => ids = packed(b)?,
+                (8, Value::Bytes(b)) => lats = packed(b)?,
+                (9, Value::Bytes(b)) => lngs = packed(b)?,
+                (10, Value::Bytes(b)) => tags = packed(b)?,
+                _ => {}
+            }
+        }
+        if lats.len() != ids.len() || lngs.len() != ids.len() {
+            return Err(OsmError::Malformed("dense nodes of different lengths"));
+        }
+
+        let mut tags = tags.into_iter();
+        let (mut id, mut lat, mut lng) = (0, 0, 0);
+        for i in 0..ids.len() {
+            id += zigzag(ids[i]);
+            lat += zigzag(lats[i]);
+            lng += zigzag(lngs[i]);
+
+            let mut category = None;
+            while let Some(key) = tags.next().filter(|&k| k != 0) {
+                let value = tags.next().unwrap_or_default();
+                category = category.or_else(|| self.category(key, value));
+            }
+
+            f(Element::Node {
+                id,
+                lat: self.degrees(self.lat_offset, lat),
+                lng: self.degrees(self.lon_offset, lng),
+                category,
+            });
+        }
+        Ok(())
+    }
+
+    fn way(&self, data: &[u8]) -> Result<Element> {
+        let (mut keys, mut values, mut refs): (_, _, &[u8]) = (vec![], vec![], &[]);
+        for field in Fields::new(data) {
+            match field? {
+                (2, Value::Bytes(b)) => keys = packed(b)?,
+                (3, Value::Bytes(b)) => values = packed(b)?,
+                (8, Value::Bytes(b)) => refs = b,
+                _ => {}
+            }
+        }
+
+        let category = self.tags_category(&keys, &values);
+        let refs = match category {
+            None => vec![],
+            Some(_) => packed(refs)?
+                .into_iter()
+                .scan(0, |id, delta| {
+                    *id += zigzag(delta);
+                    Some(*id)
+                })
+                .collect(),
+        };
+        Ok(Element::Way { category, refs })
+    }
+}
+
+/// Just enough protobuf to read the OSM blocks
+struct Fields<'a> {
+    data: &'a [u8],
+}
+
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+impl<'a> Fields<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if n > self.data.len() {
+            return Err(OsmError::Malformed("truncated field"));
+        }
+        let (taken, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn field(&mut self) -> Result<(u32, Value<'a>)> {
+        let key = varint(&mut self.data)?;
+        let value = match key & 7 {
+            0 => Value::Varint(varint(&mut self.data)?),
+            1 => self.take(8).map(|_| Value::Fixed)?,
+            2 => {
+                let length = varint(&mut self.data)? as usize;
+                Value::Bytes(self.take(length)?)
+            }
+            5 => self.take(4).map(|_| Value::Fixed)?,
+            _ => return Err(OsmError::Malformed("unknown wire type")),
+        };
+        Ok(((key >> 3) as u32, value))
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = Result<(u32, Value<'a>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let field = self.field();
+        if field.is_err() {
+            self.data = &[];
+        }
+        Some(field)
+    }
+}
+
+fn varint(data: &mut &[u8]) -> Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = data
+            .split_first()
+            .ok_or(OsmError::Malformed("truncated varint"))?;
+        *data = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(OsmError::Malformed("varint too long"))
+}
+
+fn packed(mut data: &[u8]) -> Result<Vec<u64>> {
+    let mut values = vec![];
+    while !data.is_empty() {
+        values.push(varint(&mut data)?);
+    }
+    Ok(values)
+}
+
+fn zigzag(v: u64) -> i64 {
+    (v >> 1) as i64 ^ -((v & 1) as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use flate2::{write::ZlibEncoder, Compression};
+
+    use super::*;
+
+    fn encode_varint(buf: &mut Vec<u8>, mut v: u64) {
+        while v >= 0x80 {
+            buf.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        buf.push(v as u8);
+    }
+
+    fn encode_zigzag(v: i64) -> u64 {
+        ((v << 1) ^ (v >> 63)) as u64
+    }
+
+    fn varint_field(buf: &mut Vec<u8>, field: u64, v: u64) {
+        encode_varint(buf, field << 3);
+        encode_varint(buf, v);
+    }
+
+    fn bytes_field(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+        encode_varint(buf, field << 3 | 2);
+        encode_varint(buf, bytes.len() as u64);
+        buf.extend_from_slice(bytes);
+    }
+
+    fn packed_field(buf: &mut Vec<u8>, field: u64, values: impl IntoIterator<Item = u64>) {
+        let mut packed = vec![];
+        for v in values {
+            encode_varint(&mut packed, v);
+        }
+        bytes_field(buf, field, &packed);
+    }
+
+    fn deltas(values: &[i64]) -> Vec<u64> {
+        let mut last = 0;
+        values
+            .iter()
+            .map(|&v| {
+                let delta = v - last;
+                last = v;
+                encode_zigzag(delta)
+            })
+            .collect()
+    }
+
+    fn write_block(file: &mut Vec<u8>, kind: &str, block: &[u8]) {
+        let mut zlib = ZlibEncoder::new(vec![], Compression::default());
+        zlib.write_all(block).unwrap();
+        let mut blob = vec![];
+        varint_field(&mut blob, 2, block.len() as u64);
+        bytes_field(&mut blob, 3, &zlib.finish().unwrap());
+
+        let mut header = vec![];
+        bytes_field(&mut header, 1, kind.as_bytes());
+        varint_field(&mut header, 3, blob.len() as u64);
+
+        file.extend_from_slice(&(header.len() as u32).to_be_bytes());
+        file.extend_from_slice(&header);
+        file.extend_from_slice(&blob);
+    }
+
+    /// Around the Duomo: an Esselunga, a pharmacy, a bench, and a park
+    /// drawn as a square way
+    fn extract() -> Vec<u8> {
+        let strings = [
+            "",
+            "shop",
+            "supermarket",
+            "amenity",
+            "pharmacy",
+            "bench",
+            "leisure",
+            "park",
+        ];
+        let mut table = vec![];
+        for s in strings {
+            bytes_field(&mut table, 1, s.as_bytes());
+        }
+
+        // In units of the default granularity, 100 nanodegrees
+        let ids = [1, 2, 3, 10, 11, 12, 13];
+        let lats = [
+            454_642_000,
+            454_650_000,
+            454_630_000,
+            454_700_000,
+            454_700_000,
+            454_720_000,
+            454_720_000,
+        ];
+        let lngs = [
+            91_900_000, 91_880_000, 91_910_000, 91_750_000, 91_770_000, 91_770_000, 91_750_000,
+        ];
+        let mut dense = vec![];
+        packed_field(&mut dense, 1, deltas(&ids));
+        packed_field(&mut dense, 8, deltas(&lats));
+        packed_field(&mut dense, 9, deltas(&lngs));
+        packed_field(&mut dense, 10, [1, 2, 0, 3, 4, 0, 3, 5, 0, 0, 0, 0, 0]);
+        let mut nodes = vec![];
+        bytes_field(&mut nodes, 2, &dense);
+
+        let mut way = vec![];
+        varint_field(&mut way, 1, 100);
+        packed_field(&mut way, 2, [6]);
+        packed_field(&mut way, 3, [7]);
+        packed_field(&mut way, 8, deltas(&[10, 11, 12, 13, 10]));
+        let mut ways = vec![];
+        bytes_field(&mut ways, 3, &way);
+
+        let mut block = vec![];
+        bytes_field(&mut block, 1, &table);
+        bytes_field(&mut block, 2, &nodes);
+        bytes_field(&mut block, 2, &ways);
+
+        let mut file = vec![];
+        write_block(&mut file, "OSMHeader", &[]);
+        write_block(&mut file, "OSMData", &block);
+        file
+    }
+
+    #[test]
+    fn test_from_pbf() {
+        let amenities = Amenities::from_pbf(Cursor::new(extract())).unwrap();
+        assert_eq!(amenities.count(), 3);
+
+        let park = amenities.amenities.last().unwrap();
+        assert_eq!(park.category, Category::Park);
+        assert!((park.lat - 45.4708).abs() < 1e-6, "{}", park.lat);
+        assert!((park.lng - 9.1758).abs() < 1e-6, "{}", park.lng);
+
+        assert_eq!(
+            amenities.counts(45.4641, 9.1919, 500),
+            AmenityCounts {
+                radius: 500,
+                supermarkets: 1,
+                pharmacies: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(amenities.counts(45.4641, 9.1919, 2000).parks, 1);
+    }
+
+    #[test]
+    fn test_malformed() {
+        let mut file = extract();
+        file.truncate(file.len() - 10);
+        assert!(Amenities::from_pbf(Cursor::new(file)).is_err());
+    }
+}
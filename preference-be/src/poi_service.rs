@@ -0,0 +1,223 @@
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document},
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+
+use crate::geo;
+
+/// The places that matter to each of us: work, gym, parents' home...
+#[derive(Clone)]
+pub struct PoisService {
+    collection: Collection<PoiEntity>,
+}
+
+type Result<T> = std::result::Result<T, PoisServiceError>;
+
+#[derive(Debug)]
+pub enum PoisServiceError {
+    MongoDbError(mongodb::error::Error),
+    ObjectId(mongodb::bson::oid::Error),
+    PoiNotFound(String),
+}
+
+impl fmt::Display for PoisServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::MongoDbError(e) => write!(f, "mongodb: {}", e),
+            Self::ObjectId(e) => write!(f, "invalid id: {}", e),
+            Self::PoiNotFound(id) => write!(f, "poi {} not found", id),
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for PoisServiceError {
+    fn from(e: mongodb::error::Error) -> Self {
+        Self::MongoDbError(e)
+    }
+}
+impl From<mongodb::bson::oid::Error> for PoisServiceError {
+    fn from(e: mongodb::bson::oid::Error) -> Self {
+        Self::ObjectId(e)
+    }
+}
+
+impl PoisService {
+    pub fn new(collection: Collection<PoiEntity>) -> Self {
+        Self { collection }
+    }
+
+    pub async fn insert_poi(&self, poi: PoiDTOInsert) -> Result<String> {
+        let poi = PoiEntity {
+            _id: ObjectId::new(),
+            user: poi.user,
+            name: poi.name,
+            lat: poi.lat,
+            lng: poi.lng,
+        };
+
+        event!(Level::INFO, user = %poi.user, name = %poi.name, "inserting poi");
+        self.collection.insert_one(&poi, None).await?;
+
+        Ok(poi._id.to_hex())
+    }
+
+    pub async fn get_pois(&self, query: PoisQuery) -> Result<Vec<PoiDTO>> {
+        let filter = match query.user {
+            Some(user) => doc! { "user": user },
+            None => doc! {},
+        };
+        let pois = self.pois(filter).await?;
+
+        event!(Level::INFO, count = pois.len(), "found");
+
+        Ok(pois.into_iter().map(PoiDTO::from).collect())
+    }
+
+    pub async fn remove_poi(&self, poi_id: String) -> Result<()> {
+        let id = ObjectId::from_str(&poi_id)?;
+
+        event!(Level::INFO, poi_id = %poi_id, "removing");
+        let res = self.collection.delete_one(doc! { "_id": id }, None).await?;
+
+        if res.deleted_count == 0 {
+            event!(Level::WARN, poi_id = %poi_id, "Not found");
+            return Err(PoisServiceError::PoiNotFound(poi_id));
+        }
+
+        Ok(())
+    }
+
+    async fn pois(&self, filter: Document) -> Result<Vec<PoiEntity>> {
+        let cur = self.collection.find(filter, None).await?;
+        Ok(cur.try_collect().await?)
+    }
+
+    /// The points of interest of everybody, read once for as many houses as needed
+    pub async fn load(&self) -> Result<Pois> {
+        Ok(Pois(self.pois(doc! {}).await?))
+    }
+}
+
+/// See `PoisService::load`
+pub struct Pois(Vec<PoiEntity>);
+
+impl Pois {
+    /// Distances from a house to each point of interest,
+    /// keyed by the poi id so the list can filter and sort on them
+    pub fn distances(&self, lat: f64, lng: f64) -> BTreeMap<String, PoiDistance> {
+        self.0
+            .iter()
+            .map(|p| (p._id.to_hex(), PoiDistance::new(p, lat, lng)))
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PoiEntity {
+    _id: ObjectId,
+    /// Whose place it is
+    user: String,
+    name: String,
+    lat: f64,
+    lng: f64,
+}
+
+#[derive(Deserialize)]
+pub struct PoiDTOInsert {
+    user: String,
+    name: String,
+    lat: f64,
+    lng: f64,
+}
+
+#[derive(Deserialize)]
+pub struct PoisQuery {
+    user: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct PoiDTO {
+    id: String,
+    user: String,
+    name: String,
+    lat: f64,
+    lng: f64,
+}
+
+impl From<PoiEntity> for PoiDTO {
+    fn from(e: PoiEntity) -> Self {
+        Self {
+            id: e._id.to_hex(),
+            user: e.user,
+            name: e.name,
+            lat: e.lat,
+            lng: e.lng,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PoiDistance {
+    user: String,
+    name: String,
+    /// Straight line
+    meters: f64,
+    /// Estimated on the straight line, streets are longer
+    walk_minutes: u32,
+    bike_minutes: u32,
+}
+
+impl PoiDistance {
+    fn new(poi: &PoiEntity, lat: f64, lng: f64) -> Self {
+        let streets = geo::walking_distance(lat, lng, poi.lat, poi.lng);
+        let minutes = |speed: f64| (streets / speed / 60.0).ceil() as u32;
+
+        Self {
+            user: poi.user.clone(),
+            name: poi.name.clone(),
+            meters: geo::distance(lat, lng, poi.lat, poi.lng).round(),
+            walk_minutes: minutes(geo::WALKING_SPEED),
+            bike_minutes: minutes(geo::CYCLING_SPEED),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn meters(&self) -> f64 {
+        self.meters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distance() {
+        let gym = PoiEntity {
+            _id: ObjectId::new(),
+            user: "alice".to_string(),
+            name: "gym".to_string(),
+            lat: 45.4683,
+            lng: 9.1755,
+        };
+
+        assert_eq!(
+            PoiDistance::new(&gym, 45.4641, 9.1919),
+            PoiDistance {
+                user: "alice".to_string(),
+                name: "gym".to_string(),
+                meters: 1362.0,
+                walk_minutes: 22,
+                bike_minutes: 8,
+            }
+        );
+    }
+}
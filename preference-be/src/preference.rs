@@ -0,0 +1,196 @@
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{fair_price::Listing, regression::Ridge};
+
+/// Votes needed before guessing the next ones
+const MIN_VOTES: usize = 5;
+/// Votes are few: a strong penalty keeps a single vote from deciding
+/// what a zone or an amenity is worth
+const PENALTY: f64 = 3.0;
+/// Features explaining a predicted vote
+const TOP_FEATURES: usize = 3;
+pub const MAX_VOTE: f64 = 10.0;
+
+/// What a vote is predicted from
+#[derive(Debug, Clone, Default)]
+pub struct VoteFeatures {
+    pub listing: Listing,
+    /// Walking, in meters
+    pub metro_distance: Option<f64>,
+}
+
+/// How much a feature of the house moved the vote from the average one
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Contribution {
+    pub feature: String,
+    pub effect: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PredictedVote {
+    pub vote: f64,
+    /// The biggest effects first
+    pub contributions: Vec<Contribution>,
+    /// How many votes it was learnt from
+    pub samples: usize,
+}
+
+/// A ridge regression of a voter's votes
+#[derive(Debug)]
+pub struct VoteModel {
+    zones: Vec<String>,
+    amenities: Vec<String>,
+    ridge: Ridge,
+    samples: usize,
+}
+
+impl VoteModel {
+    /// None with too few votes
+    pub fn fit(voted: &[(&VoteFeatures, u8)]) -> Option<Self> {
+        if voted.len() < MIN_VOTES {
+            return None;
+        }
+
+        let zones: BTreeSet<_> = voted
+            .iter()
+            .filter_map(|(f, _)| f.listing.zone.clone())
+            .collect();
+        let amenities: BTreeSet<_> = voted
+            .iter()
+            .flat_map(|(f, _)| f.listing.amenities.keys().cloned())
+            .collect();
+        let zones: Vec<_> = zones.into_iter().collect();
+        let amenities: Vec<_> = amenities.into_iter().collect();
+
+        let rows: Vec<_> = voted
+            .iter()
+            .map(|(f, _)| features(&zones, &amenities, f))
+            .collect();
+        let votes: Vec<f64> = voted.iter().map(|&(_, v)| v as f64).collect();
+
+        Some(Self {
+            ridge: Ridge::fit(&rows, &votes, PENALTY)?,
+            zones,
+            amenities,
+            samples: voted.len(),
+        })
+    }
+
+    /// Rounded to a tenth, so a refit moving it slightly changes nothing
+    pub fn predict(&self, house: &VoteFeatures) -> PredictedVote {
+        let row = features(&self.zones, &self.amenities, house);
+        let round = |v: f64| (v * 10.0).round() / 10.0;
+
+        let mut contributions: Vec<_> = self
+            .names()
+            .into_iter()
+            .zip(self.ridge.contributions(&row))
+            .filter(|(_, effect)| round(*effect) != 0.0)
+            .map(|(feature, effect)| Contribution {
+                feature,
+                effect: round(effect),
+            })
+            .collect();
+        contributions.sort_by(|a, b| b.effect.abs().total_cmp(&a.effect.abs()));
+        contributions.truncate(TOP_FEATURES);
+
+        PredictedVote {
+            vote: round(self.ridge.predict(&row).clamp(0.0, MAX_VOTE)),
+            contributions,
+            samples: self.samples,
+        }
+    }
+
+    /// In the order of `features`
+    fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = [
+            "cost",
+            "cost_per_square_meter",
+            "square_meters",
+            "rooms_number",
+            "floor",
+            "metro_distance",
+        ]
+        .map(String::from)
+        .into();
+        names.extend(self.zones.iter().map(|z| format!("zone={}", z)));
+        names.extend(self.amenities.iter().cloned());
+        names
+    }
+}
+
+fn features(zones: &[String], amenities: &[String], house: &VoteFeatures) -> Vec<Option<f64>> {
+    let listing = &house.listing;
+    let cost_per_square_meter = match (listing.cost, listing.square_meters) {
+        (Some(cost), Some(square_meters)) if square_meters > 0 => {
+            Some(cost as f64 / square_meters as f64)
+        }
+        _ => None,
+    };
+
+    let mut features = vec![
+        listing.cost.map(|c| c as f64),
+        cost_per_square_meter,
+        listing.square_meters.map(|s| s as f64),
+        listing.rooms_number.map(|r| r as f64),
+        listing.floor.map(|f| f as f64),
+        house.metro_distance,
+    ];
+    features.extend(listing.flags(zones, amenities));
+    features
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn house(cost: u32, zone: &str, garden: bool, metro_distance: f64) -> VoteFeatures {
+        VoteFeatures {
+            listing: Listing {
+                square_meters: Some(60),
+                rooms_number: Some(2),
+                floor: None,
+                zone: Some(zone.to_string()),
+                amenities: BTreeMap::from([("garden".to_string(), garden)]),
+                cost: Some(cost),
+            },
+            metro_distance: Some(metro_distance),
+        }
+    }
+
+    #[test]
+    fn test_vote_model() {
+        // We like gardens and hate long walks, the zone does not matter
+        let houses: Vec<_> = (0..30)
+            .map(|i| {
+                let garden = i % 2 == 0;
+                let distance = (i % 5) as f64 * 300.0;
+                let vote = 5 + if garden { 3 } else { 0 } - (distance / 600.0) as u8;
+                let zone = if i % 3 == 0 { "Isola" } else { "Dergano" };
+                (house(1000 + i * 10, zone, garden, distance), vote)
+            })
+            .collect();
+        let voted: Vec<_> = houses.iter().map(|(f, v)| (f, *v)).collect();
+
+        assert!(VoteModel::fit(&voted[..MIN_VOTES - 1]).is_none());
+        let model = VoteModel::fit(&voted).unwrap();
+
+        let good = model.predict(&house(1100, "Isola", true, 0.0));
+        let bad = model.predict(&house(1100, "Isola", false, 1200.0));
+        assert!(good.vote > 6.5, "{:?}", good);
+        assert!(bad.vote < 4.5, "{:?}", bad);
+        assert_eq!(good.samples, 30);
+
+        assert!(good.contributions.len() <= TOP_FEATURES);
+        assert_eq!(good.contributions[0].feature, "garden");
+        assert!(good.contributions[0].effect > 0.0);
+        assert!(bad
+            .contributions
+            .iter()
+            .any(|c| c.feature == "metro_distance" && c.effect < 0.0));
+    }
+}
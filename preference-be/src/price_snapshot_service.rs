@@ -0,0 +1,196 @@
+use std::fmt;
+
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId},
+    options::FindOneOptions,
+    Collection, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+
+use crate::{
+    geo::{self, GeoPoint, InvalidGeometry},
+    heatmap::PriceSample,
+    house_query::{HeatmapQuery, InvalidQuery},
+    house_service::ListingType,
+};
+
+/// The prices the portals showed for the houses and the candidates
+/// other than the saved ones, which they never replace
+#[derive(Clone)]
+pub struct PriceSnapshotsService {
+    collection: Collection<PriceSnapshotEntity>,
+}
+
+type Result<T> = std::result::Result<T, PriceSnapshotsServiceError>;
+
+#[derive(Debug)]
+pub enum PriceSnapshotsServiceError {
+    MongoDbError(mongodb::error::Error),
+    InvalidQuery(String),
+}
+
+impl fmt::Display for PriceSnapshotsServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::MongoDbError(e) => write!(f, "mongodb: {}", e),
+            Self::InvalidQuery(message) => write!(f, "invalid query: {}", message),
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for PriceSnapshotsServiceError {
+    fn from(e: mongodb::error::Error) -> Self {
+        Self::MongoDbError(e)
+    }
+}
+impl From<InvalidQuery> for PriceSnapshotsServiceError {
+    fn from(e: InvalidQuery) -> Self {
+        Self::InvalidQuery(e.0)
+    }
+}
+impl From<InvalidGeometry> for PriceSnapshotsServiceError {
+    fn from(e: InvalidGeometry) -> Self {
+        Self::InvalidQuery(e.0)
+    }
+}
+
+impl PriceSnapshotsService {
+    pub fn new(collection: Collection<PriceSnapshotEntity>) -> Self {
+        Self { collection }
+    }
+
+    pub async fn create_indexes(&self) -> Result<()> {
+        let location = IndexModel::builder()
+            .keys(doc! { "location": "2dsphere" })
+            .build();
+        let link = IndexModel::builder().keys(doc! { "link": 1 }).build();
+        self.collection
+            .create_indexes([location, link], None)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Appends the price, unless it is the saved one or the last one seen.
+    /// Returns whether it did.
+    pub async fn record(
+        &self,
+        snapshot: &PriceSnapshotEntity,
+        saved_cost: Option<u32>,
+    ) -> Result<bool> {
+        if saved_cost == Some(snapshot.cost) {
+            return Ok(false);
+        }
+        let options = FindOneOptions::builder().sort(doc! { "_id": -1 }).build();
+        let last = self
+            .collection
+            .find_one(doc! { "link": &snapshot.link }, options)
+            .await?;
+        if last.is_some_and(|l| l.cost == snapshot.cost) {
+            return Ok(false);
+        }
+
+        event!(Level::INFO, link = %snapshot.link, cost = snapshot.cost, "recording price");
+        self.collection.insert_one(snapshot, None).await?;
+
+        Ok(true)
+    }
+
+    /// The other prices in the viewport, seen within the time window
+    pub async fn get_price_samples(&self, query: &HeatmapQuery) -> Result<Vec<PriceSample>> {
+        let polygon = query.bounding_box().polygon()?;
+        let mut filter = doc! {
+            "location": { "$geoWithin": { "$geometry": polygon.to_document() } },
+            "listing_type": mongodb::bson::to_bson(&query.listing_type).unwrap(),
+        };
+        filter.extend(query.window()?);
+
+        let cur = self.collection.find(filter, None).await?;
+        let snapshots: Vec<PriceSnapshotEntity> = cur.try_collect().await?;
+
+        Ok(snapshots
+            .into_iter()
+            .map(|s| PriceSample {
+                listing_type: s.listing_type,
+                lat: s.lat,
+                lng: s.lng,
+                cost_per_square_meter: s.cost_per_square_meter,
+                removed: false,
+                snapshot: true,
+            })
+            .collect())
+    }
+}
+
+/// A price the portal showed at `_id` time
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PriceSnapshotEntity {
+    _id: ObjectId,
+    link: String,
+    listing_type: ListingType,
+    lat: f64,
+    lng: f64,
+    location: GeoPoint,
+    cost: u32,
+    square_meters: u32,
+    cost_per_square_meter: f64,
+}
+
+impl PriceSnapshotEntity {
+    /// None when it could not go on the heatmap: without a listing type,
+    /// a position or a surface
+    pub fn new(
+        link: &str,
+        listing_type: Option<ListingType>,
+        position: Option<(f64, f64)>,
+        cost: Option<u32>,
+        square_meters: Option<u32>,
+    ) -> Option<Self> {
+        let listing_type = listing_type?;
+        let (lat, lng) = position?;
+        geo::check_position(lat, lng).ok()?;
+        let (cost, square_meters) = (cost?, square_meters.filter(|&s| s > 0)?);
+
+        Some(Self {
+            _id: ObjectId::new(),
+            link: link.to_owned(),
+            listing_type,
+            lat,
+            lng,
+            location: GeoPoint::new(lat, lng),
+            cost,
+            square_meters,
+            cost_per_square_meter: cost as f64 / square_meters as f64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new() {
+        let new = |listing_type, position, square_meters| {
+            PriceSnapshotEntity::new(
+                "http://the.link",
+                listing_type,
+                position,
+                Some(1000),
+                square_meters,
+            )
+        };
+        let rent = Some(ListingType::Rent);
+
+        let snapshot = new(rent, Some((45.46, 9.19)), Some(50)).unwrap();
+        assert_eq!(snapshot.cost_per_square_meter, 20.0);
+        assert_eq!(snapshot.location, GeoPoint::new(45.46, 9.19));
+
+        assert!(new(None, Some((45.46, 9.19)), Some(50)).is_none());
+        assert!(new(rent, None, Some(50)).is_none());
+        assert!(new(rent, Some((95.0, 9.19)), Some(50)).is_none());
+        assert!(new(rent, Some((45.46, 9.19)), Some(0)).is_none());
+    }
+}
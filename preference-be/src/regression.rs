@@ -0,0 +1,146 @@
+/// A ridge regression on standardized features.
+/// A missing value becomes the mean of its feature, which weighs nothing.
+#[derive(Debug)]
+pub struct Ridge {
+    /// Of each feature, to standardize it
+    means: Vec<f64>,
+    scales: Vec<f64>,
+    weights: Vec<f64>,
+    /// The features are centered: it is the mean target
+    intercept: f64,
+}
+
+impl Ridge {
+    /// The penalty shrinks the weights of the features seen a few times only.
+    /// None without rows, or if the system cannot be solved.
+    pub fn fit(rows: &[Vec<Option<f64>>], targets: &[f64], penalty: f64) -> Option<Self> {
+        let width = rows.first()?.len();
+
+        let mut means = Vec::with_capacity(width);
+        let mut scales = Vec::with_capacity(width);
+        for j in 0..width {
+            let values: Vec<f64> = rows.iter().filter_map(|r| r[j]).collect();
+            let (mean, variance) = if values.is_empty() {
+                (0.0, 0.0)
+            } else {
+                let mean = values.iter().sum::<f64>() / values.len() as f64;
+                let variance =
+                    values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64;
+                (mean, variance)
+            };
+            means.push(mean);
+            scales.push(if variance > 0.0 { variance.sqrt() } else { 1.0 });
+        }
+        let mut ridge = Self {
+            means,
+            scales,
+            weights: vec![],
+            intercept: targets.iter().sum::<f64>() / targets.len() as f64,
+        };
+
+        // (XᵀX + λI) w = Xᵀ(y - ȳ)
+        let mut a = vec![vec![0.0; width]; width];
+        let mut b = vec![0.0; width];
+        for (row, target) in rows.iter().zip(targets) {
+            let row = ridge.standardize(row);
+            for (i, xi) in row.iter().enumerate() {
+                b[i] += xi * (target - ridge.intercept);
+                for (j, xj) in row.iter().enumerate() {
+                    a[i][j] += xi * xj;
+                }
+            }
+        }
+        for (i, row) in a.iter_mut().enumerate() {
+            row[i] += penalty;
+        }
+        ridge.weights = solve(a, b)?;
+
+        Some(ridge)
+    }
+
+    fn standardize(&self, row: &[Option<f64>]) -> Vec<f64> {
+        row.iter()
+            .zip(self.means.iter().zip(&self.scales))
+            .map(|(x, (mean, scale))| x.map_or(0.0, |x| (x - mean) / scale))
+            .collect()
+    }
+
+    /// How much each feature moves the prediction away from the mean target
+    pub fn contributions(&self, row: &[Option<f64>]) -> Vec<f64> {
+        self.standardize(row)
+            .iter()
+            .zip(&self.weights)
+            .map(|(x, w)| x * w)
+            .collect()
+    }
+
+    pub fn predict(&self, row: &[Option<f64>]) -> f64 {
+        self.intercept + self.contributions(row).iter().sum::<f64>()
+    }
+}
+
+/// Gaussian elimination with partial pivoting, None if singular
+fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+
+        let pivot_row = a[col].clone();
+        for row in col + 1..n {
+            let factor = a[row][col] / pivot_row[col];
+            for (x, p) in a[row].iter_mut().zip(&pivot_row).skip(col) {
+                *x -= factor * p;
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let known: f64 = (row + 1..n).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - known) / a[row][row];
+    }
+    Some(x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_solve() {
+        let x = solve(vec![vec![2.0, 1.0], vec![1.0, 3.0]], vec![5.0, 10.0]).unwrap();
+        assert!((x[0] - 1.0).abs() < 1e-9);
+        assert!((x[1] - 3.0).abs() < 1e-9);
+
+        assert_eq!(
+            solve(vec![vec![1.0, 2.0], vec![2.0, 4.0]], vec![1.0, 2.0]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_ridge() {
+        // y = 2x, a useless constant feature and a missing value
+        let rows: Vec<_> = (0..20)
+            .map(|x| vec![Some(x as f64), Some(1.0), (x != 3).then_some(0.5)])
+            .collect();
+        let targets: Vec<_> = (0..20).map(|x| 2.0 * x as f64).collect();
+
+        let ridge = Ridge::fit(&rows, &targets, 0.01).unwrap();
+        assert!((ridge.predict(&[Some(10.0), Some(1.0), Some(0.5)]) - 20.0).abs() < 0.1);
+        // The mean input predicts the mean target
+        assert!((ridge.predict(&[None, None, None]) - 19.0).abs() < 1e-9);
+
+        let contributions = ridge.contributions(&[Some(19.0), Some(1.0), None]);
+        assert!(contributions[0] > 18.0);
+        assert_eq!(contributions[1..], [0.0, 0.0]);
+
+        assert!(Ridge::fit(&[], &[], 1.0).is_none());
+    }
+}
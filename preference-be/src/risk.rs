@@ -0,0 +1,297 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{geo, house_service::ListingType, statistics_service::percentile};
+
+/// Below this share of the zone median a price is too good to be true
+const CHEAP_RATIO: f64 = 0.6;
+/// Houses of a zone needed for its median to mean something
+const MIN_ZONE_HOUSES: usize = 5;
+/// Differing bits of two photo hashes still of the same picture, resized or recompressed
+const SAME_PHOTO_BITS: u32 = 4;
+/// Two houses closer than this are at the same address
+const SAME_ADDRESS_METERS: f64 = 100.0;
+/// What the fake landlords write: money up front, keys by mail, "I am abroad"
+const SCAM_PHRASES: &[&str] = &[
+    "western union",
+    "moneygram",
+    "postepay",
+    "bonifico anticipato",
+    "pagamento anticipato",
+    "caparra prima della visita",
+    "mi trovo all'estero",
+    "sono all'estero",
+    "chiavi per posta",
+    "chiavi tramite corriere",
+    "deposit before viewing",
+    "payment in advance",
+    "i am abroad",
+    "i'm abroad",
+    "keys by mail",
+    "keys by courier",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RiskSignal {
+    BelowZoneMedian,
+    MissingStreet,
+    MissingCoordinates,
+    ReusedPhotos,
+    ScamPhrases,
+}
+
+/// Why a house may be a fake listing
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RiskFlag {
+    pub signal: RiskSignal,
+    pub reason: String,
+}
+
+/// What a house is judged on
+#[derive(Debug, Clone, Default)]
+pub struct RiskFeatures<'a> {
+    pub id: String,
+    pub listing_type: Option<ListingType>,
+    /// The official neighborhood, or the zone of the portal without one
+    pub zone: Option<&'a str>,
+    pub street: Option<&'a str>,
+    /// Latitude and longitude
+    pub position: Option<(f64, f64)>,
+    pub cost_per_square_meter: Option<f64>,
+    pub description: Option<&'a str>,
+    /// See `media_service::photo_hash`
+    pub photo_hashes: Vec<u64>,
+}
+
+/// Judges each house against all the others
+pub struct RiskAnalyzer<'a> {
+    houses: &'a [RiskFeatures<'a>],
+    /// Cost per square meter, by listing type and zone
+    medians: BTreeMap<(Option<ListingType>, &'a str), f64>,
+}
+
+impl<'a> RiskAnalyzer<'a> {
+    pub fn new(houses: &'a [RiskFeatures<'a>]) -> Self {
+        let mut zones: BTreeMap<(Option<ListingType>, &str), Vec<f64>> = BTreeMap::new();
+        for house in houses {
+            if let (Some(zone), Some(cost)) = (house.zone, house.cost_per_square_meter) {
+                zones
+                    .entry((house.listing_type, zone))
+                    .or_default()
+                    .push(cost);
+            }
+        }
+
+        let medians = zones
+            .into_iter()
+            .filter(|(_, costs)| costs.len() >= MIN_ZONE_HOUSES)
+            .map(|(zone, mut costs)| {
+                costs.sort_by(f64::total_cmp);
+                (zone, percentile(&costs, 0.5))
+            })
+            .collect();
+
+        Self { houses, medians }
+    }
+
+    /// Empty when nothing looks wrong
+    pub fn analyze(&self, house: &RiskFeatures) -> Vec<RiskFlag> {
+        let mut flags = Vec::new();
+        let mut flag = |signal, reason: String| flags.push(RiskFlag { signal, reason });
+
+        if let (Some(zone), Some(cost)) = (house.zone, house.cost_per_square_meter) {
+            match self.medians.get(&(house.listing_type, zone)) {
+                Some(&median) if cost < median * CHEAP_RATIO => flag(
+                    RiskSignal::BelowZoneMedian,
+                    format!(
+                        "{:.1} €/m², {:.0}% below the median of {} ({:.1} €/m²)",
+                        cost,
+                        (1.0 - cost / median) * 100.0,
+                        zone,
+                        median
+                    ),
+                ),
+                _ => {}
+            }
+        }
+
+        if house.street.is_none_or(|s| s.trim().is_empty()) {
+            flag(RiskSignal::MissingStreet, "no street".to_owned());
+        }
+        if house.position.is_none() {
+            flag(RiskSignal::MissingCoordinates, "no coordinates".to_owned());
+        }
+
+        let reusing: Vec<&str> = self
+            .houses
+            .iter()
+            .filter(|other| other.id != house.id)
+            .filter(|other| shares_photos(house, other) && !same_address(house, other))
+            .map(|other| other.id.as_str())
+            .collect();
+        if !reusing.is_empty() {
+            flag(
+                RiskSignal::ReusedPhotos,
+                format!(
+                    "photos also in {} house(s) at another address: {}",
+                    reusing.len(),
+                    reusing.join(", ")
+                ),
+            );
+        }
+
+        let phrases = scam_phrases(house.description.unwrap_or_default());
+        if !phrases.is_empty() {
+            flag(
+                RiskSignal::ScamPhrases,
+                format!("mentions \"{}\"", phrases.join("\", \"")),
+            );
+        }
+
+        flags
+    }
+}
+
+fn shares_photos(a: &RiskFeatures, b: &RiskFeatures) -> bool {
+    // A blank or single colour picture hashes to 0, like any other blank one
+    a.photo_hashes.iter().filter(|&&h| h != 0).any(|&h| {
+        b.photo_hashes
+            .iter()
+            .any(|&other| (h ^ other).count_ones() <= SAME_PHOTO_BITS)
+    })
+}
+
+/// Unknown addresses are taken as different
+fn same_address(a: &RiskFeatures, b: &RiskFeatures) -> bool {
+    if let (Some((lat1, lng1)), Some((lat2, lng2))) = (a.position, b.position) {
+        return geo::distance(lat1, lng1, lat2, lng2) <= SAME_ADDRESS_METERS;
+    }
+    match (a.street, b.street) {
+        (Some(s1), Some(s2)) => normalize(s1) == normalize(s2),
+        _ => false,
+    }
+}
+
+fn scam_phrases(description: &str) -> Vec<&'static str> {
+    let text = format!(" {} ", normalize(description));
+    SCAM_PHRASES
+        .iter()
+        .copied()
+        .filter(|phrase| text.contains(&format!(" {} ", normalize(phrase))))
+        .collect()
+}
+
+/// Lower case words separated by a space: "All'estero!" becomes "all estero"
+fn normalize(text: &str) -> String {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn house(id: &str, cost: f64) -> RiskFeatures<'static> {
+        RiskFeatures {
+            id: id.to_string(),
+            listing_type: Some(ListingType::Rent),
+            zone: Some("Isola"),
+            street: Some("Via Borsieri 12"),
+            position: Some((45.487, 9.19)),
+            cost_per_square_meter: Some(cost),
+            description: Some("Bilocale luminoso, libero da subito."),
+            photo_hashes: vec![],
+        }
+    }
+
+    fn signals(flags: &[RiskFlag]) -> Vec<RiskSignal> {
+        flags.iter().map(|f| f.signal).collect()
+    }
+
+    #[test]
+    fn test_analyze() {
+        let mut houses: Vec<_> = [20.0, 21.0, 22.0, 23.0, 24.0]
+            .iter()
+            .enumerate()
+            .map(|(i, &cost)| house(&i.to_string(), cost))
+            .collect();
+        houses[0].photo_hashes = vec![0xf0f0_f0f0_0f0f_0f0f];
+
+        // Cheap, nowhere, with the photos of the first one
+        houses.push(RiskFeatures {
+            street: None,
+            position: None,
+            cost_per_square_meter: Some(9.0),
+            description: Some(
+                "Sono all'estero per lavoro, le chiavi per posta dopo un bonifico anticipato.",
+            ),
+            photo_hashes: vec![0xf0f0_f0f0_0f0f_0f0e],
+            ..house("scam", 0.0)
+        });
+        // Relisted at the same address, with the same photo
+        houses.push(RiskFeatures {
+            photo_hashes: vec![0xf0f0_f0f0_0f0f_0f0f],
+            ..house("again", 22.0)
+        });
+
+        let analyzer = RiskAnalyzer::new(&houses);
+
+        assert!(analyzer.analyze(&houses[1]).is_empty());
+
+        let flags = analyzer.analyze(&houses[5]);
+        assert_eq!(
+            signals(&flags),
+            [
+                RiskSignal::BelowZoneMedian,
+                RiskSignal::MissingStreet,
+                RiskSignal::MissingCoordinates,
+                RiskSignal::ReusedPhotos,
+                RiskSignal::ScamPhrases,
+            ]
+        );
+        assert_eq!(
+            flags[0].reason,
+            "9.0 €/m², 59% below the median of Isola (22.0 €/m²)"
+        );
+        assert_eq!(
+            flags[3].reason,
+            "photos also in 2 house(s) at another address: 0, again"
+        );
+        assert_eq!(
+            flags[4].reason,
+            "mentions \"bonifico anticipato\", \"sono all'estero\", \"chiavi per posta\""
+        );
+
+        // The others too, but not for each other: they are at the same address
+        for other in [&houses[0], &houses[6]] {
+            let flags = analyzer.analyze(other);
+            assert_eq!(signals(&flags), [RiskSignal::ReusedPhotos]);
+            assert_eq!(
+                flags[0].reason,
+                "photos also in 1 house(s) at another address: scam"
+            );
+        }
+    }
+
+    #[test]
+    fn test_no_median() {
+        // Too few houses in the zone, or in another listing type
+        let houses = [
+            house("0", 20.0),
+            house("1", 5.0),
+            RiskFeatures {
+                listing_type: Some(ListingType::Sale),
+                ..house("2", 5.0)
+            },
+        ];
+        let analyzer = RiskAnalyzer::new(&houses);
+
+        assert!(analyzer.analyze(&houses[1]).is_empty());
+        assert!(analyzer.analyze(&houses[2]).is_empty());
+    }
+}
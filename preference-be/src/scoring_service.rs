@@ -0,0 +1,404 @@
+use std::{fmt, str::FromStr};
+
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId},
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+
+/// How the household weighs what makes a house good, as a transparent score
+#[derive(Clone)]
+pub struct ScoringService {
+    collection: Collection<ScoringProfileEntity>,
+}
+
+type Result<T> = std::result::Result<T, ScoringServiceError>;
+
+#[derive(Debug)]
+pub enum ScoringServiceError {
+    MongoDbError(mongodb::error::Error),
+    ObjectId(mongodb::bson::oid::Error),
+    ProfileNotFound(String),
+    InvalidProfile(String),
+}
+
+impl fmt::Display for ScoringServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::MongoDbError(e) => write!(f, "mongodb: {}", e),
+            Self::ObjectId(e) => write!(f, "invalid id: {}", e),
+            Self::ProfileNotFound(id) => write!(f, "profile {} not found", id),
+            Self::InvalidProfile(message) => write!(f, "invalid profile: {}", message),
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for ScoringServiceError {
+    fn from(e: mongodb::error::Error) -> Self {
+        Self::MongoDbError(e)
+    }
+}
+impl From<mongodb::bson::oid::Error> for ScoringServiceError {
+    fn from(e: mongodb::bson::oid::Error) -> Self {
+        Self::ObjectId(e)
+    }
+}
+
+impl ScoringService {
+    pub fn new(collection: Collection<ScoringProfileEntity>) -> Self {
+        Self { collection }
+    }
+
+    pub async fn insert_profile(&self, profile: ScoringProfileDTOInsert) -> Result<String> {
+        validate(&profile.criteria)?;
+        let profile = ScoringProfileEntity {
+            _id: ObjectId::new(),
+            name: profile.name,
+            criteria: profile.criteria,
+        };
+
+        event!(Level::INFO, name = %profile.name, criteria = profile.criteria.len(), "inserting profile");
+        self.collection.insert_one(&profile, None).await?;
+
+        Ok(profile._id.to_hex())
+    }
+
+    pub async fn get_profiles(&self) -> Result<Vec<ScoringProfileDTO>> {
+        Ok(self
+            .profiles()
+            .await?
+            .into_iter()
+            .map(ScoringProfileDTO::from)
+            .collect())
+    }
+
+    pub async fn remove_profile(&self, profile_id: String) -> Result<()> {
+        let id = ObjectId::from_str(&profile_id)?;
+
+        event!(Level::INFO, profile_id = %profile_id, "removing");
+        let res = self.collection.delete_one(doc! { "_id": id }, None).await?;
+
+        if res.deleted_count == 0 {
+            event!(Level::WARN, profile_id = %profile_id, "Not found");
+            return Err(ScoringServiceError::ProfileNotFound(profile_id));
+        }
+
+        Ok(())
+    }
+
+    pub async fn profiles(&self) -> Result<Vec<ScoringProfileEntity>> {
+        let cur = self.collection.find(doc! {}, None).await?;
+        Ok(cur.try_collect().await?)
+    }
+}
+
+/// What a house is judged on
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "criterion", rename_all = "snake_case")]
+pub enum Criterion {
+    Cost,
+    CostPerSquareMeter,
+    SquareMeters,
+    /// Walking to the nearest metro stop, in meters
+    MetroDistance,
+    /// Straight line to a point of interest, in meters
+    PoiDistance {
+        poi: String,
+    },
+    /// By public transport to a commute destination, in minutes
+    Commute {
+        destination: String,
+    },
+    Floor,
+    /// 1 with the amenity, 0 without
+    Amenity {
+        amenity: String,
+    },
+}
+
+impl Criterion {
+    /// Without a curve the value must already be between 0 and 1
+    fn needs_curve(&self) -> bool {
+        !matches!(self, Self::Amenity { .. })
+    }
+}
+
+/// Turns a value into a score between 0 and 1
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Curve {
+    /// 1 at `best`, 0 at `worst`, a straight line in between.
+    /// `best` is lower than `worst` when less is better, as for the cost.
+    Linear { best: f64, worst: f64 },
+    /// Same ends, but flat near them and steep in the middle:
+    /// a few euros more on a cheap house barely matter
+    Sigmoid { best: f64, worst: f64 },
+}
+
+impl Curve {
+    fn apply(&self, value: f64) -> f64 {
+        match *self {
+            Self::Linear { best, worst } => (1.0 - (value - best) / (worst - best)).clamp(0.0, 1.0),
+            Self::Sigmoid { best, worst } => {
+                let t = (value - best) / (worst - best);
+                1.0 / (1.0 + (10.0 * (t - 0.5)).exp())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WeightedCriterion {
+    #[serde(flatten)]
+    pub criterion: Criterion,
+    pub weight: f64,
+    pub curve: Option<Curve>,
+}
+
+fn validate(criteria: &[WeightedCriterion]) -> Result<()> {
+    let invalid = |message: String| Err(ScoringServiceError::InvalidProfile(message));
+
+    if criteria.is_empty() {
+        return invalid("no criteria".to_string());
+    }
+    for c in criteria {
+        if !c.weight.is_finite() || c.weight <= 0.0 {
+            return invalid(format!("{:?}: the weight must be positive", c.criterion));
+        }
+        match c.curve {
+            None if c.criterion.needs_curve() => {
+                return invalid(format!("{:?}: a curve is needed", c.criterion));
+            }
+            Some(Curve::Linear { best, worst } | Curve::Sigmoid { best, worst })
+                if !best.is_finite() || !worst.is_finite() || best == worst =>
+            {
+                return invalid(format!("{:?}: best and worst must differ", c.criterion));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScoringProfileEntity {
+    _id: ObjectId,
+    name: String,
+    criteria: Vec<WeightedCriterion>,
+}
+
+impl ScoringProfileEntity {
+    pub fn id(&self) -> String {
+        self._id.to_hex()
+    }
+
+    /// The criteria the house has no value for are left out,
+    /// the others share the 100 points by weight
+    pub fn score(&self, value: impl Fn(&Criterion) -> Option<f64>) -> Score {
+        let round = |v: f64| (v * 10.0).round() / 10.0;
+
+        let mut breakdown: Vec<ScoreItem> = self
+            .criteria
+            .iter()
+            .map(|c| {
+                let value = value(&c.criterion);
+                ScoreItem {
+                    criterion: c.criterion.clone(),
+                    weight: c.weight,
+                    value,
+                    score: value.map(|v| match &c.curve {
+                        Some(curve) => curve.apply(v),
+                        None => v.clamp(0.0, 1.0),
+                    }),
+                    points: 0.0,
+                }
+            })
+            .collect();
+
+        let weights: f64 = breakdown
+            .iter()
+            .filter(|i| i.score.is_some())
+            .map(|i| i.weight)
+            .sum();
+        let mut total = 0.0;
+        for item in &mut breakdown {
+            if let Some(score) = item.score {
+                let points = score * item.weight / weights * 100.0;
+                total += points;
+                item.points = round(points);
+                item.score = Some((score * 1000.0).round() / 1000.0);
+            }
+        }
+
+        Score {
+            profile: self.name.clone(),
+            total: round(total),
+            breakdown,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ScoringProfileDTOInsert {
+    name: String,
+    criteria: Vec<WeightedCriterion>,
+}
+
+#[derive(Serialize)]
+pub struct ScoringProfileDTO {
+    id: String,
+    name: String,
+    criteria: Vec<WeightedCriterion>,
+}
+
+impl From<ScoringProfileEntity> for ScoringProfileDTO {
+    fn from(e: ScoringProfileEntity) -> Self {
+        Self {
+            id: e._id.to_hex(),
+            name: e.name,
+            criteria: e.criteria,
+        }
+    }
+}
+
+/// A house against a profile
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Score {
+    /// The profile name
+    pub profile: String,
+    /// From 0 to 100
+    pub total: f64,
+    pub breakdown: Vec<ScoreItem>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScoreItem {
+    #[serde(flatten)]
+    pub criterion: Criterion,
+    pub weight: f64,
+    /// Missing when unknown for the house
+    pub value: Option<f64>,
+    /// From 0 to 1, after the curve
+    pub score: Option<f64>,
+    /// Share of the total
+    pub points: f64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn criterion(criterion: Criterion, weight: f64, curve: Option<Curve>) -> WeightedCriterion {
+        WeightedCriterion {
+            criterion,
+            weight,
+            curve,
+        }
+    }
+
+    #[test]
+    fn test_curve() {
+        let cheap = Curve::Linear {
+            best: 800.0,
+            worst: 1600.0,
+        };
+        assert_eq!(cheap.apply(600.0), 1.0);
+        assert_eq!(cheap.apply(1000.0), 0.75);
+        assert_eq!(cheap.apply(2000.0), 0.0);
+
+        let big = Curve::Sigmoid {
+            best: 90.0,
+            worst: 40.0,
+        };
+        assert!(big.apply(90.0) > 0.99);
+        assert_eq!(big.apply(65.0), 0.5);
+        assert!(big.apply(40.0) < 0.01);
+    }
+
+    #[test]
+    fn test_score() {
+        let profile = ScoringProfileEntity {
+            _id: ObjectId::new(),
+            name: "family".to_string(),
+            criteria: vec![
+                criterion(
+                    Criterion::Cost,
+                    3.0,
+                    Some(Curve::Linear {
+                        best: 800.0,
+                        worst: 1600.0,
+                    }),
+                ),
+                criterion(
+                    Criterion::Amenity {
+                        amenity: "elevator".to_string(),
+                    },
+                    1.0,
+                    None,
+                ),
+                criterion(
+                    Criterion::Floor,
+                    1.0,
+                    Some(Curve::Linear {
+                        best: 4.0,
+                        worst: 0.0,
+                    }),
+                ),
+            ],
+        };
+
+        let score = profile.score(|c| match c {
+            Criterion::Cost => Some(1000.0),
+            Criterion::Amenity { .. } => Some(1.0),
+            _ => None,
+        });
+        assert_eq!(score.profile, "family");
+        // (0.75 * 3 + 1) / 4
+        assert_eq!(score.total, 81.3);
+        assert_eq!(score.breakdown[0].points, 56.3);
+        assert_eq!(score.breakdown[1].points, 25.0);
+        assert_eq!(score.breakdown[2].score, None);
+        assert_eq!(score.breakdown[2].points, 0.0);
+
+        let json = serde_json::to_value(&score.breakdown[1]).unwrap();
+        assert_eq!(json["criterion"], "amenity");
+        assert_eq!(json["amenity"], "elevator");
+    }
+
+    #[test]
+    fn test_validate() {
+        let profile: ScoringProfileDTOInsert = serde_json::from_value(serde_json::json!({
+            "name": "work",
+            "criteria": [
+                { "criterion": "commute", "destination": "65f0c0ffee", "weight": 2,
+                  "curve": { "type": "sigmoid", "best": 15, "worst": 60 } },
+                { "criterion": "amenity", "amenity": "balcony", "weight": 0.5 }
+            ]
+        }))
+        .unwrap();
+        assert!(validate(&profile.criteria).is_ok());
+
+        assert!(validate(&[]).is_err());
+        assert!(validate(&[criterion(Criterion::SquareMeters, 1.0, None)]).is_err());
+        assert!(validate(&[criterion(
+            Criterion::Cost,
+            0.0,
+            Some(Curve::Linear {
+                best: 1.0,
+                worst: 2.0
+            })
+        )])
+        .is_err());
+        assert!(validate(&[criterion(
+            Criterion::Cost,
+            1.0,
+            Some(Curve::Linear {
+                best: 1.0,
+                worst: 1.0
+            })
+        )])
+        .is_err());
+    }
+}
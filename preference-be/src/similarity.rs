@@ -0,0 +1,169 @@
+use crate::{fair_price::Listing, geo};
+
+pub const DEFAULT_NEIGHBOURS: usize = 10;
+pub const MAX_NEIGHBOURS: usize = 50;
+/// A kilometre apart weighs as much as a standard deviation of the rest
+const LOCATION_SCALE: f64 = 1000.0;
+
+/// What houses are alike by
+#[derive(Debug, Clone, Default)]
+pub struct SimilarityFeatures {
+    pub listing: Listing,
+    /// Latitude and longitude
+    pub position: Option<(f64, f64)>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Neighbour {
+    /// In the searched houses
+    pub index: usize,
+    /// From 0 to 1, 1 when alike in everything known of both
+    pub similarity: f64,
+}
+
+/// Standard deviations, so a euro and a square meter can be summed
+struct Scales {
+    cost: Option<f64>,
+    square_meters: Option<f64>,
+    rooms_number: Option<f64>,
+}
+
+impl Scales {
+    fn new<'a>(houses: impl Iterator<Item = &'a SimilarityFeatures> + Clone) -> Self {
+        let deviation = |value: &dyn Fn(&Listing) -> Option<f64>| {
+            let values: Vec<f64> = houses.clone().filter_map(|h| value(&h.listing)).collect();
+            if values.len() < 2 {
+                return None;
+            }
+            let mean = values.iter().sum::<f64>() / values.len() as f64;
+            let variance =
+                values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64;
+            Some(variance.sqrt()).filter(|&d| d > 0.0)
+        };
+
+        Self {
+            cost: deviation(&|l| l.cost.map(f64::from)),
+            square_meters: deviation(&|l| l.square_meters.map(f64::from)),
+            rooms_number: deviation(&|l| l.rooms_number.map(f64::from)),
+        }
+    }
+}
+
+/// The `k` houses most alike the target, the most alike first
+pub fn nearest(
+    target: &SimilarityFeatures,
+    houses: &[SimilarityFeatures],
+    k: usize,
+) -> Vec<Neighbour> {
+    let scales = Scales::new(houses.iter().chain([target]));
+
+    let mut neighbours: Vec<_> = houses
+        .iter()
+        .enumerate()
+        .filter_map(|(index, house)| {
+            Some(Neighbour {
+                index,
+                similarity: 1.0 / (1.0 + distance(target, house, &scales)?),
+            })
+        })
+        .collect();
+    neighbours.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+    neighbours.truncate(k);
+
+    for n in &mut neighbours {
+        n.similarity = (n.similarity * 1000.0).round() / 1000.0;
+    }
+    neighbours
+}
+
+/// The root mean square of the differences known for both,
+/// None when nothing is
+fn distance(a: &SimilarityFeatures, b: &SimilarityFeatures, scales: &Scales) -> Option<f64> {
+    let mut squares = Vec::new();
+
+    if let (Some((lat1, lng1)), Some((lat2, lng2))) = (a.position, b.position) {
+        squares.push((geo::distance(lat1, lng1, lat2, lng2) / LOCATION_SCALE).powi(2));
+    }
+
+    let (a, b) = (&a.listing, &b.listing);
+    for (x, y, scale) in [
+        (a.cost.map(f64::from), b.cost.map(f64::from), scales.cost),
+        (
+            a.square_meters.map(f64::from),
+            b.square_meters.map(f64::from),
+            scales.square_meters,
+        ),
+        (
+            a.rooms_number.map(f64::from),
+            b.rooms_number.map(f64::from),
+            scales.rooms_number,
+        ),
+    ] {
+        match (x, y, scale) {
+            (Some(x), Some(y), Some(scale)) => squares.push(((x - y) / scale).powi(2)),
+            // Nothing to scale by: all the same
+            (Some(_), Some(_), None) => squares.push(0.0),
+            _ => {}
+        }
+    }
+
+    // The share of the amenities both descriptions tell about that differ
+    let told: Vec<bool> = a
+        .amenities
+        .iter()
+        .filter_map(|(amenity, has)| Some(b.amenities.get(amenity)? == has))
+        .collect();
+    if !told.is_empty() {
+        let differing = told.iter().filter(|&&same| !same).count();
+        squares.push((differing as f64 / told.len() as f64).powi(2));
+    }
+
+    if squares.is_empty() {
+        return None;
+    }
+    Some((squares.iter().sum::<f64>() / squares.len() as f64).sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn house(cost: u32, square_meters: u32, lat: f64, garden: bool) -> SimilarityFeatures {
+        SimilarityFeatures {
+            listing: Listing {
+                square_meters: Some(square_meters),
+                rooms_number: Some(2),
+                floor: None,
+                zone: None,
+                amenities: BTreeMap::from([("garden".to_string(), garden)]),
+                cost: Some(cost),
+            },
+            position: Some((lat, 9.19)),
+        }
+    }
+
+    #[test]
+    fn test_nearest() {
+        let target = house(1000, 60, 45.47, true);
+        let houses = [
+            house(1800, 110, 45.52, false),
+            house(1050, 62, 45.471, true),
+            SimilarityFeatures::default(),
+            house(1000, 60, 45.50, true),
+            house(1000, 60, 45.47, true),
+        ];
+
+        let neighbours = nearest(&target, &houses, 3);
+        let order: Vec<_> = neighbours.iter().map(|n| n.index).collect();
+        assert_eq!(order, [4, 1, 3]);
+        assert_eq!(neighbours[0].similarity, 1.0);
+        assert!(neighbours[1].similarity > neighbours[2].similarity);
+
+        // Nothing known to compare the empty one by
+        let all = nearest(&target, &houses, DEFAULT_NEIGHBOURS);
+        assert_eq!(all.len(), 4);
+        assert_eq!(all.last().unwrap().index, 0);
+    }
+}
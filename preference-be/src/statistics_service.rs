@@ -0,0 +1,346 @@
+use std::{collections::BTreeMap, fmt};
+
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, Document},
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+
+use crate::{house_query::HouseState, house_service::ListingType};
+
+/// What the market asks, zone by zone, out of the houses we collected
+#[derive(Clone)]
+pub struct StatisticsService {
+    collection: Collection<Document>,
+}
+
+type Result<T> = std::result::Result<T, StatisticsServiceError>;
+
+#[derive(Debug)]
+pub enum StatisticsServiceError {
+    MongoDbError(mongodb::error::Error),
+    BsonError(mongodb::bson::de::Error),
+}
+
+impl fmt::Display for StatisticsServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::MongoDbError(e) => write!(f, "mongodb: {}", e),
+            Self::BsonError(e) => write!(f, "unexpected statistics group: {}", e),
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for StatisticsServiceError {
+    fn from(e: mongodb::error::Error) -> Self {
+        Self::MongoDbError(e)
+    }
+}
+
+impl From<mongodb::bson::de::Error> for StatisticsServiceError {
+    fn from(e: mongodb::bson::de::Error) -> Self {
+        Self::BsonError(e)
+    }
+}
+
+impl StatisticsService {
+    /// On the houses collection
+    pub fn new(collection: Collection<Document>) -> Self {
+        Self { collection }
+    }
+
+    /// Mongo groups the prices by area and month, the percentiles are
+    /// computed here: `$percentile` needs a recent server
+    pub async fn get_statistics(&self, query: StatisticsQuery) -> Result<Vec<AreaStatistics>> {
+        let area = match query.group_by {
+            StatisticsGroup::City => None,
+            StatisticsGroup::Zone => Some("$zone"),
+            StatisticsGroup::Neighborhood => Some("$neighborhood"),
+        };
+        let pipeline = vec![
+            doc! { "$match": query.filter() },
+            doc! { "$group": {
+                "_id": {
+                    "city": "$city",
+                    "area": area,
+                    "listing_type": "$listing_type",
+                    "month": { "$dateToString": {
+                        "format": "%Y-%m",
+                        "date": { "$toDate": "$_id" },
+                    } },
+                },
+                "costs": { "$push": "$cost" },
+                "costs_per_square_meter": { "$push": "$cost_per_square_meter" },
+                "rooms": { "$push": "$rooms_number" },
+            } },
+        ];
+
+        let cur = self.collection.aggregate(pipeline, None).await?;
+        let groups: Vec<Document> = cur.try_collect().await?;
+        let groups: Vec<MonthGroup> = groups
+            .into_iter()
+            .map(mongodb::bson::from_document)
+            .collect::<std::result::Result<_, _>>()?;
+
+        event!(Level::INFO, groups = groups.len(), "aggregated");
+
+        Ok(statistics(groups))
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StatisticsGroup {
+    City,
+    /// As the portals call it
+    #[default]
+    Zone,
+    /// The official one, consistent between portals
+    Neighborhood,
+}
+
+/// Query parameters of `GET /api/statistics`
+#[derive(Deserialize, Default)]
+pub struct StatisticsQuery {
+    #[serde(default)]
+    pub group_by: StatisticsGroup,
+    pub city: Option<String>,
+    pub listing_type: Option<ListingType>,
+    /// All by default: the delisted houses tell what the market asked too
+    pub state: Option<HouseState>,
+}
+
+impl StatisticsQuery {
+    fn filter(&self) -> Document {
+        let mut filter = doc! { "cost": { "$gt": 0 } };
+        match self.state.unwrap_or(HouseState::All) {
+            HouseState::Available => {
+                filter.insert("removed", false);
+            }
+            HouseState::Removed => {
+                filter.insert("removed", true);
+            }
+            HouseState::All => {}
+        }
+        if let Some(city) = &self.city {
+            filter.insert("city", city);
+        }
+        if let Some(listing_type) = self.listing_type {
+            filter.insert(
+                "listing_type",
+                mongodb::bson::to_bson(&listing_type).unwrap(),
+            );
+        }
+        filter
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct GroupKey {
+    city: Option<String>,
+    area: Option<String>,
+    listing_type: Option<ListingType>,
+}
+
+#[derive(Deserialize, Debug)]
+struct MonthKey {
+    #[serde(flatten)]
+    group: GroupKey,
+    month: String,
+}
+
+/// The houses of an area inserted in a month
+#[derive(Deserialize, Debug)]
+struct MonthGroup {
+    _id: MonthKey,
+    costs: Vec<Option<f64>>,
+    costs_per_square_meter: Vec<Option<f64>>,
+    rooms: Vec<Option<f64>>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Distribution {
+    pub min: f64,
+    pub p25: f64,
+    pub median: f64,
+    pub p75: f64,
+    pub max: f64,
+}
+
+impl Distribution {
+    fn new(values: impl IntoIterator<Item = Option<f64>>) -> Option<Self> {
+        let mut values: Vec<f64> = values.into_iter().flatten().collect();
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_by(f64::total_cmp);
+
+        Some(Self {
+            min: values[0],
+            p25: percentile(&values, 0.25),
+            median: percentile(&values, 0.5),
+            p75: percentile(&values, 0.75),
+            max: values[values.len() - 1],
+        })
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct MonthStatistics {
+    /// "YYYY-MM", by insertion date
+    pub month: String,
+    pub count: usize,
+    pub median_cost: Option<f64>,
+    pub median_cost_per_square_meter: Option<f64>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct AreaStatistics {
+    pub city: Option<String>,
+    /// Zone or neighborhood, missing when grouping by city
+    pub area: Option<String>,
+    pub listing_type: Option<ListingType>,
+    pub count: usize,
+    pub cost: Option<Distribution>,
+    pub cost_per_square_meter: Option<Distribution>,
+    pub average_rooms: Option<f64>,
+    /// Oldest month first
+    pub trend: Vec<MonthStatistics>,
+}
+
+/// Linear interpolation between the closest ranks
+pub fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = p * (sorted.len() - 1) as f64;
+    let (low, high) = (rank.floor() as usize, rank.ceil() as usize);
+    sorted[low] + (sorted[high] - sorted[low]) * (rank - low as f64)
+}
+
+fn median(values: &[Option<f64>]) -> Option<f64> {
+    Distribution::new(values.iter().copied()).map(|d| d.median)
+}
+
+fn statistics(groups: Vec<MonthGroup>) -> Vec<AreaStatistics> {
+    let mut areas: BTreeMap<GroupKey, Vec<MonthGroup>> = BTreeMap::new();
+    for group in groups {
+        areas
+            .entry(group._id.group.clone())
+            .or_default()
+            .push(group);
+    }
+
+    let mut statistics: Vec<_> = areas
+        .into_iter()
+        .map(|(key, mut months)| {
+            months.sort_by(|a, b| a._id.month.cmp(&b._id.month));
+            let all = |values: fn(&MonthGroup) -> &Vec<Option<f64>>| {
+                months
+                    .iter()
+                    .flat_map(move |m| values(m).iter().copied())
+                    .collect::<Vec<_>>()
+            };
+            let rooms: Vec<f64> = all(|m| &m.rooms).into_iter().flatten().collect();
+
+            AreaStatistics {
+                city: key.city,
+                area: key.area,
+                listing_type: key.listing_type,
+                count: months.iter().map(|m| m.costs.len()).sum(),
+                cost: Distribution::new(all(|m| &m.costs)),
+                cost_per_square_meter: Distribution::new(all(|m| &m.costs_per_square_meter)),
+                average_rooms: (!rooms.is_empty())
+                    .then(|| rooms.iter().sum::<f64>() / rooms.len() as f64),
+                trend: months
+                    .iter()
+                    .map(|m| MonthStatistics {
+                        month: m._id.month.clone(),
+                        count: m.costs.len(),
+                        median_cost: median(&m.costs),
+                        median_cost_per_square_meter: median(&m.costs_per_square_meter),
+                    })
+                    .collect(),
+            }
+        })
+        .collect();
+    // The busiest markets first
+    statistics.sort_by_key(|s| std::cmp::Reverse(s.count));
+    statistics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(zone: &str, month: &str, costs: &[f64], rooms: &[f64]) -> MonthGroup {
+        MonthGroup {
+            _id: MonthKey {
+                group: GroupKey {
+                    city: Some("Milano".to_string()),
+                    area: Some(zone.to_string()),
+                    listing_type: Some(ListingType::Rent),
+                },
+                month: month.to_string(),
+            },
+            costs: costs.iter().map(|&c| Some(c)).collect(),
+            costs_per_square_meter: costs.iter().map(|&c| Some(c / 50.0)).collect(),
+            rooms: rooms.iter().map(|&r| Some(r)).collect(),
+        }
+    }
+
+    #[test]
+    fn test_percentile() {
+        let values = [1.0, 2.0, 3.0, 4.0];
+        assert_eq!(percentile(&values, 0.0), 1.0);
+        assert_eq!(percentile(&values, 0.5), 2.5);
+        assert_eq!(percentile(&values, 0.75), 3.25);
+        assert_eq!(percentile(&values, 1.0), 4.0);
+        assert_eq!(percentile(&[7.0], 0.25), 7.0);
+    }
+
+    #[test]
+    fn test_statistics() {
+        let statistics = statistics(vec![
+            group("Dergano", "2024-02", &[900.0, 1100.0], &[2.0, 3.0]),
+            group("Isola", "2024-01", &[1500.0], &[2.0]),
+            group("Dergano", "2024-01", &[800.0], &[1.0]),
+        ]);
+
+        assert_eq!(statistics.len(), 2);
+        let dergano = &statistics[0];
+        assert_eq!(dergano.area.as_deref(), Some("Dergano"));
+        assert_eq!(dergano.count, 3);
+        assert_eq!(
+            dergano.cost,
+            Some(Distribution {
+                min: 800.0,
+                p25: 850.0,
+                median: 900.0,
+                p75: 1000.0,
+                max: 1100.0,
+            })
+        );
+        assert_eq!(dergano.average_rooms, Some(2.0));
+        assert_eq!(
+            dergano
+                .trend
+                .iter()
+                .map(|m| (m.month.as_str(), m.count, m.median_cost))
+                .collect::<Vec<_>>(),
+            [("2024-01", 1, Some(800.0)), ("2024-02", 2, Some(1000.0))]
+        );
+    }
+
+    #[test]
+    fn test_filter() {
+        let query: StatisticsQuery =
+            serde_urlencoded::from_str("group_by=neighborhood&listing_type=rent&state=available")
+                .unwrap();
+
+        assert_eq!(query.group_by, StatisticsGroup::Neighborhood);
+        assert_eq!(
+            query.filter(),
+            doc! { "cost": { "$gt": 0 }, "removed": false, "listing_type": "rent" }
+        );
+    }
+}
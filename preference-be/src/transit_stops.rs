@@ -0,0 +1,338 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+
+use crate::geo;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Stop {
+    pub name: String,
+    /// Normalized as "M1", "M2"...
+    pub lines: Vec<String>,
+    pub lat: f64,
+    pub lng: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NearestStop {
+    pub name: String,
+    pub lines: Vec<String>,
+    /// Estimated walking distance, in meters
+    pub distance: f64,
+}
+
+/// The metro stops, kept in memory: they are a few hundred
+#[derive(Clone, Debug, Default)]
+pub struct TransitStops {
+    file: Option<String>,
+    stops: Arc<RwLock<Vec<Stop>>>,
+}
+
+type Result<T> = std::result::Result<T, TransitStopsError>;
+
+#[derive(Debug)]
+pub enum TransitStopsError {
+    NoFile,
+    Io(std::io::Error),
+    InvalidGeoJson(serde_json::Error),
+}
+
+impl fmt::Display for TransitStopsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NoFile => write!(f, "no transit stops file configured"),
+            Self::Io(e) => write!(f, "reading the transit stops: {}", e),
+            Self::InvalidGeoJson(e) => write!(f, "invalid transit stops GeoJSON: {}", e),
+        }
+    }
+}
+
+impl From<std::io::Error> for TransitStopsError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+impl From<serde_json::Error> for TransitStopsError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::InvalidGeoJson(e)
+    }
+}
+
+impl TransitStops {
+    pub fn new(stops: Vec<Stop>) -> Self {
+        Self {
+            file: None,
+            stops: Arc::new(RwLock::new(stops)),
+        }
+    }
+
+    /// Reads the stops from a GeoJSON or a CSV file, chosen by extension.
+    /// Without a file there are no stops and the houses get no distance.
+    pub fn load(path: Option<&str>) -> Self {
+        let path = match path {
+            None => {
+                event!(Level::WARN, "no transit stops file configured");
+                return Self::default();
+            }
+            Some(path) => path,
+        };
+
+        let content = std::fs::read_to_string(path).unwrap();
+        let stops = stops_from_file(path, &content).unwrap();
+        event!(Level::INFO, count = stops.len(), "transit stops loaded");
+
+        Self {
+            file: Some(path.to_string()),
+            ..Self::new(stops)
+        }
+    }
+
+    /// Reads the file again, after the dataset is updated.
+    /// Returns how many stops it has.
+    pub async fn reload(&self) -> Result<usize> {
+        let file = self.file.as_deref().ok_or(TransitStopsError::NoFile)?;
+
+        let content = tokio::fs::read_to_string(file).await?;
+        let stops = stops_from_file(file, &content)?;
+        let count = stops.len();
+        *self.stops.write().unwrap() = stops;
+        event!(Level::INFO, count, "transit stops reloaded");
+
+        Ok(count)
+    }
+
+    pub fn stops(&self) -> Vec<Stop> {
+        self.stops.read().unwrap().clone()
+    }
+
+    pub fn nearest(&self, lat: f64, lng: f64) -> Option<NearestStop> {
+        self.stops
+            .read()
+            .unwrap()
+            .iter()
+            .map(|s| (s, walking_distance(s, lat, lng)))
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(s, distance)| NearestStop {
+                name: s.name.clone(),
+                lines: s.lines.clone(),
+                distance,
+            })
+    }
+
+    /// Walking distance to the nearest stop of every line
+    pub fn line_distances(&self, lat: f64, lng: f64) -> BTreeMap<String, f64> {
+        let mut distances = BTreeMap::new();
+        for stop in self.stops.read().unwrap().iter() {
+            let distance = walking_distance(stop, lat, lng);
+            for line in &stop.lines {
+                let d = distances.entry(line.clone()).or_insert(distance);
+                *d = f64::min(*d, distance);
+            }
+        }
+        distances
+    }
+}
+
+fn walking_distance(stop: &Stop, lat: f64, lng: f64) -> f64 {
+    geo::walking_distance(stop.lat, stop.lng, lat, lng).round()
+}
+
+/// "1", "M1", "2 - 3", "M2,M3"... become ["M1"], ["M2", "M3"]
+pub fn lines(lines: &str) -> Vec<String> {
+    lines
+        .split(|c: char| !c.is_alphanumeric())
+        .map(|l| l.trim_start_matches(['M', 'm']))
+        .filter(|l| !l.is_empty())
+        .map(|l| format!("M{}", l))
+        .collect()
+}
+
+/// The Comune di Milano dataset uses the Italian names
+#[derive(Deserialize)]
+struct StopProperties {
+    #[serde(alias = "nome")]
+    name: String,
+    #[serde(alias = "linee")]
+    lines: String,
+}
+
+#[derive(Deserialize)]
+struct FeatureCollection {
+    features: Vec<Feature>,
+}
+
+#[derive(Deserialize)]
+struct Feature {
+    geometry: Option<geo::GeoPoint>,
+    properties: StopProperties,
+}
+
+/// A GeoJSON or a CSV file, chosen by extension
+fn stops_from_file(path: &str, content: &str) -> Result<Vec<Stop>> {
+    Ok(match Path::new(path).extension().and_then(|e| e.to_str()) {
+        Some("csv") => stops_from_csv(content),
+        _ => stops_from_geojson(content)?,
+    })
+}
+
+fn stops_from_geojson(content: &str) -> Result<Vec<Stop>> {
+    let collection: FeatureCollection = serde_json::from_str(content)?;
+
+    Ok(collection
+        .features
+        .into_iter()
+        .filter_map(|f| {
+            let geo::GeoPoint::Point {
+                coordinates: [lng, lat],
+            } = f.geometry?;
+            Some(Stop {
+                name: f.properties.name,
+                lines: lines(&f.properties.lines),
+                lat,
+                lng,
+            })
+        })
+        .collect())
+}
+
+// Not flattening StopProperties: the csv crate would read "1" as a number
+#[derive(Deserialize)]
+struct CsvStop {
+    #[serde(alias = "nome")]
+    name: String,
+    #[serde(alias = "linee")]
+    lines: String,
+    #[serde(alias = "LAT_Y_4326", alias = "latitude")]
+    lat: f64,
+    #[serde(alias = "LONG_X_4326", alias = "longitude")]
+    lng: f64,
+}
+
+fn stops_from_csv(content: &str) -> Vec<Stop> {
+    // Italian open data files are often separated by semicolons
+    let header = content.lines().next().unwrap_or_default();
+    let delimiter = if header.contains(';') { b';' } else { b',' };
+
+    csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .from_reader(content.as_bytes())
+        .deserialize::<CsvStop>()
+        .filter_map(|s| match s {
+            Ok(s) => Some(Stop {
+                name: s.name,
+                lines: lines(&s.lines),
+                lat: s.lat,
+                lng: s.lng,
+            }),
+            Err(e) => {
+                event!(Level::WARN, error = %e, "skipping transit stop");
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load() {
+        let geojson = r#"{
+            "type": "FeatureCollection",
+            "features": [
+                {
+                    "type": "Feature",
+                    "geometry": { "type": "Point", "coordinates": [9.1899, 45.4642] },
+                    "properties": { "id_amat": 1, "nome": "DUOMO", "linee": "1 - 3" }
+                },
+                {
+                    "type": "Feature",
+                    "geometry": { "type": "Point", "coordinates": [9.1755, 45.4683] },
+                    "properties": { "nome": "CADORNA", "linee": "1,2" }
+                }
+            ]
+        }"#;
+        let csv = "id_amat;nome;linee;LONG_X_4326;LAT_Y_4326\n\
+            1;DUOMO;1 - 3;9.1899;45.4642\n\
+            2;CADORNA;1,2;9.1755;45.4683\n\
+            3;LANZA;2;9.1818;45.4720\n";
+
+        let from_geojson = stops_from_geojson(geojson).unwrap();
+        let from_csv = stops_from_csv(csv);
+        assert_eq!(from_geojson, from_csv[..2]);
+        assert_eq!(from_csv[2].lines, ["M2"]);
+        assert_eq!(
+            from_geojson[0],
+            Stop {
+                name: "DUOMO".to_string(),
+                lines: vec!["M1".to_string(), "M3".to_string()],
+                lat: 45.4642,
+                lng: 9.1899,
+            }
+        );
+    }
+
+    #[test]
+    fn test_distances() {
+        let stops = TransitStops::new(vec![
+            Stop {
+                name: "DUOMO".to_string(),
+                lines: vec!["M1".to_string(), "M3".to_string()],
+                lat: 45.4642,
+                lng: 9.1899,
+            },
+            Stop {
+                name: "CADORNA".to_string(),
+                lines: vec!["M1".to_string(), "M2".to_string()],
+                lat: 45.4683,
+                lng: 9.1755,
+            },
+        ]);
+
+        // Piazza Castello: closer to Cadorna
+        let nearest = stops.nearest(45.4690, 9.1790).unwrap();
+        assert_eq!(nearest.name, "CADORNA");
+        assert!(nearest.distance < 400.0);
+
+        let distances = stops.line_distances(45.4690, 9.1790);
+        assert_eq!(distances.keys().collect::<Vec<_>>(), ["M1", "M2", "M3"]);
+        assert_eq!(distances["M1"], distances["M2"]);
+        assert!(distances["M3"] > distances["M1"]);
+
+        assert_eq!(TransitStops::default().nearest(45.4690, 9.1790), None);
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let path = std::env::temp_dir().join(format!("stops-test-{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(
+            path,
+            "nome;linee;LONG_X_4326;LAT_Y_4326\nDUOMO;1;9.1899;45.4642\n",
+        )
+        .unwrap();
+        let stops = TransitStops::load(Some(path));
+        assert_eq!(stops.stops().len(), 1);
+
+        std::fs::write(
+            path,
+            "nome;linee;LONG_X_4326;LAT_Y_4326\nDUOMO;1;9.1899;45.4642\nLANZA;2;9.1818;45.4720\n",
+        )
+        .unwrap();
+        assert_eq!(stops.reload().await.unwrap(), 2);
+        assert_eq!(stops.nearest(45.4720, 9.1818).unwrap().name, "LANZA");
+        std::fs::remove_file(path).unwrap();
+
+        assert!(matches!(
+            TransitStops::default().reload().await,
+            Err(TransitStopsError::NoFile)
+        ));
+    }
+}